@@ -33,6 +33,23 @@ bitflags! {
     }
 }
 
+bitflags! {
+    // What a handle is allowed to do with the object it refers to.
+    pub struct HandleRights : u32 {
+        const WAIT = 1 << 0;
+        const SIGNAL = 1 << 1;
+        const MAP = 1 << 2;
+        const TRANSFER = 1 << 3;
+        const DUPLICATE = 1 << 4;
+        const BIND_INTERRUPT = 1 << 5;
+        // Only means anything on a capability handle, see INTROSPECTION_HANDLE.
+        const INTROSPECT = 1 << 6;
+
+        const ALL = Self::WAIT.bits | Self::SIGNAL.bits | Self::MAP.bits | Self::TRANSFER.bits
+            | Self::DUPLICATE.bits | Self::BIND_INTERRUPT.bits;
+    }
+}
+
 use num_derive::FromPrimitive;
 #[derive(Copy, Clone, FromPrimitive, Debug)]
 pub enum MapType {
@@ -41,7 +58,8 @@ pub enum MapType {
     Device,
 }
 
-#[repr(C)]
+// usize sized, so FramebufferInfo has no padding to leak when the kernel copies it out.
+#[repr(usize)]
 #[derive(Clone, Debug)]
 pub enum FramebufferFormat {
     Rgb,
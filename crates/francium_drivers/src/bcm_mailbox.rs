@@ -0,0 +1,151 @@
+// The VideoCore mailbox, as used by the property channel.
+// https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
+
+pub struct BCMMailbox {
+    base_address: usize,
+}
+
+const MBOX_FULL: u32 = 0x80000000;
+const MBOX_EMPTY: u32 = 0x40000000;
+
+const PROPERTY_CHANNEL: u32 = 8;
+const RESPONSE_SUCCESS: u32 = 0x80000000;
+
+// How long to wait for the firmware before giving up, in status reads.
+const REPLY_SPIN_COUNT: usize = 10_000_000;
+
+impl BCMMailbox {
+    pub fn new(base_address: usize) -> BCMMailbox {
+        BCMMailbox {
+            base_address: base_address,
+        }
+    }
+
+    unsafe fn read_mbox0(&self) -> u32 {
+        const MBOX0_READ: usize = 0x00;
+        ((self.base_address + MBOX0_READ) as *mut u32).read_volatile()
+    }
+
+    unsafe fn read_mbox0_status(&self) -> u32 {
+        const MBOX0_STATUS: usize = 0x18;
+        ((self.base_address + MBOX0_STATUS) as *mut u32).read_volatile()
+    }
+
+    unsafe fn write_mbox1(&mut self, value: u32) {
+        const MBOX1_WRITE: usize = 0x20;
+        ((self.base_address + MBOX1_WRITE) as *mut u32).write_volatile(value)
+    }
+
+    unsafe fn read_mbox1_status(&self) -> u32 {
+        const MBOX1_STATUS: usize = 0x38;
+        ((self.base_address + MBOX1_STATUS) as *mut u32).read_volatile()
+    }
+
+    // Hands the firmware the property message at buffer_bus, and waits for it to be answered in
+    // place. The buffer must be 16 byte aligned, and the caller has to keep it coherent with the
+    // VideoCore on either side of this.
+    pub unsafe fn call_property(&mut self, buffer_bus: u32) -> bool {
+        let message = (buffer_bus & !0xf) | PROPERTY_CHANNEL;
+
+        while self.read_mbox1_status() & MBOX_FULL != 0 {}
+        self.write_mbox1(message);
+
+        for _ in 0..REPLY_SPIN_COUNT {
+            if self.read_mbox0_status() & MBOX_EMPTY != 0 {
+                continue;
+            }
+            // Replies on other channels aren't ours, so skip past them.
+            if self.read_mbox0() == message {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+pub struct MailboxFramebuffer {
+    pub bus_addr: u32,
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+}
+
+// Where the values we care about end up, in words.
+const FB_REQUEST_WORDS: usize = 30;
+const FB_WIDTH: usize = 5;
+const FB_HEIGHT: usize = 6;
+const FB_ALLOCATE: usize = 23;
+const FB_PITCH: usize = 28;
+const FB_TAGS: [usize; 6] = [2, 7, 12, 16, 20, 25];
+
+// A 32bpp RGB framebuffer of the given size. Returns the length of the message in bytes.
+pub fn write_framebuffer_request(buffer: &mut [u32], width: u32, height: u32) -> usize {
+    const SET_PHYSICAL_SIZE: u32 = 0x00048003;
+    const SET_VIRTUAL_SIZE: u32 = 0x00048004;
+    const SET_DEPTH: u32 = 0x00048005;
+    const SET_PIXEL_ORDER: u32 = 0x00048006;
+    const ALLOCATE_BUFFER: u32 = 0x00040001;
+    const GET_PITCH: u32 = 0x00040008;
+
+    let request: [u32; FB_REQUEST_WORDS] = [
+        (FB_REQUEST_WORDS * 4) as u32,
+        0,
+        SET_PHYSICAL_SIZE,
+        8,
+        0,
+        width,
+        height,
+        SET_VIRTUAL_SIZE,
+        8,
+        0,
+        width,
+        height,
+        SET_DEPTH,
+        4,
+        0,
+        32,
+        // 1 is RGB.
+        SET_PIXEL_ORDER,
+        4,
+        0,
+        1,
+        // Alignment in, address out.
+        ALLOCATE_BUFFER,
+        8,
+        0,
+        16,
+        0,
+        GET_PITCH,
+        4,
+        0,
+        0,
+        0,
+    ];
+    buffer[..FB_REQUEST_WORDS].copy_from_slice(&request);
+    FB_REQUEST_WORDS * 4
+}
+
+pub fn read_framebuffer_reply(buffer: &[u32]) -> Option<MailboxFramebuffer> {
+    if buffer[1] != RESPONSE_SUCCESS {
+        return None;
+    }
+    // Each tag's request code turns into a response code with the top bit set.
+    if FB_TAGS
+        .iter()
+        .any(|tag| buffer[tag + 2] & RESPONSE_SUCCESS == 0)
+    {
+        return None;
+    }
+    if buffer[FB_ALLOCATE] == 0 {
+        return None;
+    }
+
+    Some(MailboxFramebuffer {
+        bus_addr: buffer[FB_ALLOCATE],
+        size: buffer[FB_ALLOCATE + 1],
+        width: buffer[FB_WIDTH],
+        height: buffer[FB_HEIGHT],
+        pitch: buffer[FB_PITCH],
+    })
+}
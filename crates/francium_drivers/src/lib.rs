@@ -71,5 +71,7 @@ pub mod pl011_uart;
 
 pub mod arm_gicv2;
 pub mod bcm_interrupt;
+pub mod bcm_mailbox;
+pub mod qemu_fw_cfg;
 
 pub mod print;
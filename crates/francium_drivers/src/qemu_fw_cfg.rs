@@ -0,0 +1,156 @@
+// QEMU's firmware config device, MMIO flavour, and the ramfb display that's configured through it.
+// https://www.qemu.org/docs/master/specs/fw_cfg.html
+
+pub struct FwCfg {
+    base_address: usize,
+}
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_ID: u16 = 0x0001;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+const FW_CFG_VERSION_DMA: u32 = 1 << 1;
+
+const DMA_CTL_ERROR: u32 = 1 << 0;
+const DMA_CTL_SELECT: u32 = 1 << 3;
+const DMA_CTL_WRITE: u32 = 1 << 4;
+
+// Everything in here is big endian.
+#[repr(C)]
+pub struct FwCfgDmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+impl FwCfg {
+    pub fn new(base_address: usize) -> FwCfg {
+        FwCfg {
+            base_address: base_address,
+        }
+    }
+
+    unsafe fn read_data(&mut self) -> u8 {
+        const DATA: usize = 0x00;
+        ((self.base_address + DATA) as *mut u8).read_volatile()
+    }
+
+    unsafe fn write_selector(&mut self, key: u16) {
+        const SELECTOR: usize = 0x08;
+        ((self.base_address + SELECTOR) as *mut u16).write_volatile(key.to_be())
+    }
+
+    unsafe fn write_dma_address(&mut self, address: u64) {
+        const DMA_ADDRESS: usize = 0x10;
+        ((self.base_address + DMA_ADDRESS) as *mut u64).write_volatile(address.to_be())
+    }
+
+    fn read_bytes(&mut self, out: &mut [u8]) {
+        for byte in out {
+            *byte = unsafe { self.read_data() };
+        }
+    }
+
+    fn read_be32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes);
+        u32::from_be_bytes(bytes)
+    }
+
+    fn read_be16(&mut self) -> u16 {
+        let mut bytes = [0; 2];
+        self.read_bytes(&mut bytes);
+        u16::from_be_bytes(bytes)
+    }
+
+    // Writes need DMA, so a device without it is no use to us.
+    pub fn is_present(&mut self) -> bool {
+        let mut signature = [0; 4];
+        unsafe { self.write_selector(FW_CFG_SIGNATURE) };
+        self.read_bytes(&mut signature);
+        if &signature != b"QEMU" {
+            return false;
+        }
+
+        let mut id = [0; 4];
+        unsafe { self.write_selector(FW_CFG_ID) };
+        self.read_bytes(&mut id);
+        u32::from_le_bytes(id) & FW_CFG_VERSION_DMA != 0
+    }
+
+    // The selector for a named file.
+    pub fn find_file(&mut self, name: &str) -> Option<u16> {
+        unsafe { self.write_selector(FW_CFG_FILE_DIR) };
+        let count = self.read_be32();
+        for _ in 0..count {
+            let _size = self.read_be32();
+            let select = self.read_be16();
+            let _reserved = self.read_be16();
+            let mut file_name = [0u8; 56];
+            self.read_bytes(&mut file_name);
+
+            let len = file_name.iter().position(|x| *x == 0).unwrap_or(56);
+            if &file_name[..len] == name.as_bytes() {
+                return Some(select);
+            }
+        }
+        None
+    }
+
+    // Writes length bytes at data_phys to the file. access is scratch space for the request, which
+    // the device reads at access_phys.
+    pub unsafe fn dma_write(
+        &mut self,
+        key: u16,
+        access: *mut FwCfgDmaAccess,
+        access_phys: u64,
+        data_phys: u64,
+        length: u32,
+    ) -> bool {
+        access.write_volatile(FwCfgDmaAccess {
+            control: (((key as u32) << 16) | DMA_CTL_SELECT | DMA_CTL_WRITE).to_be(),
+            length: length.to_be(),
+            address: data_phys.to_be(),
+        });
+        self.write_dma_address(access_phys);
+
+        // QEMU finishes the transfer before the register write returns, but the spec says to wait
+        // for the control field to clear.
+        loop {
+            let control = u32::from_be(core::ptr::addr_of!((*access).control).read_volatile());
+            if control & DMA_CTL_ERROR != 0 {
+                return false;
+            }
+            if control == 0 {
+                return true;
+            }
+        }
+    }
+}
+
+// What gets written to etc/ramfb. Big endian, like the rest of fw_cfg.
+#[repr(C, packed)]
+pub struct RamfbConfig {
+    address: u64,
+    fourcc: u32,
+    flags: u32,
+    width: u32,
+    height: u32,
+    stride: u32,
+}
+
+// DRM_FORMAT_XBGR8888, which is R, G, B, X in memory.
+const FOURCC_XBGR8888: u32 = u32::from_le_bytes(*b"XB24");
+
+impl RamfbConfig {
+    pub fn new(address: u64, width: u32, height: u32) -> RamfbConfig {
+        RamfbConfig {
+            address: address.to_be(),
+            fourcc: FOURCC_XBGR8888.to_be(),
+            flags: 0,
+            width: width.to_be(),
+            height: height.to_be(),
+            stride: (width * 4).to_be(),
+        }
+    }
+}
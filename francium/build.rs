@@ -19,4 +19,19 @@ fn main() {
     if platform == "" {
         panic!("No platform specified!");
     }
+
+    syscall_gen_buildtime::generate_kernel("../syscalls.toml");
+
+    // Reported by get_system_info. Builds from a tarball don't have git, which is fine.
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=FRANCIUM_GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
 }
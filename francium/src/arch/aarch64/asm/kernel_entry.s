@@ -2,6 +2,7 @@
 .global set_ttbr0_el1
 
 .extern rust_main
+.extern BOOT_DEVICE_TREE_ADDR
 
 .section .text
 kernel_start:
@@ -23,6 +24,10 @@ kernel_start:
      cmp x0, x1
      bne .bss_clear
 
+     // Now that bss is clear, save the device tree address from the stub.
+     ldr x0, =BOOT_DEVICE_TREE_ADDR
+     str x19, [x0]
+
 	b rust_main
 
 .section .bss.bootstrap_stack
@@ -32,6 +32,8 @@
 
 .section .text.entry
 _start:
+# The bootloader passes the device tree's physical address in x0, keep it for kernel_start.
+mov x19, x0
 
 mrs x2, currentel
 cmp x2, 0x8
@@ -4,3 +4,14 @@ pub unsafe fn clear_cache_for_address(addr: usize) {
     asm!("dc cvau, {addr}
 		  ic ivau, {addr}", addr = in (reg) (addr));
 }
+
+// For memory shared with a device that doesn't snoop our caches: clean and invalidate to the point
+// of coherency, so our writes reach RAM and we don't read stale lines afterwards.
+pub unsafe fn clean_invalidate_range(addr: usize, len: usize) {
+    const CACHE_LINE_SIZE: usize = 64;
+    let start = addr & !(CACHE_LINE_SIZE - 1);
+    for line in (start..addr + len).step_by(CACHE_LINE_SIZE) {
+        asm!("dc civac, {line}", line = in (reg) (line));
+    }
+    asm!("dsb sy");
+}
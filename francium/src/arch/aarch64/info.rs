@@ -0,0 +1,4 @@
+// Physical address of the device tree, or 0 if the bootloader didn't pass one in x0.
+// Set by kernel_start.
+#[no_mangle]
+pub static mut BOOT_DEVICE_TREE_ADDR: usize = 0;
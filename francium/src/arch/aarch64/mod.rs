@@ -1,6 +1,7 @@
 pub mod arch_timer;
 pub mod cache;
 pub mod context;
+pub mod info;
 pub mod interrupt;
 pub mod mmu;
 pub mod per_cpu;
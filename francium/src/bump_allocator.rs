@@ -42,5 +42,6 @@ unsafe impl GlobalAlloc for BumpAllocator {
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
 }
 
+#[cfg(not(test))]
 #[global_allocator]
 static BUMP_ALLOCATOR: BumpAllocator = BumpAllocator {};
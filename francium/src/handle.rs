@@ -1,12 +1,16 @@
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use common::os_error::ResultCode;
+use francium_common::types::HandleRights;
 use spin::Mutex;
 
 use crate::memory::AddressSpace;
 use crate::process::Process;
 use crate::scheduler;
 use crate::svc::event::Event;
-use crate::svc::ipc::{ClientSession, Port, ServerSession};
+use crate::svc::ipc::{ClientSession, PendingRequest, Port, ServerSession};
+use crate::svc::semaphore::Semaphore;
+use crate::svc::timer::Timer;
 
 #[derive(Debug, Clone)]
 pub enum HandleObject {
@@ -16,6 +20,11 @@ pub enum HandleObject {
     ServerSession(Arc<ServerSession>),
     ClientSession(Arc<ClientSession>),
     Event(Arc<Event>),
+    Timer(Arc<Timer>),
+    Semaphore(Arc<Semaphore>),
+    IPCRequest(Arc<PendingRequest>),
+    // Nothing but the rights on the handle, which let a process do things others can't.
+    Capability,
     Invalid,
 }
 
@@ -24,3 +33,16 @@ pub fn get_handle(reg: u32) -> HandleObject {
     let x = process_locked.lock().handle_table.get_object(reg);
     x
 }
+
+// Like get_handle, but fails unless the handle has all of the required rights.
+pub fn get_handle_with_rights(
+    reg: u32,
+    required: HandleRights,
+) -> Result<HandleObject, ResultCode> {
+    let process_locked = scheduler::get_current_process();
+    let x = process_locked
+        .lock()
+        .handle_table
+        .get_object_with_rights(reg, required)?;
+    Ok(x.0)
+}
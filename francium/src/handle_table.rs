@@ -1,11 +1,28 @@
 use crate::handle::HandleObject;
+use alloc::vec::Vec;
 use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+use francium_common::types::HandleRights;
+
+// Handle values are (generation << 16) | index. The generation is bumped every time a slot is
+// closed, so a stale handle value can't silently refer to whatever reuses its slot.
+const INDEX_BITS: u32 = 16;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+
+// A slot that has used up every generation is never handed out again, since the next one would
+// make its oldest handle values valid. No handle value has generation 0.
+const RETIRED: u16 = 0;
+
+// Index 0xffff is never handed out, so no handle can ever be INVALID_HANDLE (0xffffffff).
+const MAX_HANDLES: usize = INDEX_MASK as usize;
+
+struct HandleEntry {
+    generation: u16,
+    rights: HandleRights,
+    object: HandleObject,
+}
 
-// for now i will just fix the handle table size.
-// todo: dynamic
-const MAX_HANDLES: usize = 256;
 pub struct HandleTable {
-    handles: [HandleObject; MAX_HANDLES],
+    handles: Vec<HandleEntry>,
 }
 
 impl core::fmt::Debug for HandleTable {
@@ -14,46 +31,158 @@ impl core::fmt::Debug for HandleTable {
     }
 }
 
+fn make_handle(index: usize, generation: u16) -> u32 {
+    ((generation as u32) << INDEX_BITS) | index as u32
+}
+
 impl HandleTable {
     pub fn new() -> HandleTable {
-        const INVALID_HANDLE: HandleObject = HandleObject::Invalid;
         HandleTable {
-            handles: [INVALID_HANDLE; MAX_HANDLES],
+            handles: Vec::new(),
+        }
+    }
+
+    fn get_entry(&self, handle: u32) -> Option<&HandleEntry> {
+        let index = (handle & INDEX_MASK) as usize;
+        let generation = (handle >> INDEX_BITS) as u16;
+
+        match self.handles.get(index) {
+            Some(entry) if entry.generation == generation => Some(entry),
+            _ => None,
         }
     }
 
     pub fn get_object(&self, handle: u32) -> HandleObject {
-        if (handle as usize) < MAX_HANDLES {
-            self.handles[handle as usize].clone()
-        } else {
-            HandleObject::Invalid
+        match self.get_entry(handle) {
+            Some(entry) => entry.object.clone(),
+            None => HandleObject::Invalid,
         }
     }
 
-    pub fn get_handle(&mut self, handle_obj: HandleObject) -> u32 {
-        for (index, obj) in self.handles.iter().enumerate() {
-            match obj {
-                HandleObject::Invalid => {
-                    self.handles[index] = handle_obj;
-                    return index as u32;
+    // Look up an object, checking that the handle has all of the required rights.
+    pub fn get_object_with_rights(
+        &self,
+        handle: u32,
+        required: HandleRights,
+    ) -> Result<(HandleObject, HandleRights), ResultCode> {
+        match self.get_entry(handle) {
+            Some(HandleEntry {
+                object: HandleObject::Invalid,
+                ..
+            })
+            | None => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+            Some(entry) => {
+                if entry.rights.contains(required) {
+                    Ok((entry.object.clone(), entry.rights))
+                } else {
+                    Err(ResultCode::new(Module::Kernel, Reason::NotAllowed))
                 }
-                _ => continue,
             }
         }
-        panic!("handle table is exhausted!");
+    }
+
+    // How many handles are open.
+    pub fn count(&self) -> usize {
+        self.handles
+            .iter()
+            .filter(|entry| !matches!(entry.object, HandleObject::Invalid))
+            .count()
+    }
+
+    pub fn get_handle(&mut self, handle_obj: HandleObject) -> Result<u32, ResultCode> {
+        self.get_handle_with_rights(handle_obj, HandleRights::ALL)
+    }
+
+    pub fn get_handle_with_rights(
+        &mut self,
+        handle_obj: HandleObject,
+        rights: HandleRights,
+    ) -> Result<u32, ResultCode> {
+        for (index, entry) in self.handles.iter_mut().enumerate() {
+            if entry.generation == RETIRED {
+                continue;
+            }
+            if let HandleObject::Invalid = entry.object {
+                entry.object = handle_obj;
+                entry.rights = rights;
+                return Ok(make_handle(index, entry.generation));
+            }
+        }
+
+        if self.handles.len() >= MAX_HANDLES {
+            return Err(ResultCode::new(Module::Kernel, Reason::ResourceExhausted));
+        }
+
+        // Start at generation 1, so a zeroed handle value is never valid.
+        self.handles.push(HandleEntry {
+            generation: 1,
+            rights: rights,
+            object: handle_obj,
+        });
+        Ok(make_handle(self.handles.len() - 1, 1))
     }
 
     pub fn close(&mut self, handle: u32) -> ResultCode {
-        if (handle as usize) < MAX_HANDLES {
-            match self.handles[handle as usize] {
-                HandleObject::Invalid => ResultCode::new(Module::Kernel, Reason::InvalidHandle),
-                _ => {
-                    self.handles[handle as usize] = HandleObject::Invalid;
-                    RESULT_OK
-                }
+        let index = (handle & INDEX_MASK) as usize;
+        match self.get_entry(handle) {
+            Some(HandleEntry {
+                object: HandleObject::Invalid,
+                ..
+            })
+            | None => ResultCode::new(Module::Kernel, Reason::InvalidHandle),
+            Some(_) => {
+                let entry = &mut self.handles[index];
+                entry.object = HandleObject::Invalid;
+                entry.rights = HandleRights::empty();
+                entry.generation = entry.generation.wrapping_add(1);
+                RESULT_OK
             }
-        } else {
-            ResultCode::new(Module::Kernel, Reason::InvalidHandle)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::event::Event;
+    use alloc::sync::Arc;
+    use common::event::EventMode;
+
+    fn event() -> HandleObject {
+        HandleObject::Event(Arc::new(Event::new(EventMode::AutoReset)))
+    }
+
+    fn is_invalid_handle(table: &HandleTable, handle: u32) -> bool {
+        table
+            .get_object_with_rights(handle, HandleRights::empty())
+            .err()
+            == Some(ResultCode::new(Module::Kernel, Reason::InvalidHandle))
+    }
+
+    #[test]
+    fn closed_handles_stay_closed() {
+        let mut table = HandleTable::new();
+        let first = table.get_handle(event()).unwrap();
+        assert_eq!(table.close(first), RESULT_OK);
+
+        // Go through every generation the slot has, and then some.
+        let mut last = first;
+        for _ in 0..u16::MAX as usize + 16 {
+            last = table.get_handle(event()).unwrap();
+            assert!(is_invalid_handle(&table, first));
+            assert_eq!(table.close(last), RESULT_OK);
+        }
+
+        assert!(is_invalid_handle(&table, first));
+        assert!(is_invalid_handle(&table, last));
+        assert_eq!(
+            table.close(first),
+            ResultCode::new(Module::Kernel, Reason::InvalidHandle)
+        );
+
+        // The first slot was retired along the way, and the rest of the table still works.
+        assert_ne!(last & INDEX_MASK, first & INDEX_MASK);
+        let handle = table.get_handle(event()).unwrap();
+        assert!(!is_invalid_handle(&table, handle));
+    }
+}
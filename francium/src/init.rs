@@ -1,6 +1,7 @@
 use crate::arch::cache::clear_cache_for_address;
 use crate::arch::mmu::{get_current_page_table, invalidate_tlb_for_range};
 use crate::constants::*;
+use crate::handle::HandleObject;
 use crate::memory::AddressSpace;
 use crate::memory::KERNEL_ADDRESS_SPACE;
 use crate::mmu::{MapType, PagePermission};
@@ -8,8 +9,10 @@ use crate::phys_allocator;
 use crate::platform;
 use crate::process::{Process, Thread};
 use alloc::boxed::Box;
+use common::introspection::INTROSPECTION_HANDLE;
+use common::system_info::{MemoryRegion, MemoryRegionType};
 use francium_common::align::align_up;
-use francium_common::types::PhysAddr;
+use francium_common::types::{HandleRights, PhysAddr};
 
 use alloc::sync::Arc;
 use elf_rs::*;
@@ -289,10 +292,30 @@ pub fn load_process(elf_buf: &[u8], name: &'static str) -> Arc<Thread> {
     panic!("Failed to load process??");
 }
 
+// Lets a process look at the rest of the system, see INTROSPECTION_HANDLE. This has to happen before
+// it runs, while its handle table is still empty. It can pass the capability on to others.
+pub fn grant_introspection(thread: &Arc<Thread>) {
+    let mut process = thread.process.lock();
+    let handle = process
+        .handle_table
+        .get_handle_with_rights(
+            HandleObject::Capability,
+            HandleRights::INTROSPECT | HandleRights::TRANSFER | HandleRights::DUPLICATE,
+        )
+        .unwrap();
+    assert!(handle == INTROSPECTION_HANDLE.0);
+}
+
 pub fn setup_physical_allocator(start: usize, end: usize) {
     unsafe {
         let start: usize = align_up(start, 0x1000);
 
+        phys_allocator::add_region(MemoryRegion {
+            start,
+            length: end.saturating_sub(start),
+            ty: MemoryRegionType::Memory,
+        });
+
         let phys_mem_start = platform::PHYS_MEM_BASE;
 
         let text_start_virt = &__text_start as *const i32 as usize;
@@ -311,6 +334,15 @@ pub fn setup_physical_allocator(start: usize, end: usize) {
     }
 }
 
+// Memory the bootloader is still using. It isn't handed to the allocator, but it shows up in get_system_info.
+pub fn add_bootloader_region(start: usize, end: usize) {
+    phys_allocator::add_region(MemoryRegion {
+        start,
+        length: end.saturating_sub(start),
+        ty: MemoryRegionType::Bootloader,
+    });
+}
+
 pub fn setup_virtual_memory() {
     let page_table_root = &mut KERNEL_ADDRESS_SPACE.write().page_table;
 
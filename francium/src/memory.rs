@@ -1,5 +1,7 @@
-use crate::mmu::{MapType, PagePermission, PageTable};
+use crate::constants::PAGE_SIZE;
+use crate::mmu::{phys_to_virt, MapType, PagePermission, PageTable};
 use crate::phys_allocator;
+use common::os_error::{Module, Reason, ResultCode};
 use francium_common::types::PhysAddr;
 use smallvec::SmallVec;
 use spin::RwLock;
@@ -184,6 +186,82 @@ impl AddressSpace {
         panic!("Wtf?");
     }
 
+    // Is [address, address + size) entirely inside one user region that allows perm?
+    pub fn is_range_valid(&self, address: usize, size: usize, perm: PagePermission) -> bool {
+        let end = match address.checked_add(size) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        self.regions.iter().any(|reg| {
+            address >= reg.address
+                && end <= reg.address + reg.size
+                && !reg.permissions.contains(PagePermission::KERNEL)
+                && reg.permissions.contains(perm)
+        })
+    }
+
+    // Walk a user range a page at a time, handing f the kernel (physmap) address of each chunk.
+    // Going through the physmap means a bad user pointer can never fault inside the kernel.
+    fn for_each_user_chunk(
+        &self,
+        address: usize,
+        len: usize,
+        perm: PagePermission,
+        mut f: impl FnMut(usize, usize, usize),
+    ) -> Result<(), ResultCode> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        if !self.is_range_valid(address, len, perm) {
+            return Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer));
+        }
+
+        let mut offset = 0;
+        while offset < len {
+            let addr = address + offset;
+            let chunk_len = core::cmp::min(len - offset, PAGE_SIZE - (addr & (PAGE_SIZE - 1)));
+
+            match self.page_table.virt_to_phys(addr) {
+                Some(phys) => f(phys_to_virt(phys), offset, chunk_len),
+                None => return Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer)),
+            }
+            offset += chunk_len;
+        }
+        Ok(())
+    }
+
+    pub fn copy_from_user(&self, address: usize, buf: &mut [u8]) -> Result<(), ResultCode> {
+        self.for_each_user_chunk(
+            address,
+            buf.len(),
+            PagePermission::USER_READ_ONLY,
+            |kernel_addr, offset, chunk_len| unsafe {
+                core::ptr::copy_nonoverlapping(
+                    kernel_addr as *const u8,
+                    buf[offset..].as_mut_ptr(),
+                    chunk_len,
+                );
+            },
+        )
+    }
+
+    pub fn copy_to_user(&self, address: usize, buf: &[u8]) -> Result<(), ResultCode> {
+        self.for_each_user_chunk(
+            address,
+            buf.len(),
+            PagePermission::USER_READ_WRITE,
+            |kernel_addr, offset, chunk_len| unsafe {
+                core::ptr::copy_nonoverlapping(
+                    buf[offset..].as_ptr(),
+                    kernel_addr as *mut u8,
+                    chunk_len,
+                );
+            },
+        )
+    }
+
     pub fn make_active(&self) {
         unsafe {
             arch::mmu::switch_to_page_table(self.page_table_phys);
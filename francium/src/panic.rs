@@ -2,6 +2,7 @@ use crate::println;
 use core::panic::PanicInfo;
 
 /// This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     log::debug!("kernel {}", info);
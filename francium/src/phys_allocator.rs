@@ -1,10 +1,32 @@
 use crate::mmu::phys_to_virt;
+use common::system_info::{MemoryRegion, MemoryRegionType};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use francium_common::types::PhysAddr;
+use spin::Mutex;
 
 // classic 4k pages everywhere
 // dumb linked list
 
 static mut PHYS_FREELIST: Option<PhysAddr> = None;
+static FREE_PAGES: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+// This gets filled in before the heap exists, so it can't be a Vec.
+const MAX_MEMORY_REGIONS: usize = 64;
+
+struct MemoryRegionTable {
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    count: usize,
+}
+
+static MEMORY_REGIONS: Mutex<MemoryRegionTable> = Mutex::new(MemoryRegionTable {
+    regions: [MemoryRegion {
+        start: 0,
+        length: 0,
+        ty: MemoryRegionType::None,
+    }; MAX_MEMORY_REGIONS],
+    count: 0,
+});
 
 #[derive(Copy, Clone)]
 struct PhysEntry {
@@ -28,6 +50,7 @@ pub unsafe fn alloc() -> Option<PhysAddr> {
     let entry = read_phys::<PhysEntry>(freelist_addr);
 
     PHYS_FREELIST = entry.next;
+    FREE_PAGES.fetch_sub(1, Ordering::Relaxed);
 
     Some(freelist_addr)
 }
@@ -40,4 +63,32 @@ pub unsafe fn free(addr: PhysAddr) {
 
     write_phys::<PhysEntry>(addr, entry);
     PHYS_FREELIST = Some(addr);
+    FREE_PAGES.fetch_add(1, Ordering::Relaxed);
+}
+
+// Regions past MAX_MEMORY_REGIONS still get used, they just won't be reported.
+pub fn add_region(region: MemoryRegion) {
+    if region.ty == MemoryRegionType::Memory {
+        TOTAL_PAGES.fetch_add(region.length / 4096, Ordering::Relaxed);
+    }
+
+    let mut table = MEMORY_REGIONS.lock();
+    if table.count < MAX_MEMORY_REGIONS {
+        let index = table.count;
+        table.regions[index] = region;
+        table.count += 1;
+    }
+}
+
+pub fn get_region(index: usize) -> Option<MemoryRegion> {
+    let table = MEMORY_REGIONS.lock();
+    table.regions[..table.count].get(index).copied()
+}
+
+pub fn free_pages() -> usize {
+    FREE_PAGES.load(Ordering::Relaxed)
+}
+
+pub fn total_pages() -> usize {
+    TOTAL_PAGES.load(Ordering::Relaxed)
 }
@@ -13,6 +13,9 @@ pub mod raspi4;
 #[cfg(feature = "platform_raspi4")]
 pub use raspi4::*;
 
+#[cfg(any(feature = "platform_raspi3", feature = "platform_raspi4"))]
+mod raspi_framebuffer;
+
 #[cfg(feature = "platform_pc")]
 pub mod pc;
 #[cfg(feature = "platform_pc")]
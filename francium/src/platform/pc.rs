@@ -10,7 +10,7 @@ use acpi::platform::ProcessorState::WaitingForSipi;
 use alloc::alloc::{alloc_zeroed, Layout};
 use alloc::vec::Vec;
 use core::arch::asm;
-use francium_common::types::PhysAddr;
+use francium_common::types::{FramebufferInfo, PhysAddr};
 use spin::Mutex;
 
 pub const PHYS_MEM_BASE: usize = 0;
@@ -209,4 +209,9 @@ pub fn bringup_other_cpus() {
 pub fn get_cpu_count() -> usize {
     let processor_info = PLATFORM_INFO.processor_info.as_ref().unwrap();
     processor_info.application_processors.len() + 1
-}
\ No newline at end of file
+}
+
+// The bootloader sets this one up, and francium_pc records it.
+pub fn get_framebuffer_info() -> Option<FramebufferInfo> {
+    unsafe { crate::arch::x86_64::info::FRAMEBUFFER_INFO.clone() }
+}
@@ -114,6 +114,12 @@ pub fn scheduler_post_init() {
 
 pub fn bringup_other_cpus() {}
 
+pub fn framebuffer_init() {
+    super::raspi_framebuffer::framebuffer_init(RPI_PERIPHERAL_BASE);
+}
+
+pub use super::raspi_framebuffer::get_framebuffer_info;
+
 use core::arch::global_asm;
 global_asm!(include_str!("../arch/aarch64/asm/stub_raspi3.s"));
 
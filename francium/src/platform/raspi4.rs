@@ -106,6 +106,12 @@ pub fn scheduler_post_init() {
 
 pub fn bringup_other_cpus() {}
 
+pub fn framebuffer_init() {
+    super::raspi_framebuffer::framebuffer_init(PERIPHERAL_BASE + 0xfe000000);
+}
+
+pub use super::raspi_framebuffer::get_framebuffer_info;
+
 use core::arch::global_asm;
 global_asm!(include_str!("../arch/aarch64/asm/stub_raspi4.s"));
 
@@ -0,0 +1,54 @@
+use crate::arch::cache::clean_invalidate_range;
+use crate::drivers::bcm_mailbox::*;
+use crate::mmu::phys_to_virt;
+use crate::phys_allocator;
+use francium_common::types::{FramebufferFormat, FramebufferInfo};
+use spin::Mutex;
+
+// Same mode disp used to ask the firmware for itself.
+const FRAMEBUFFER_WIDTH: u32 = 1920;
+const FRAMEBUFFER_HEIGHT: u32 = 1080;
+
+static FRAMEBUFFER_INFO: Mutex<Option<FramebufferInfo>> = Mutex::new(None);
+
+pub fn framebuffer_init(peripheral_base: usize) {
+    let mut mailbox = BCMMailbox::new(peripheral_base + 0xb880);
+
+    unsafe {
+        let buffer_phys = phys_allocator::alloc().unwrap();
+        let buffer_virt = phys_to_virt(buffer_phys);
+        let buffer = core::slice::from_raw_parts_mut(buffer_virt as *mut u32, 1024);
+
+        let length = write_framebuffer_request(buffer, FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT);
+        clean_invalidate_range(buffer_virt, length);
+        let answered = mailbox.call_property(buffer_phys.0 as u32);
+        clean_invalidate_range(buffer_virt, length);
+
+        let reply = if answered {
+            read_framebuffer_reply(buffer)
+        } else {
+            None
+        };
+        phys_allocator::free(buffer_phys);
+
+        match reply {
+            Some(fb) => {
+                *FRAMEBUFFER_INFO.lock() = Some(FramebufferInfo {
+                    // The firmware hands back a VideoCore bus address.
+                    phys_addr: (fb.bus_addr & 0x3fffffff) as usize,
+                    size: fb.size as usize,
+                    pixel_format: FramebufferFormat::Rgb,
+                    width: fb.width as usize,
+                    height: fb.height as usize,
+                    stride: (fb.pitch / 4) as usize,
+                    bytes_per_pixel: 4,
+                });
+            }
+            None => println!("Firmware didn't give us a framebuffer"),
+        }
+    }
+}
+
+pub fn get_framebuffer_info() -> Option<FramebufferInfo> {
+    FRAMEBUFFER_INFO.lock().clone()
+}
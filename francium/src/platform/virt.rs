@@ -2,12 +2,17 @@ use crate::arch::arch_timer::ArchTimer;
 use crate::constants;
 use crate::drivers::arm_gicv2::*;
 use crate::drivers::pl011_uart::Pl011Uart;
+use crate::drivers::qemu_fw_cfg::{FwCfg, FwCfgDmaAccess, RamfbConfig};
 use crate::drivers::Timer;
 use crate::drivers::{InterruptController, InterruptDistributor};
+use crate::mmu::phys_to_virt;
+use crate::phys_allocator;
+use francium_common::types::{FramebufferFormat, FramebufferInfo};
 use spin::Mutex;
 
 const VIRT_GICD_BASE: usize = constants::PERIPHERAL_BASE + 0x08000000;
 const VIRT_GICC_BASE: usize = constants::PERIPHERAL_BASE + 0x08010000;
+const VIRT_FW_CFG_BASE: usize = constants::PERIPHERAL_BASE + 0x09020000;
 
 lazy_static! {
     // Qemu doesn't care about the baud rate, but we give it one and a UART clock anyway.
@@ -20,6 +25,15 @@ lazy_static! {
 pub const PHYS_MEM_BASE: usize = 0x40000000;
 pub const PHYS_MEM_SIZE: usize = 0x80000000; // idk 2 gig
 
+// ramfb scans out of guest RAM, so the top of memory is kept back for it. main stops the physical
+// allocator short of this.
+const FRAMEBUFFER_WIDTH: usize = 1024;
+const FRAMEBUFFER_HEIGHT: usize = 768;
+const FRAMEBUFFER_SIZE: usize = FRAMEBUFFER_WIDTH * FRAMEBUFFER_HEIGHT * 4;
+pub const FRAMEBUFFER_PHYS: usize = PHYS_MEM_BASE + PHYS_MEM_SIZE - FRAMEBUFFER_SIZE;
+
+static FRAMEBUFFER_INFO: Mutex<Option<FramebufferInfo>> = Mutex::new(None);
+
 pub fn platform_specific_init() {
     // nothing, for now
 }
@@ -46,6 +60,59 @@ pub fn scheduler_post_init() {
 
 pub fn bringup_other_cpus() {}
 
+// Only does anything if qemu was started with -device ramfb.
+pub fn framebuffer_init() {
+    let mut fw_cfg = FwCfg::new(VIRT_FW_CFG_BASE);
+    if !fw_cfg.is_present() {
+        return;
+    }
+    let Some(ramfb) = fw_cfg.find_file("etc/ramfb") else {
+        return;
+    };
+
+    unsafe {
+        // The device reads the request and the config out of RAM, so they share a scratch page.
+        let scratch_phys = phys_allocator::alloc().unwrap();
+        let scratch_virt = phys_to_virt(scratch_phys);
+        let access = scratch_virt as *mut FwCfgDmaAccess;
+        let config_offset = core::mem::size_of::<FwCfgDmaAccess>();
+        let config = (scratch_virt + config_offset) as *mut RamfbConfig;
+
+        config.write_unaligned(RamfbConfig::new(
+            FRAMEBUFFER_PHYS as u64,
+            FRAMEBUFFER_WIDTH as u32,
+            FRAMEBUFFER_HEIGHT as u32,
+        ));
+        let written = fw_cfg.dma_write(
+            ramfb,
+            access,
+            scratch_phys.0 as u64,
+            (scratch_phys.0 + config_offset) as u64,
+            core::mem::size_of::<RamfbConfig>() as u32,
+        );
+        phys_allocator::free(scratch_phys);
+
+        if !written {
+            println!("Couldn't configure ramfb");
+            return;
+        }
+    }
+
+    *FRAMEBUFFER_INFO.lock() = Some(FramebufferInfo {
+        phys_addr: FRAMEBUFFER_PHYS,
+        size: FRAMEBUFFER_SIZE,
+        pixel_format: FramebufferFormat::Rgb,
+        width: FRAMEBUFFER_WIDTH,
+        height: FRAMEBUFFER_HEIGHT,
+        stride: FRAMEBUFFER_WIDTH,
+        bytes_per_pixel: 4,
+    });
+}
+
+pub fn get_framebuffer_info() -> Option<FramebufferInfo> {
+    FRAMEBUFFER_INFO.lock().clone()
+}
+
 use core::arch::global_asm;
 global_asm!(include_str!("../arch/aarch64/asm/stub_virt.s"));
 
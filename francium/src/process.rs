@@ -7,7 +7,7 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use atomic_enum::atomic_enum;
 use core::sync::atomic::Ordering;
-use core::sync::atomic::{AtomicBool, AtomicUsize};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
 use spin::Mutex;
 
 use intrusive_collections::intrusive_adapter;
@@ -37,6 +37,13 @@ pub struct Thread {
 
     pub is_idle_thread: AtomicBool,
     pub last_svc_number: AtomicUsize,
+
+    // Kept up to date by the scheduler when switching threads.
+    pub cpu_time_ns: AtomicU64,
+    pub switched_in_at_ns: AtomicU64,
+
+    // The futex this thread is queued on, if it's in futex_wait. Requeueing keeps it up to date.
+    pub futex_key: AtomicUsize,
 }
 
 intrusive_adapter!(pub ThreadProcessAdapter = Arc<Thread>: Thread { process_link: LinkedListAtomicLink });
@@ -89,6 +96,9 @@ impl Thread {
             kernel_stack_size: kernel_stack_size,
             is_idle_thread: AtomicBool::new(false),
             last_svc_number: AtomicUsize::new(0),
+            cpu_time_ns: AtomicU64::new(0),
+            switched_in_at_ns: AtomicU64::new(0),
+            futex_key: AtomicUsize::new(0),
         });
 
         process.lock().threads.push_back(thread.clone());
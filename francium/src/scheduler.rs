@@ -5,6 +5,7 @@ use spin::{Mutex, MutexGuard};
 
 use crate::arch::context::ThreadContext;
 use crate::process::{Process, Thread, ThreadState};
+use crate::timer;
 
 use intrusive_collections::intrusive_adapter;
 use intrusive_collections::{LinkedList, LinkedListAtomicLink};
@@ -53,6 +54,15 @@ fn set_thread_context_tag(p: &Arc<Thread>, tag: usize) {
 #[cfg(target_arch = "x86_64")]
 use crate::arch;
 
+// Charge from for the time since it was switched to, and start the clock for to.
+fn account_cpu_time(from: &Arc<Thread>, to: &Arc<Thread>) {
+    let now = timer::get_counter_ns();
+    let switched_in_at = from.switched_in_at_ns.load(Ordering::Relaxed);
+    from.cpu_time_ns
+        .fetch_add(now.saturating_sub(switched_in_at), Ordering::Relaxed);
+    to.switched_in_at_ns.store(now, Ordering::Relaxed);
+}
+
 #[cfg(target_arch = "x86_64")]
 pub unsafe fn set_current_thread_state(kernel_stack: usize, tls: usize) {
     crate::per_cpu::get().saved_kernel_stack = kernel_stack;
@@ -88,6 +98,7 @@ impl Scheduler {
         }
 
         crate::per_cpu::set_current_thread(to.clone());
+        account_cpu_time(from, to);
 
         // TODO: wow, this sucks
         {
@@ -228,7 +239,7 @@ impl Scheduler {
         0
     }
 
-    pub fn wake(&mut self, thread: &Arc<Thread>, tag: usize) {
+    pub fn wake(&mut self, thread: &Arc<Thread>, tag: usize) -> bool {
         if thread.state.load(Ordering::Acquire) != ThreadState::Runnable {
             trace!(
                 "Waking thread {:?} ({})",
@@ -247,9 +258,11 @@ impl Scheduler {
             //self.switch_thread(&current_thread, thread);
 
             // Do it outside, instead.
+            true
         } else {
             // This should be OK, hopefully.
             trace!("Trying to re-wake thread {:?}!", thread.id);
+            false
         }
     }
 
@@ -348,6 +361,19 @@ pub fn register_thread(thread: Arc<Thread>) {
     sched.runnable_threads.push_back(thread);
 }
 
+// Every thread that hasn't exited, including the idle threads.
+pub fn get_all_threads() -> Vec<Arc<Thread>> {
+    let sched = SCHEDULER.lock();
+    let mut threads = Vec::new();
+
+    let mut cursor = sched.threads.front();
+    while let Some(thread) = cursor.clone_pointer() {
+        threads.push(thread);
+        cursor.move_next();
+    }
+    threads
+}
+
 pub fn get_current_thread() -> Arc<Thread> {
     crate::per_cpu::get_current_thread()
 }
@@ -368,9 +394,25 @@ pub fn suspend_current_thread() -> usize {
     return sched.suspend(&curr);
 }
 
-pub fn wake_thread(p: &Arc<Thread>, tag: usize) {
+// Returns false if the thread was already runnable (eg. it timed out), and so didn't get the tag.
+pub fn wake_thread(p: &Arc<Thread>, tag: usize) -> bool {
     let mut sched = SCHEDULER.lock();
-    sched.wake(p, tag);
+    sched.wake(p, tag)
+}
+
+// Like wake_thread, but runs callback first if the thread is going to be woken. Both happen under
+// the scheduler lock, so the thread can't be woken elsewhere (or run) in between.
+pub fn wake_thread_with_callback(
+    p: &Arc<Thread>,
+    tag: usize,
+    callback: &dyn Fn(&Arc<Thread>),
+) -> bool {
+    let mut sched = SCHEDULER.lock();
+    if p.state.load(Ordering::Acquire) == ThreadState::Runnable {
+        return false;
+    }
+    callback(p);
+    sched.wake(p, tag)
 }
 
 pub fn terminate_current_thread() {
@@ -409,6 +451,9 @@ pub fn force_switch_to(thread: Arc<Thread>) {
     {
         thread.state.store(ThreadState::Runnable, Ordering::Release);
         crate::per_cpu::set_current_thread(thread.clone());
+        thread
+            .switched_in_at_ns
+            .store(timer::get_counter_ns(), Ordering::Relaxed);
     }
 
     thread.process.lock().use_pages();
@@ -1,17 +1,27 @@
-pub fn svc_debug_output(user_ptr: *const u8, len: usize) {
-    let mut temp_buffer: [u8; 1024] = [0; 1024];
-    unsafe {
-        core::ptr::copy_nonoverlapping(user_ptr, temp_buffer.as_mut_ptr(), len);
+use crate::svc::user;
+use alloc::string::String;
+use alloc::vec;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+
+// Don't let userspace make us allocate an arbitrary amount of memory.
+const MAX_DEBUG_OUTPUT_LEN: usize = 0x10000;
+
+pub fn svc_debug_output(user_ptr: *const u8, len: usize) -> ResultCode {
+    if len > MAX_DEBUG_OUTPUT_LEN {
+        return ResultCode::new(Module::Kernel, Reason::InvalidArgument);
+    }
+
+    let mut temp_buffer = vec![0u8; len];
+    if let Err(res) = user::copy_from_user(user_ptr as usize, &mut temp_buffer) {
+        return res;
     }
 
     // These are on seperate lines so a potential panic doesn't occur inside the print
     // (which locks the serial port, leading to a hang when panic tries to print).
-    let as_utf8 = core::str::from_utf8(&temp_buffer[0..len]).unwrap();
+    let as_utf8 = String::from_utf8_lossy(&temp_buffer);
 
     // Strip a newline off the end, if it's present. Log will add one for us.
-    if &as_utf8[len - 1..len] == "\n" {
-        log::debug!("{}", &as_utf8[0..len - 1]);
-    } else {
-        log::debug!("{}", as_utf8);
-    }
+    log::debug!("{}", as_utf8.strip_suffix('\n').unwrap_or(&as_utf8));
+
+    RESULT_OK
 }
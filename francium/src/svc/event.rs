@@ -1,90 +1,137 @@
 use crate::drivers::InterruptDistributor;
+use crate::handle;
 use crate::handle::HandleObject;
 use crate::platform::{INTERRUPT_CONTROLLER, INTERRUPT_DISTRIBUTOR};
 use crate::scheduler;
 use crate::waitable::{Waitable, Waiter};
 use alloc::sync::Arc;
+use common::event::EventMode;
 use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
-use core::sync::atomic::AtomicU32;
+use core::convert::TryFrom;
 use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, AtomicU32};
+use francium_common::types::HandleRights;
 use francium_drivers::InterruptController;
 use spin::Mutex;
 
 #[derive(Debug)]
 pub struct Event {
     pub interrupt: AtomicU32,
+    mode: EventMode,
+    // Only used by manual reset events, auto reset events latch in the waiter instead.
+    signalled: AtomicBool,
     w: Waiter,
 }
 
 impl Event {
-    fn new() -> Event {
+    pub(crate) fn new(mode: EventMode) -> Event {
         Event {
             interrupt: AtomicU32::new(0),
+            mode: mode,
+            signalled: AtomicBool::new(false),
             w: Waiter::new(),
         }
     }
+
+    pub fn signal(&self) {
+        if self.signal_without_tick() {
+            scheduler::tick();
+        }
+    }
+
+    // Returns whether anything was woken, so the caller can reschedule when it is safe to.
+    pub fn signal_without_tick(&self) -> bool {
+        match self.mode {
+            EventMode::AutoReset => self.w.signal_one(false),
+            EventMode::ManualReset => {
+                self.signalled.store(true, Ordering::Release);
+                self.w.signal_n(usize::MAX) != 0
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        self.signalled.store(false, Ordering::Release);
+        self.w.clear();
+    }
 }
 
 impl Waitable for Event {
     fn get_waiter(&self) -> &Waiter {
         &self.w
     }
+
+    fn post_wait(&self, tag: usize) -> bool {
+        // Manual reset events stay signalled, so waiting doesn't consume anything.
+        if self.mode == EventMode::ManualReset && self.signalled.load(Ordering::Acquire) {
+            return true;
+        }
+        self.w.post_wait(tag)
+    }
 }
 
-pub fn svc_create_event() -> (ResultCode, u32) {
-    let ev = Event::new();
+pub fn svc_create_event(mode: usize) -> (ResultCode, u32) {
+    let mode = match EventMode::try_from(mode) {
+        Ok(mode) => mode,
+        Err(_) => return (ResultCode::new(Module::Kernel, Reason::InvalidArgument), 0),
+    };
+    let ev = Event::new(mode);
 
     let proc_locked = scheduler::get_current_process();
     let mut process = proc_locked.lock();
 
-    let handle_value = process
+    match process
         .handle_table
-        .get_handle(HandleObject::Event(Arc::new(ev)));
-
-    (RESULT_OK, handle_value)
+        .get_handle(HandleObject::Event(Arc::new(ev)))
+    {
+        Ok(handle_value) => (RESULT_OK, handle_value),
+        Err(res) => (res, 0),
+    }
 }
 
+// Events have no names, so another process signals one through a handle it was given. The owner
+// duplicates down to SIGNAL | TRANSFER and sends that over IPC, and the other side can signal but
+// never wait or clear.
 pub fn svc_signal_event(h: u32) -> ResultCode {
-    let proc = scheduler::get_current_process();
-    let proc_locked = proc.lock();
-
-    if let HandleObject::Event(ev) = proc_locked.handle_table.get_object(h) {
-        drop(proc_locked);
-
-        ev.w.signal_one(true);
-        RESULT_OK
-    } else {
-        ResultCode::new(Module::Kernel, Reason::InvalidHandle)
+    match handle::get_handle_with_rights(h, HandleRights::SIGNAL) {
+        Ok(HandleObject::Event(ev)) => {
+            ev.signal();
+            RESULT_OK
+        }
+        Ok(_) => ResultCode::new(Module::Kernel, Reason::InvalidHandle),
+        Err(res) => res,
     }
 }
 
+// Clearing only needs WAIT, since waiting on an interrupt event means acking it afterwards.
 pub fn svc_clear_event(h: u32) -> ResultCode {
-    let proc_locked = scheduler::get_current_process();
-    let process = proc_locked.lock();
-
-    if let HandleObject::Event(ev) = process.handle_table.get_object(h) {
-        ev.w.clear();
-
-        let interrupt_id = ev.interrupt.load(Ordering::Acquire);
-        if interrupt_id != 0 {
-            log::trace!("clearing interrupt event {}", interrupt_id);
-            INTERRUPT_CONTROLLER.lock().ack_interrupt(interrupt_id);
-        }
-        RESULT_OK
-    } else {
-        ResultCode::new(Module::Kernel, Reason::InvalidHandle)
+    let ev = match handle::get_handle_with_rights(h, HandleRights::WAIT) {
+        Ok(HandleObject::Event(ev)) => ev,
+        Ok(_) => return ResultCode::new(Module::Kernel, Reason::InvalidHandle),
+        Err(res) => return res,
+    };
+
+    ev.clear();
+
+    let interrupt_id = ev.interrupt.load(Ordering::Acquire);
+    if interrupt_id != 0 {
+        log::trace!("clearing interrupt event {}", interrupt_id);
+        INTERRUPT_CONTROLLER.lock().ack_interrupt(interrupt_id);
     }
+    RESULT_OK
 }
 
 const NO_EVENT: Option<Arc<Event>> = None;
-pub static INTERRUPT_EVENT_TABLE: Mutex<[Option<Arc<Event>>; 128]> = Mutex::new([NO_EVENT; 128]);
+const INTERRUPT_COUNT: usize = 128;
+pub static INTERRUPT_EVENT_TABLE: Mutex<[Option<Arc<Event>>; INTERRUPT_COUNT]> =
+    Mutex::new([NO_EVENT; INTERRUPT_COUNT]);
 
 pub fn dispatch_interrupt_event(index: usize) -> bool {
     if let Some(ev) = &INTERRUPT_EVENT_TABLE.lock()[index] {
         unsafe {
             INTERRUPT_EVENT_TABLE.force_unlock();
         }
-        ev.w.signal_one(true);
+        ev.signal();
         true
     } else {
         false
@@ -92,38 +139,49 @@ pub fn dispatch_interrupt_event(index: usize) -> bool {
 }
 
 pub fn svc_bind_interrupt(h: u32, index: usize) -> ResultCode {
-    let proc_locked = scheduler::get_current_process();
-    let process = proc_locked.lock();
+    if index >= INTERRUPT_COUNT {
+        return ResultCode::new(Module::Kernel, Reason::InvalidArgument);
+    }
 
-    if let HandleObject::Event(ev) = process.handle_table.get_object(h) {
-        let mut lock = INTERRUPT_EVENT_TABLE.lock();
-        if let None = lock[index] {
-            ev.interrupt.store(index as u32, Ordering::Release);
-            lock[index] = Some(ev);
-            INTERRUPT_DISTRIBUTOR.lock().enable_interrupt(index as u32);
+    let ev = match handle::get_handle_with_rights(h, HandleRights::BIND_INTERRUPT) {
+        Ok(HandleObject::Event(ev)) => ev,
+        Ok(_) => return ResultCode::new(Module::Kernel, Reason::InvalidHandle),
+        Err(res) => return res,
+    };
 
-            RESULT_OK
-        } else {
-            ResultCode::new(Module::Kernel, Reason::Unknown)
-        }
+    let mut lock = INTERRUPT_EVENT_TABLE.lock();
+    if let None = lock[index] {
+        ev.interrupt.store(index as u32, Ordering::Release);
+        lock[index] = Some(ev);
+        INTERRUPT_DISTRIBUTOR.lock().enable_interrupt(index as u32);
+
+        RESULT_OK
     } else {
-        ResultCode::new(Module::Kernel, Reason::InvalidHandle)
+        ResultCode::new(Module::Kernel, Reason::Unknown)
     }
 }
 
 pub fn svc_unbind_interrupt(h: u32, index: usize) -> ResultCode {
-    let proc_locked = scheduler::get_current_process();
-    let process = proc_locked.lock();
+    if index >= INTERRUPT_COUNT {
+        return ResultCode::new(Module::Kernel, Reason::InvalidArgument);
+    }
+
+    let ev = match handle::get_handle_with_rights(h, HandleRights::BIND_INTERRUPT) {
+        Ok(HandleObject::Event(ev)) => ev,
+        Ok(_) => return ResultCode::new(Module::Kernel, Reason::InvalidHandle),
+        Err(res) => return res,
+    };
 
-    if let HandleObject::Event(_ev) = process.handle_table.get_object(h) {
-        let mut lock = INTERRUPT_EVENT_TABLE.lock();
-        if let Some(_x) = &lock[index] {
+    let mut lock = INTERRUPT_EVENT_TABLE.lock();
+    match &lock[index] {
+        // Only the event that is actually bound can unbind the interrupt.
+        Some(x) if Arc::ptr_eq(x, &ev) => {
             lock[index] = None;
+            ev.interrupt.store(0, Ordering::Release);
             INTERRUPT_DISTRIBUTOR.lock().disable_interrupt(index as u32);
-        } else {
-            return ResultCode::new(Module::Kernel, Reason::Unknown);
+            RESULT_OK
         }
+        Some(_) => ResultCode::new(Module::Kernel, Reason::NotAllowed),
+        None => ResultCode::new(Module::Kernel, Reason::NotFound),
     }
-
-    ResultCode::new(Module::Kernel, Reason::InvalidHandle)
 }
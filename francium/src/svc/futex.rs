@@ -1,62 +1,210 @@
 use tracing::{event, Level};
 
+use crate::mmu::{phys_to_virt, PagePermission};
 use crate::scheduler;
+use crate::waitable;
 use crate::waitable::Waiter;
 use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
 use core::sync::atomic::{AtomicU32, Ordering};
+use francium_common::types::PhysAddr;
 use hashbrown::{hash_map::Entry, HashMap};
 use spin::Mutex;
 
+// Futexes are keyed by physical address, so that they work across shared memory.
 lazy_static! {
     static ref FUTEX_TABLE: Mutex<HashMap<usize, Waiter>> = Mutex::new(HashMap::new());
 }
 
-pub fn svc_futex_wait(addr: usize, expected: u32, _timeout_ns: usize) -> ResultCode {
+// Validate a user futex address, and translate it into a key for FUTEX_TABLE.
+fn get_futex_key(addr: usize) -> Result<usize, ResultCode> {
+    if addr & (core::mem::size_of::<u32>() - 1) != 0 {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer));
+    }
+
+    let proc = scheduler::get_current_process();
+    let locked = proc.lock();
+    let aspace = &locked.address_space;
+
+    if !aspace.is_range_valid(
+        addr,
+        core::mem::size_of::<u32>(),
+        PagePermission::USER_READ_ONLY,
+    ) {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer));
+    }
+
+    match aspace.page_table.virt_to_phys(addr) {
+        Some(phys) => Ok(phys.0),
+        None => Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer)),
+    }
+}
+
+fn load_futex(key: usize) -> u32 {
+    unsafe { (*(phys_to_virt(PhysAddr(key)) as *const AtomicU32)).load(Ordering::SeqCst) }
+}
+
+// Drop any entries nobody is waiting on any more.
+fn cleanup_futex_table(table: &mut HashMap<usize, Waiter>) {
+    table.retain(|_, waiter| waiter.has_waiters());
+}
+
+pub fn svc_futex_wait(addr: usize, expected: u32, timeout_ns: u64) -> ResultCode {
     event!(
         Level::TRACE,
         svc_name = "futex_wait",
         addr = addr,
         expected = expected,
-        timeout = _timeout_ns
+        timeout = timeout_ns
     );
-    // TODO: uhhh
-    let futex_valid = unsafe { (*(addr as *mut AtomicU32)).load(Ordering::SeqCst) == expected };
-
-    if futex_valid {
-        {
-            let mut table_lock = FUTEX_TABLE.lock();
-            let waiter = match table_lock.entry(addr) {
-                Entry::Occupied(o) => o.into_mut(),
-                Entry::Vacant(v) => v.insert(Waiter::new()),
-            };
-            waiter.post_wait(0);
+
+    let key = match get_futex_key(addr) {
+        Ok(key) => key,
+        Err(res) => return res,
+    };
+
+    {
+        // Check the value under the table lock, so we can't miss a wake.
+        let mut table_lock = FUTEX_TABLE.lock();
+        if load_futex(key) != expected {
+            return ResultCode::new(Module::Kernel, Reason::TryAgain);
         }
-        scheduler::suspend_current_thread();
 
-        RESULT_OK
-    } else {
-        ResultCode::new(Module::Kernel, Reason::TryAgain)
+        if timeout_ns == 0 {
+            return ResultCode::new(Module::Kernel, Reason::TimedOut);
+        }
+
+        let waiter = match table_lock.entry(key) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => v.insert(Waiter::new()),
+        };
+        scheduler::get_current_thread()
+            .futex_key
+            .store(key, Ordering::Relaxed);
+        waiter.post_wait(0);
     }
+
+    let tag = waitable::suspend_current_thread_timeout(timeout_ns);
+    if tag == waitable::TIMED_OUT_TAG {
+        // We may have been requeued since, which keeps futex_key up to date.
+        let mut table_lock = FUTEX_TABLE.lock();
+        let key = scheduler::get_current_thread()
+            .futex_key
+            .load(Ordering::Relaxed);
+        if let Some(waiter) = table_lock.get(&key) {
+            waiter.remove_wait();
+        }
+        cleanup_futex_table(&mut table_lock);
+
+        return ResultCode::new(Module::Kernel, Reason::TimedOut);
+    }
+
+    RESULT_OK
 }
 
+// The original form, which wakes everything.
 pub fn svc_futex_wake(addr: usize) -> ResultCode {
     event!(Level::TRACE, svc_name = "futex_wake", addr = addr);
 
-    let mut did_wake = false;
-    match FUTEX_TABLE.lock().get(&addr) {
-        Some(x) => {
-            x.signal_all();
-            did_wake = true;
-        }
-        None => {
-            // TODO: what do we do? currently nothing
-            //println!("No futex!");
-        }
+    wake_futex(addr, usize::MAX).0
+}
+
+pub fn svc_futex_wake_count(addr: usize, count: usize) -> (ResultCode, usize) {
+    event!(
+        Level::TRACE,
+        svc_name = "futex_wake_count",
+        addr = addr,
+        count = count
+    );
+
+    wake_futex(addr, count)
+}
+
+fn wake_futex(addr: usize, count: usize) -> (ResultCode, usize) {
+    let key = match get_futex_key(addr) {
+        Ok(key) => key,
+        Err(res) => return (res, 0),
+    };
+
+    let woken = {
+        let mut table_lock = FUTEX_TABLE.lock();
+        let woken = match table_lock.get(&key) {
+            Some(x) => x.signal_n(count),
+            None => 0,
+        };
+        cleanup_futex_table(&mut table_lock);
+        woken
+    };
+
+    if woken != 0 {
+        scheduler::tick();
     }
 
-    if did_wake {
+    (RESULT_OK, woken)
+}
+
+pub fn svc_futex_requeue(
+    addr: usize,
+    expected: u32,
+    wake_count: usize,
+    target_addr: usize,
+    requeue_count: usize,
+) -> (ResultCode, usize) {
+    event!(
+        Level::TRACE,
+        svc_name = "futex_requeue",
+        addr = addr,
+        expected = expected,
+        wake_count = wake_count,
+        target_addr = target_addr,
+        requeue_count = requeue_count
+    );
+
+    let key = match get_futex_key(addr) {
+        Ok(key) => key,
+        Err(res) => return (res, 0),
+    };
+
+    let target_key = match get_futex_key(target_addr) {
+        Ok(key) => key,
+        Err(res) => return (res, 0),
+    };
+
+    let woken = {
+        let mut table_lock = FUTEX_TABLE.lock();
+        if load_futex(key) != expected {
+            return (ResultCode::new(Module::Kernel, Reason::TryAgain), 0);
+        }
+
+        let has_waiters = table_lock.get(&key).map_or(false, |x| x.has_waiters());
+        let woken = if has_waiters {
+            // Make sure the target exists before we take references into the table.
+            if key != target_key {
+                table_lock.entry(target_key).or_insert_with(Waiter::new);
+            }
+
+            let waiter = table_lock.get(&key).unwrap();
+            let woken = waiter.signal_n(wake_count);
+
+            // Requeueing onto the same futex is a no-op.
+            if key != target_key {
+                waiter.requeue_to(
+                    table_lock.get(&target_key).unwrap(),
+                    requeue_count,
+                    &|thread| thread.futex_key.store(target_key, Ordering::Relaxed),
+                );
+            }
+            woken
+        } else {
+            0
+        };
+
+        cleanup_futex_table(&mut table_lock);
+        woken
+    };
+
+    if woken != 0 {
         scheduler::tick();
     }
 
-    RESULT_OK
+    (RESULT_OK, woken)
 }
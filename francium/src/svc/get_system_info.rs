@@ -1,59 +1,130 @@
-use common::os_error::{ResultCode, RESULT_OK};
+use crate::constants::PAGE_SIZE;
+use crate::phys_allocator;
+use crate::platform;
+use crate::svc::user;
+use crate::svc::user::UserData;
+use crate::timer;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
 use common::system_info::*;
+use core::convert::TryFrom;
+use core::mem::size_of;
+
+// Where a variant's fields are in info, and their bytes.
+fn payload<'a, T: UserData>(info: &SystemInfo, fields: &'a T) -> (usize, &'a [u8]) {
+    let offset = fields as *const T as usize - info as *const SystemInfo as usize;
+    (offset, user::as_bytes(fields))
+}
+
+// SystemInfo has padding after the tag, and after every variant that's smaller than the largest,
+// so it can't go through write_user. Copy the tag and the variant's fields over zeroes instead.
+fn write_info(out_ptr: *mut SystemInfo, info: SystemInfo) -> ResultCode {
+    let (offset, fields) = match &info {
+        SystemInfo::None => (0, &[][..]),
+        SystemInfo::MemoryRegion(x) => payload(&info, x),
+        SystemInfo::Platform(x) => payload(&info, x),
+        SystemInfo::FramebufferInfo(x) => payload(&info, x),
+        SystemInfo::AcpiRsdpAddress(x) => payload(&info, x),
+        SystemInfo::DeviceTreeAddress(x) => payload(&info, x),
+        SystemInfo::CpuCount(x) => payload(&info, x),
+        SystemInfo::PhysicalMemory(x) => payload(&info, x),
+        SystemInfo::KernelVersion(x) => payload(&info, x),
+        SystemInfo::Uptime(x) => payload(&info, x),
+    };
+
+    let mut buf = [0u8; size_of::<SystemInfo>()];
+    // A repr(C) enum starts with a C int sized tag.
+    let tag_len = size_of::<core::ffi::c_int>();
+    let base = &info as *const SystemInfo as *const u8;
+    let tag = unsafe { core::slice::from_raw_parts(base, tag_len) };
+    buf[..tag_len].copy_from_slice(tag);
+    buf[offset..offset + fields.len()].copy_from_slice(fields);
+
+    match user::copy_to_user(out_ptr as usize, &buf) {
+        Ok(()) => RESULT_OK,
+        Err(res) => res,
+    }
+}
+
+pub fn svc_get_system_info(ty: usize, index: usize, out_ptr: *mut SystemInfo) -> ResultCode {
+    let ty = match SystemInfoType::try_from(ty) {
+        Ok(ty) => ty,
+        Err(_) => return ResultCode::new(Module::Kernel, Reason::InvalidArgument),
+    };
 
-pub fn svc_get_system_info(
-    ty: SystemInfoType,
-    _index: usize,
-    out_ptr: *mut SystemInfo,
-) -> ResultCode {
     match ty {
         SystemInfoType::Platform => {
             #[cfg(feature = "platform_pc")]
             {
-                unsafe {
-                    *out_ptr = SystemInfo::Platform(Platform::Pc);
-                }
-                RESULT_OK
+                write_info(out_ptr, SystemInfo::Platform(Platform::Pc))
             }
 
             #[cfg(feature = "platform_virt")]
             {
-                unsafe {
-                    *out_ptr = SystemInfo::Platform(Platform::Virt);
-                }
-                RESULT_OK
+                write_info(out_ptr, SystemInfo::Platform(Platform::Virt))
             }
 
             #[cfg(feature = "platform_raspi3")]
             {
-                unsafe {
-                    *out_ptr = SystemInfo::Platform(Platform::Raspi3);
-                }
-                RESULT_OK
+                write_info(out_ptr, SystemInfo::Platform(Platform::Raspi3))
             }
 
             #[cfg(feature = "platform_raspi4")]
             {
-                unsafe {
-                    *out_ptr = SystemInfo::Platform(Platform::Raspi4);
-                }
-                RESULT_OK
+                write_info(out_ptr, SystemInfo::Platform(Platform::Raspi4))
             }
         },
-        SystemInfoType::FramebufferInfo => {
+        SystemInfoType::FramebufferInfo => match platform::get_framebuffer_info() {
+            Some(info) => write_info(out_ptr, SystemInfo::FramebufferInfo(info)),
+            None => ResultCode::new(Module::Kernel, Reason::NotFound),
+        },
+        SystemInfoType::AcpiRsdpAddress => {
             #[cfg(feature = "platform_pc")]
             {
-                unsafe {
-                    *out_ptr = SystemInfo::FramebufferInfo(crate::arch::x86_64::info::FRAMEBUFFER_INFO.clone().unwrap());
+                match unsafe { crate::arch::x86_64::info::SYSTEM_INFO_RSDP_ADDR } {
+                    Some(addr) => write_info(out_ptr, SystemInfo::AcpiRsdpAddress(addr as usize)),
+                    None => ResultCode::new(Module::Kernel, Reason::NotFound),
+                }
+            }
+            #[cfg(not(feature = "platform_pc"))]
+            {
+                ResultCode::new(Module::Kernel, Reason::NotFound)
+            }
+        }
+        SystemInfoType::DeviceTreeAddress => {
+            #[cfg(target_arch = "aarch64")]
+            {
+                match unsafe { crate::arch::aarch64::info::BOOT_DEVICE_TREE_ADDR } {
+                    0 => ResultCode::new(Module::Kernel, Reason::NotFound),
+                    addr => write_info(out_ptr, SystemInfo::DeviceTreeAddress(addr)),
                 }
-                RESULT_OK
             }
-            #[cfg(not(feature = "platform_pc"))] {
-                panic!();
+            #[cfg(not(target_arch = "aarch64"))]
+            {
+                ResultCode::new(Module::Kernel, Reason::NotFound)
             }
         }
-        _ => {
-            unimplemented!();
+        SystemInfoType::MemoryRegion => match phys_allocator::get_region(index) {
+            Some(region) => write_info(out_ptr, SystemInfo::MemoryRegion(region)),
+            None => ResultCode::new(Module::Kernel, Reason::NotFound),
+        },
+        SystemInfoType::CpuCount => {
+            write_info(out_ptr, SystemInfo::CpuCount(platform::get_cpu_count()))
         }
+        SystemInfoType::PhysicalMemory => write_info(
+            out_ptr,
+            SystemInfo::PhysicalMemory(PhysicalMemory {
+                total: phys_allocator::total_pages() * PAGE_SIZE,
+                free: phys_allocator::free_pages() * PAGE_SIZE,
+            }),
+        ),
+        SystemInfoType::KernelVersion => write_info(
+            out_ptr,
+            SystemInfo::KernelVersion(KernelVersion::new(concat!(
+                env!("CARGO_PKG_VERSION"),
+                "-",
+                env!("FRANCIUM_GIT_HASH")
+            ))),
+        ),
+        SystemInfoType::Uptime => write_info(out_ptr, SystemInfo::Uptime(timer::get_counter_ns())),
     }
 }
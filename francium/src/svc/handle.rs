@@ -1,7 +1,8 @@
 use tracing::{event, Level};
 
 use crate::scheduler;
-use common::os_error::ResultCode;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+use francium_common::types::HandleRights;
 
 pub fn svc_close_handle(handle: u32) -> ResultCode {
     event!(Level::TRACE, svc_name = "close_handle", handle = handle);
@@ -10,5 +11,46 @@ pub fn svc_close_handle(handle: u32) -> ResultCode {
     let p_ = scheduler::get_current_process();
     let mut p = p_.lock();
 
-    p.handle_table.close(handle)
+    // Closing the last handle can drop the object, which may wake up other threads (eg. a session's
+    // peer). Keep it alive until the process lock is released.
+    let object = p.handle_table.get_object(handle);
+    let res = p.handle_table.close(handle);
+    drop(p);
+    drop(object);
+
+    res
+}
+
+// Make a new handle to the same object. Rights can only ever be removed, never added.
+pub fn svc_duplicate_handle(handle: u32, rights: u32) -> (ResultCode, u32) {
+    event!(
+        Level::TRACE,
+        svc_name = "duplicate_handle",
+        handle = handle,
+        rights = rights
+    );
+
+    let requested = match HandleRights::from_bits(rights) {
+        Some(x) => x,
+        None => return (ResultCode::new(Module::Kernel, Reason::InvalidArgument), 0),
+    };
+
+    let p_ = scheduler::get_current_process();
+    let mut p = p_.lock();
+
+    let (obj, current) = match p
+        .handle_table
+        .get_object_with_rights(handle, HandleRights::DUPLICATE)
+    {
+        Ok(x) => x,
+        Err(res) => return (res, 0),
+    };
+
+    match p
+        .handle_table
+        .get_handle_with_rights(obj, current & requested)
+    {
+        Ok(new_handle) => (RESULT_OK, new_handle),
+        Err(res) => (res, 0),
+    }
 }
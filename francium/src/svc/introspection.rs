@@ -0,0 +1,79 @@
+use tracing::{event, Level};
+
+use crate::handle;
+use crate::handle::HandleObject;
+use crate::process::ThreadState;
+use crate::scheduler;
+use crate::svc::user;
+use crate::timer;
+use alloc::vec::Vec;
+use common::introspection::*;
+use common::name::pack_name;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+use core::sync::atomic::Ordering;
+use francium_common::types::HandleRights;
+
+// Process names and what they're blocked on say a lot about the system, so looking takes a
+// capability with the INTROSPECT right. The IPC trace uses the same one.
+pub fn check_introspect(capability_handle: u32) -> Result<(), ResultCode> {
+    match handle::get_handle_with_rights(capability_handle, HandleRights::INTROSPECT)? {
+        HandleObject::Capability => Ok(()),
+        _ => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    }
+}
+
+// Fills in up to count threads, returning how many there are in total.
+pub fn svc_list_threads(
+    capability_handle: u32,
+    threads_ptr: *mut ThreadInfo,
+    count: usize,
+) -> (ResultCode, usize) {
+    event!(
+        Level::TRACE,
+        svc_name = "list_threads",
+        capability_handle = capability_handle,
+        threads_ptr = threads_ptr as usize,
+        count = count
+    );
+
+    if let Err(res) = check_introspect(capability_handle) {
+        return (res, 0);
+    }
+
+    let current_thread = scheduler::get_current_thread();
+    let threads = scheduler::get_all_threads();
+
+    // Snapshot everything before writing any of it out, so no locks are held across the copy.
+    let mut infos = Vec::new();
+    for thread in threads.iter().take(count) {
+        let mut cpu_time_ns = thread.cpu_time_ns.load(Ordering::Relaxed);
+        // The caller's own time is still running, so count it up to now.
+        if thread.id == current_thread.id {
+            let switched_in_at = thread.switched_in_at_ns.load(Ordering::Relaxed);
+            cpu_time_ns += timer::get_counter_ns().saturating_sub(switched_in_at);
+        }
+
+        let process = thread.process.lock();
+        infos.push(ThreadInfo {
+            thread_id: thread.id as u64,
+            process_id: process.id as u64,
+            process_name: pack_name(process.name),
+            cpu_time_ns: cpu_time_ns,
+            state: match thread.state.load(Ordering::Acquire) {
+                ThreadState::Created => ThreadInfoState::Created,
+                ThreadState::Runnable => ThreadInfoState::Runnable,
+                ThreadState::Suspended => ThreadInfoState::Suspended,
+            },
+            last_svc_number: thread.last_svc_number.load(Ordering::Acquire) as u32,
+            handle_count: process.handle_table.count() as u32,
+            is_idle: thread.is_idle_thread.load(Ordering::Acquire),
+            _reserved: [0; 3],
+        });
+    }
+
+    if let Err(res) = user::write_user_slice(threads_ptr, &infos) {
+        return (res, 0);
+    }
+
+    (RESULT_OK, threads.len())
+}
@@ -2,49 +2,155 @@ use tracing::{event, Level};
 
 use crate::handle;
 use crate::handle::HandleObject;
-use crate::mmu::phys_to_virt;
-use crate::process::Thread;
+use crate::mmu::PagePermission;
+use crate::process::{Process, Thread};
 use crate::scheduler;
+use crate::svc::event::Event;
+use crate::svc::ipc_trace::{self, Endpoint};
+use crate::svc::user;
+use crate::timer;
 use crate::waitable;
 use crate::waitable::{Waitable, Waiter};
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::sync::{Arc, Weak};
+use alloc::vec;
 use alloc::vec::Vec;
+use common::constants::TIMEOUT_INFINITE;
 use common::ipc::*;
+use common::ipc_trace::{IPCTraceKind, IPCTraceRecord};
 use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
 use common::Handle;
 use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use francium_common::types::HandleRights;
 use spin::Mutex;
 
 use smallvec::SmallVec;
 
+// A request that is waiting for a reply.
+#[derive(Debug)]
+pub struct PendingRequest {
+    thread: Arc<Thread>,
+    buffer_ptr: usize,
+    // Buffer descriptors from the request, by translate index, with the client's addresses.
+    buffers: Mutex<[TranslateEntry; MAX_TRANSLATE]>,
+    result: Mutex<Option<ResultCode>>,
+    wait: Waiter,
+    // Also signalled on completion, for asynchronous requests.
+    event: Option<Arc<Event>>,
+    // For the IPC trace.
+    session_id: u64,
+    sent_ns: u64,
+    method_id: AtomicU32,
+}
+
+impl PendingRequest {
+    fn new(
+        thread: Arc<Thread>,
+        buffer_ptr: usize,
+        event: Option<Arc<Event>>,
+        session_id: u64,
+    ) -> PendingRequest {
+        PendingRequest {
+            thread: thread,
+            buffer_ptr: buffer_ptr,
+            buffers: Mutex::new([TranslateEntry::None; MAX_TRANSLATE]),
+            result: Mutex::new(None),
+            wait: Waiter::new(),
+            event: event,
+            session_id: session_id,
+            sent_ns: ipc_trace::send_time(),
+            method_id: AtomicU32::new(0),
+        }
+    }
+
+    fn complete(&self, result: ResultCode) -> bool {
+        *self.result.lock() = Some(result);
+        let woken = self.wait.signal_one(false);
+        match &self.event {
+            Some(event) => event.signal_without_tick() || woken,
+            None => woken,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.result.lock().is_some()
+    }
+}
+
+impl Waitable for PendingRequest {
+    fn get_waiter(&self) -> &Waiter {
+        &self.wait
+    }
+}
+
+// A one-way message. Nobody waits around for these, so the message is copied in when it is sent,
+// and any handles in it are held here until it is received.
+#[derive(Debug)]
+pub struct OneWayMessage {
+    buffer: [u8; IPC_BUFFER_LEN],
+    handles: [Option<(HandleObject, HandleRights)>; MAX_TRANSLATE],
+    // For the IPC trace.
+    session_id: u64,
+    sender: Endpoint,
+    sent_ns: u64,
+}
+
+const MAX_ONEWAY_MESSAGES: usize = 64;
+
+fn queue_oneway(
+    queue: &Mutex<VecDeque<OneWayMessage>>,
+    message: OneWayMessage,
+) -> Result<(), ResultCode> {
+    let mut queue = queue.lock();
+    if queue.len() >= MAX_ONEWAY_MESSAGES {
+        return Err(ResultCode::new(Module::Kernel, Reason::TryAgain));
+    }
+    queue.push_back(message);
+    Ok(())
+}
+
+// Sessions only hold weak references to each other, so that either side going away drops its
+// object, and the other side can be told about it.
 #[derive(Debug)]
 pub struct ServerSession {
+    // Identifies the session in IPC traces. The client end has the same id.
+    pub id: u64,
     wait: Waiter,
     connect_wait: Waiter,
-    pub queue: Mutex<SmallVec<[(Arc<Thread>, usize); 1]>>,
+    accepted: AtomicBool,
+    peer_closed: AtomicBool,
+    pub queue: Mutex<VecDeque<Arc<PendingRequest>>>,
     client: Mutex<Weak<ClientSession>>,
-    client_thread: Mutex<Option<(Arc<Thread>, usize)>>,
+    // Requests that have been received but not replied to yet, by reply token.
+    in_flight: Mutex<BTreeMap<u32, Arc<PendingRequest>>>,
+    next_token: AtomicU32,
+    oneway: Mutex<VecDeque<OneWayMessage>>,
 }
 
 #[derive(Debug)]
 pub struct ClientSession {
+    pub id: u64,
     wait: Waiter,
-    server: Arc<ServerSession>,
+    peer_closed: AtomicBool,
+    server: Weak<ServerSession>,
+    oneway: Mutex<VecDeque<OneWayMessage>>,
 }
 
+// How many connections can be waiting to be accepted before connecting fails with PortFull.
+const PORT_BACKLOG: usize = 32;
+
 #[derive(Debug)]
 pub struct Port {
     wait: Waiter,
-    // todo: queue default length
-    pub queue: Mutex<SmallVec<[Arc<ServerSession>; 1]>>,
+    pub queue: Mutex<VecDeque<Arc<ServerSession>>>,
 }
 
 impl Port {
     fn new() -> Port {
         Port {
             wait: Waiter::new(),
-            queue: Mutex::new(SmallVec::new()),
+            queue: Mutex::new(VecDeque::new()),
         }
     }
 }
@@ -55,16 +161,52 @@ impl Waitable for Port {
     }
 }
 
+impl Drop for Port {
+    fn drop(&mut self) {
+        // Nobody is going to accept these any more, so wake up whoever is trying to connect.
+        for server_session in self.queue.get_mut().drain(..) {
+            server_session.connect_wait.signal_one(false);
+        }
+    }
+}
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
 impl ServerSession {
     fn new() -> ServerSession {
         ServerSession {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
             wait: Waiter::new(),
             connect_wait: Waiter::new(),
-            queue: Mutex::new(SmallVec::new()),
+            accepted: AtomicBool::new(false),
+            peer_closed: AtomicBool::new(false),
+            queue: Mutex::new(VecDeque::new()),
             client: Mutex::new(Weak::new()),
-            client_thread: Mutex::new(None),
+            in_flight: Mutex::new(BTreeMap::new()),
+            next_token: AtomicU32::new(1),
+            oneway: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Token 0 is never handed out, it means "no request".
+    fn add_in_flight(&self, request: Arc<PendingRequest>) -> u32 {
+        let mut in_flight = self.in_flight.lock();
+        loop {
+            let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+            if token != 0 && !in_flight.contains_key(&token) {
+                in_flight.insert(token, request);
+                return token;
+            }
         }
     }
+
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed.load(Ordering::Acquire)
+    }
+
+    pub fn has_oneway(&self) -> bool {
+        !self.oneway.lock().is_empty()
+    }
 }
 impl Waitable for ServerSession {
     fn get_waiter(&self) -> &Waiter {
@@ -72,13 +214,42 @@ impl Waitable for ServerSession {
     }
 }
 
+impl Drop for ServerSession {
+    fn drop(&mut self) {
+        // Fail every request the client is still waiting on.
+        let closed = ResultCode::new(Module::Kernel, Reason::SessionClosed);
+        for request in self.queue.get_mut().drain(..) {
+            request.complete(closed);
+        }
+        for (_, request) in core::mem::take(self.in_flight.get_mut()) {
+            request.complete(closed);
+        }
+
+        if let Some(client) = self.client.get_mut().upgrade() {
+            client.peer_closed.store(true, Ordering::Release);
+            client.signal_all();
+        }
+    }
+}
+
 impl ClientSession {
-    fn new(server: Arc<ServerSession>) -> ClientSession {
+    fn new(server: &Arc<ServerSession>) -> ClientSession {
         ClientSession {
+            id: server.id,
             wait: Waiter::new(),
-            server: server,
+            peer_closed: AtomicBool::new(false),
+            server: Arc::downgrade(server),
+            oneway: Mutex::new(VecDeque::new()),
         }
     }
+
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed.load(Ordering::Acquire)
+    }
+
+    pub fn has_oneway(&self) -> bool {
+        !self.oneway.lock().is_empty()
+    }
 }
 impl Waitable for ClientSession {
     fn get_waiter(&self) -> &Waiter {
@@ -86,6 +257,16 @@ impl Waitable for ClientSession {
     }
 }
 
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        // Leave the server session signalled, so the server's next receive sees the close.
+        if let Some(server) = self.server.upgrade() {
+            server.peer_closed.store(true, Ordering::Release);
+            server.signal_one_without_tick();
+        }
+    }
+}
+
 lazy_static! {
     static ref PORT_LIST: Mutex<BTreeMap<u64, Arc<Port>>> = Mutex::new(BTreeMap::new());
     static ref PORT_WAITERS: Mutex<Vec<(u64, Arc<Thread>)>> = Mutex::new(Vec::new());
@@ -103,8 +284,9 @@ pub fn svc_create_port(tag: u64) -> (ResultCode, u32) {
         // Hold port list/waiter locks
         {
             let mut ports = PORT_LIST.lock();
+            // A name has one server. Whoever asks second gets told, rather than taking it over.
             if ports.contains_key(&tag) {
-                panic!("Port already contains key!");
+                return (ResultCode::new(Module::Kernel, Reason::AlreadyExists), 0);
             }
 
             ports.insert(tag, server_port_handle.clone());
@@ -113,7 +295,7 @@ pub fn svc_create_port(tag: u64) -> (ResultCode, u32) {
             port_waiters.retain(|x| {
                 if x.0 == tag {
                     did_wake = true;
-                    scheduler::wake_thread(&x.1, 0xffffffffffffffff);
+                    scheduler::wake_thread(&x.1, 0);
                     false
                 } else {
                     true
@@ -129,45 +311,108 @@ pub fn svc_create_port(tag: u64) -> (ResultCode, u32) {
     let proc_locked = scheduler::get_current_process();
     let mut process = proc_locked.lock();
 
-    let handle_value = process
+    match process
         .handle_table
-        .get_handle(HandleObject::Port(server_port_handle));
-    (RESULT_OK, handle_value)
+        .get_handle(HandleObject::Port(server_port_handle))
+    {
+        Ok(handle_value) => (RESULT_OK, handle_value),
+        Err(res) => (res, 0),
+    }
+}
+
+fn deadline_after(timeout_ns: u64) -> u64 {
+    if timeout_ns == TIMEOUT_INFINITE {
+        TIMEOUT_INFINITE
+    } else {
+        timer::get_counter_ns().saturating_add(timeout_ns)
+    }
+}
+
+fn timeout_until(deadline_ns: u64) -> u64 {
+    if deadline_ns == TIMEOUT_INFINITE {
+        TIMEOUT_INFINITE
+    } else {
+        deadline_ns.saturating_sub(timer::get_counter_ns())
+    }
 }
 
-fn connect_to_port_impl(port: &Arc<Port>) -> u32 {
+fn connect_to_port_impl(port: Arc<Port>, deadline_ns: u64) -> Result<u32, ResultCode> {
     let server_session = Arc::new(ServerSession::new());
-    let client_session = Arc::new(ClientSession::new(server_session.clone()));
+    let client_session = Arc::new(ClientSession::new(&server_session));
 
     // TODO: ugh, i really wanted OnceCell here
     *server_session.client.lock() = Arc::downgrade(&client_session);
 
     // create the session, and wait for it to be accepted by the server
-
-    port.queue.lock().push(server_session.clone());
+    {
+        let mut queue = port.queue.lock();
+        if queue.len() >= PORT_BACKLOG {
+            return Err(ResultCode::new(Module::Kernel, Reason::PortFull));
+        }
+        queue.push_back(server_session.clone());
+    }
     port.signal_one();
-    server_session.connect_wait.wait();
+
+    // Don't keep the port alive while we wait, or we'd never find out it was closed.
+    let weak_port = Arc::downgrade(&port);
+    drop(port);
+
+    if !server_session.connect_wait.post_wait(0) {
+        let tag = waitable::suspend_current_thread_timeout(timeout_until(deadline_ns));
+        if tag == waitable::TIMED_OUT_TAG {
+            server_session.connect_wait.remove_wait();
+
+            // Take ourselves off the port, unless the server has already picked us up.
+            let still_queued = match weak_port.upgrade() {
+                Some(port) => {
+                    let mut queue = port.queue.lock();
+                    match queue.iter().position(|x| Arc::ptr_eq(x, &server_session)) {
+                        Some(index) => {
+                            queue.remove(index);
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                None => false,
+            };
+
+            if still_queued {
+                return Err(ResultCode::new(Module::Kernel, Reason::TimedOut));
+            }
+
+            // We lost the race with accept (or the port closing), which is about to signal us.
+            server_session.connect_wait.wait();
+        }
+    }
+
+    if !server_session.accepted.load(Ordering::Acquire) {
+        return Err(ResultCode::new(Module::Kernel, Reason::SessionClosed));
+    }
 
     // return session
     {
         let current_process = scheduler::get_current_process();
         let mut process = current_process.lock();
-        let handle_value = process
+        process
             .handle_table
-            .get_handle(HandleObject::ClientSession(client_session));
-        handle_value
+            .get_handle(HandleObject::ClientSession(client_session))
     }
 }
 
-pub fn svc_connect_to_port_handle(h: u32) -> (ResultCode, u32) {
+pub fn svc_connect_to_port_handle(h: u32, timeout_ns: u64) -> (ResultCode, u32) {
     event!(
         Level::TRACE,
         svc_name = "connect_to_port_handle",
-        handle = h
+        handle = h,
+        timeout = timeout_ns
     );
 
     if let HandleObject::Port(port) = handle::get_handle(h) {
-        (RESULT_OK, connect_to_port_impl(&port))
+        match connect_to_port_impl(port, deadline_after(timeout_ns)) {
+            Ok(handle_value) => (RESULT_OK, handle_value),
+            Err(res) => (res, 0xffffffff),
+        }
     } else {
         (
             ResultCode::new(Module::Kernel, Reason::InvalidHandle),
@@ -176,21 +421,37 @@ pub fn svc_connect_to_port_handle(h: u32) -> (ResultCode, u32) {
     }
 }
 
-pub fn svc_connect_to_named_port(tag: u64) -> (ResultCode, u32) {
-    event!(Level::TRACE, svc_name = "connect_to_named_port", tag = tag);
+pub fn svc_connect_to_named_port(tag: u64, timeout_ns: u64) -> (ResultCode, u32) {
+    event!(
+        Level::TRACE,
+        svc_name = "connect_to_named_port",
+        tag = tag,
+        timeout = timeout_ns
+    );
 
+    // The timeout covers both waiting for the port to exist, and waiting to be accepted.
+    let deadline_ns = deadline_after(timeout_ns);
     let port = {
         let ports = PORT_LIST.lock();
         if let Some(server_port) = ports.get(&tag) {
             server_port.clone()
         } else {
+            let current_thread = scheduler::get_current_thread();
+            PORT_WAITERS.lock().push((tag, current_thread.clone()));
+
             // make sure to drop the lock guard before suspending ourselves!
             drop(ports);
 
-            PORT_WAITERS
-                .lock()
-                .push((tag, scheduler::get_current_thread()));
-            scheduler::suspend_current_thread();
+            let wake_tag = waitable::suspend_current_thread_timeout(timeout_until(deadline_ns));
+            if wake_tag == waitable::TIMED_OUT_TAG {
+                PORT_WAITERS
+                    .lock()
+                    .retain(|x| !(x.0 == tag && Arc::ptr_eq(&x.1, &current_thread)));
+                return (
+                    ResultCode::new(Module::Kernel, Reason::TimedOut),
+                    0xffffffff,
+                );
+            }
 
             // oops, try again
             {
@@ -199,7 +460,10 @@ pub fn svc_connect_to_named_port(tag: u64) -> (ResultCode, u32) {
             }
         }
     };
-    (RESULT_OK, connect_to_port_impl(&port))
+    match connect_to_port_impl(port, deadline_ns) {
+        Ok(handle_value) => (RESULT_OK, handle_value),
+        Err(res) => (res, 0xffffffff),
+    }
 }
 
 // x0: ipc session
@@ -211,86 +475,326 @@ pub fn svc_ipc_request(session_handle: u32, ipc_buffer_ptr: usize) -> ResultCode
         ipc_buffer_ptr = ipc_buffer_ptr
     );
 
+    if let Err(res) = validate_ipc_buffer(ipc_buffer_ptr) {
+        return res;
+    }
+
     if let HandleObject::ClientSession(client_session) = handle::get_handle(session_handle) {
+        let server = match client_session.server.upgrade() {
+            Some(server) => server,
+            None => return ResultCode::new(Module::Kernel, Reason::SessionClosed),
+        };
+
         // signal, then wait for reply
-        let current_thread = scheduler::get_current_thread();
+        let request = Arc::new(PendingRequest::new(
+            scheduler::get_current_thread(),
+            ipc_buffer_ptr,
+            None,
+            client_session.id,
+        ));
+        server.queue.lock().push_back(request.clone());
+        server.signal_one();
+
+        // Don't keep the server session alive while we wait, or we'd never find out it was closed.
+        drop(server);
+        request.wait.wait();
+
+        let result = request.result.lock().take();
+        result.unwrap()
+    } else {
+        // error
+        ResultCode::new(Module::Kernel, Reason::InvalidHandle)
+    }
+}
 
-        client_session
-            .server
-            .queue
-            .lock()
-            .push((current_thread, ipc_buffer_ptr));
-        client_session.server.signal_one();
-        client_session.wait();
+// Like svc_ipc_request, but returns a handle to the request straight away. The request handle is
+// signalled when the reply has been written to ipc_buffer_ptr, as is event_handle if it isn't
+// INVALID_HANDLE, so one event can cover many outstanding requests.
+// The buffer has to stay valid until then, even if the request handle is closed.
+pub fn svc_ipc_request_async(
+    session_handle: u32,
+    ipc_buffer_ptr: usize,
+    event_handle: u32,
+) -> (ResultCode, u32) {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_request_async",
+        session_handle = session_handle,
+        ipc_buffer_ptr = ipc_buffer_ptr,
+        event_handle = event_handle
+    );
 
-        RESULT_OK
+    if let Err(res) = validate_ipc_buffer(ipc_buffer_ptr) {
+        return (res, 0);
+    }
+
+    let event = if event_handle == common::INVALID_HANDLE.0 {
+        None
+    } else {
+        match handle::get_handle_with_rights(event_handle, HandleRights::SIGNAL) {
+            Ok(HandleObject::Event(event)) => Some(event),
+            Ok(_) => return (ResultCode::new(Module::Kernel, Reason::InvalidHandle), 0),
+            Err(res) => return (res, 0),
+        }
+    };
+
+    if let HandleObject::ClientSession(client_session) = handle::get_handle(session_handle) {
+        let server = match client_session.server.upgrade() {
+            Some(server) => server,
+            None => return (ResultCode::new(Module::Kernel, Reason::SessionClosed), 0),
+        };
+
+        let request = Arc::new(PendingRequest::new(
+            scheduler::get_current_thread(),
+            ipc_buffer_ptr,
+            event,
+            client_session.id,
+        ));
+
+        // Get the handle first, so we don't send a request nobody can collect the result of.
+        let request_handle = {
+            let proc_locked = scheduler::get_current_process();
+            let mut process = proc_locked.lock();
+            match process
+                .handle_table
+                .get_handle(HandleObject::IPCRequest(request.clone()))
+            {
+                Ok(handle_value) => handle_value,
+                Err(res) => return (res, 0),
+            }
+        };
+
+        server.queue.lock().push_back(request);
+        server.signal_one();
+        (RESULT_OK, request_handle)
+    } else {
+        (ResultCode::new(Module::Kernel, Reason::InvalidHandle), 0)
+    }
+}
+
+// Get the result of an asynchronous request, or TryAgain if there is no reply yet.
+pub fn svc_ipc_request_result(request_handle: u32) -> ResultCode {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_request_result",
+        request_handle = request_handle
+    );
+
+    if let HandleObject::IPCRequest(request) = handle::get_handle(request_handle) {
+        let result = *request.result.lock();
+        match result {
+            Some(res) => res,
+            None => ResultCode::new(Module::Kernel, Reason::TryAgain),
+        }
     } else {
-        // error
         ResultCode::new(Module::Kernel, Reason::InvalidHandle)
     }
 }
 
 const IPC_BUFFER_LEN: usize = 128;
 
+// Check up front that an IPC buffer is usable, so that transfers to and from it can't fail later
+// (when the other side is already committed to the message).
+fn validate_ipc_buffer(ipc_buffer_ptr: usize) -> Result<(), ResultCode> {
+    let proc = scheduler::get_current_process();
+    let locked = proc.lock();
+    if locked.address_space.is_range_valid(
+        ipc_buffer_ptr,
+        IPC_BUFFER_LEN,
+        PagePermission::USER_READ_WRITE,
+    ) {
+        Ok(())
+    } else {
+        Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer))
+    }
+}
+
+fn read_message_header(ipc_buffer: &[u8; IPC_BUFFER_LEN]) -> Result<IPCHeader, ResultCode> {
+    let invalid_message = ResultCode::new(Module::Kernel, Reason::InvalidMessage);
+
+    let packed_header = u32::from_le_bytes(ipc_buffer[0..4].try_into().unwrap());
+    let header = match IPCHeader::unpack(packed_header) {
+        Some(header) => header,
+        None => return Err(invalid_message),
+    };
+
+    // The size includes the header itself.
+    if header.size < 4
+        || header.translate_count > MAX_TRANSLATE
+        || header.size + header.translate_count * 16 > IPC_BUFFER_LEN
+    {
+        return Err(invalid_message);
+    }
+    Ok(header)
+}
+
+// A handle can only be sent once per message. Moving the same handle twice would mean closing it
+// twice.
+fn check_unique_handle(
+    seen: &mut SmallVec<[u32; MAX_TRANSLATE]>,
+    handle: u32,
+) -> Result<(), ResultCode> {
+    if seen.contains(&handle) {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidMessage));
+    }
+    seen.push(handle);
+    Ok(())
+}
+
+// Undo handles installed for a message that couldn't be delivered after all.
+fn close_handles(process: &Mutex<Process>, handles: &[Option<Handle>]) {
+    let mut process = process.lock();
+    for handle in handles.iter().flatten() {
+        process.handle_table.close(handle.0);
+    }
+}
+
+// Buffers are only allowed in requests, where request_buffers is where to keep them.
+// Returns the message's header.
 fn do_ipc_transfer(
     from_thread: &Arc<Thread>,
     to_thread: &Arc<Thread>,
     from_ptr: usize,
     to_ptr: usize,
-) {
-    let from_ipc_buffer_ptr = phys_to_virt(
-        from_thread
-            .process
-            .lock()
-            .address_space
-            .page_table
-            .virt_to_phys(from_ptr)
-            .unwrap(),
-    ) as *const u8;
-    let to_ipc_buffer_ptr = phys_to_virt(
-        to_thread
-            .process
-            .lock()
-            .address_space
-            .page_table
-            .virt_to_phys(to_ptr)
-            .unwrap(),
-    ) as *mut u8;
-
-    unsafe {
-        core::ptr::copy_nonoverlapping(from_ipc_buffer_ptr, to_ipc_buffer_ptr, 128);
+    mut request_buffers: Option<&mut [TranslateEntry; MAX_TRANSLATE]>,
+    mut trace: Option<&mut IPCTraceRecord>,
+) -> Result<IPCHeader, ResultCode> {
+    let mut ipc_buffer: [u8; IPC_BUFFER_LEN] = [0; IPC_BUFFER_LEN];
+    from_thread
+        .process
+        .lock()
+        .address_space
+        .copy_from_user(from_ptr, &mut ipc_buffer)?;
+
+    let invalid_message = ResultCode::new(Module::Kernel, Reason::InvalidMessage);
+    let header = read_message_header(&ipc_buffer)?;
+    if let Some(trace) = trace.as_mut() {
+        ipc_trace::set_header(trace, &header);
     }
 
-    let to_ipc_buffer =
-        unsafe { core::slice::from_raw_parts_mut(to_ipc_buffer_ptr as *mut u8, IPC_BUFFER_LEN) };
+    // Check every entry before acting on any, so a bad message doesn't lose handles.
+    let mut entries = [TranslateEntry::None; MAX_TRANSLATE];
+    let mut objects: [Option<(HandleObject, HandleRights)>; MAX_TRANSLATE] =
+        core::array::from_fn(|_| None);
+    let mut seen_handles = SmallVec::new();
+    for i in 0..header.translate_count {
+        let off = header.size + i * 16;
+        let entry = match TranslateEntry::read(ipc_buffer[off..off + 16].try_into().unwrap()) {
+            Some(entry) => entry,
+            None => return Err(invalid_message),
+        };
+
+        match entry {
+            TranslateEntry::None => {}
+            TranslateEntry::CopyHandle(handle) | TranslateEntry::MoveHandle(handle) => {
+                check_unique_handle(&mut seen_handles, handle.0)?;
+                objects[i] = Some(
+                    from_thread
+                        .process
+                        .lock()
+                        .handle_table
+                        .get_object_with_rights(handle.0, HandleRights::TRANSFER)?,
+                );
+            }
+            TranslateEntry::SendBuffer(desc)
+            | TranslateEntry::ReceiveBuffer(desc)
+            | TranslateEntry::ExchangeBuffer(desc) => {
+                if request_buffers.is_none() || desc.size > MAX_BUFFER_SIZE {
+                    return Err(invalid_message);
+                }
 
-    let packed_header = u32::from_le_bytes(to_ipc_buffer[0..4].try_into().unwrap());
-    let header = IPCHeader::unpack(packed_header);
+                let perm = if let TranslateEntry::SendBuffer(_) = entry {
+                    PagePermission::USER_READ_ONLY
+                } else {
+                    PagePermission::USER_READ_WRITE
+                };
+
+                if desc.size != 0
+                    && !from_thread.process.lock().address_space.is_range_valid(
+                        desc.address,
+                        desc.size,
+                        perm,
+                    )
+                {
+                    return Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer));
+                }
+            }
+        }
+        entries[i] = entry;
+        if let Some(trace) = trace.as_mut() {
+            ipc_trace::set_translate(trace, i, &entry, objects[i].as_ref().map(|x| &x.0));
+        }
+    }
+
+    // Install the handles in the receiver before taking any away from the sender, so a failure part
+    // way through doesn't lose them.
+    let mut new_handles: [Option<Handle>; MAX_TRANSLATE] = [None; MAX_TRANSLATE];
+    for i in 0..header.translate_count {
+        if let Some((obj, rights)) = objects[i].take() {
+            // Transferred handles keep their rights, so a reduced handle stays reduced.
+            let res = to_thread
+                .process
+                .lock()
+                .handle_table
+                .get_handle_with_rights(obj, rights);
+            match res {
+                Ok(handle) => new_handles[i] = Some(Handle(handle)),
+                Err(res) => {
+                    close_handles(&to_thread.process, &new_handles);
+                    return Err(res);
+                }
+            }
+        }
+    }
 
     // Translate all translate parameters
     for i in 0..header.translate_count {
         let off = header.size + i * 16;
-        let entry = TranslateEntry::read(to_ipc_buffer[off..off + 16].try_into().unwrap());
+        let entry = entries[i];
 
         let new_entry = match entry {
-            TranslateEntry::CopyHandle(handle) => {
-                let obj = from_thread.process.lock().handle_table.get_object(handle.0);
-                let new_handle = to_thread.process.lock().handle_table.get_handle(obj);
-                TranslateEntry::CopyHandle(Handle(new_handle))
-            }
-            TranslateEntry::MoveHandle(handle) => {
-                let obj = from_thread.process.lock().handle_table.get_object(handle.0);
-                let new_handle = to_thread.process.lock().handle_table.get_handle(obj);
-                assert!(from_thread.process.lock().handle_table.close(handle.0) == RESULT_OK);
-
-                TranslateEntry::MoveHandle(Handle(new_handle))
-            }
-            _ => {
-                unimplemented!("Can't translate {:?}", entry);
+            TranslateEntry::None => TranslateEntry::None,
+            TranslateEntry::CopyHandle(_) => TranslateEntry::CopyHandle(new_handles[i].unwrap()),
+            TranslateEntry::MoveHandle(_) => TranslateEntry::MoveHandle(new_handles[i].unwrap()),
+            TranslateEntry::SendBuffer(desc)
+            | TranslateEntry::ReceiveBuffer(desc)
+            | TranslateEntry::ExchangeBuffer(desc) => {
+                request_buffers.as_mut().unwrap()[i] = entry;
+
+                // The server only gets to know the size.
+                let hidden = BufferDescriptor {
+                    address: 0,
+                    size: desc.size,
+                };
+                match entry {
+                    TranslateEntry::SendBuffer(_) => TranslateEntry::SendBuffer(hidden),
+                    TranslateEntry::ReceiveBuffer(_) => TranslateEntry::ReceiveBuffer(hidden),
+                    _ => TranslateEntry::ExchangeBuffer(hidden),
+                }
             }
         };
-        TranslateEntry::write(&mut to_ipc_buffer[off..off + 16], new_entry);
+        TranslateEntry::write(&mut ipc_buffer[off..off + 16], new_entry);
     }
+
+    let res = to_thread
+        .process
+        .lock()
+        .address_space
+        .copy_to_user(to_ptr, &ipc_buffer);
+    if let Err(res) = res {
+        close_handles(&to_thread.process, &new_handles);
+        return Err(res);
+    }
+
+    // Only now that the message has arrived do moved handles leave the sender. If another of the
+    // sender's threads closed one in the meantime, it's gone either way.
+    let mut from_process = from_thread.process.lock();
+    for entry in &entries[..header.translate_count] {
+        if let TranslateEntry::MoveHandle(handle) = entry {
+            from_process.handle_table.close(handle.0);
+        }
+    }
+    Ok(header)
 }
 
 const MAX_HANDLES: usize = 128;
@@ -298,82 +802,419 @@ pub fn svc_ipc_receive(
     handles_ptr: *const u32,
     handle_count: usize,
     ipc_buffer_ptr: usize,
+    timeout_ns: u64,
 ) -> (ResultCode, usize) {
     event!(
         Level::TRACE,
         svc_name = "ipc_receive",
         handles_ptr = handles_ptr as usize,
         handle_count = handle_count,
-        ipc_buffer_ptr = ipc_buffer_ptr
+        ipc_buffer_ptr = ipc_buffer_ptr,
+        timeout = timeout_ns
     );
 
-    let mut handles: [u32; MAX_HANDLES] = [0xffffffff; MAX_HANDLES];
+    if let Err(res) = validate_ipc_buffer(ipc_buffer_ptr) {
+        return (res, 0);
+    }
+
+    ipc_receive_impl(handles_ptr, handle_count, ipc_buffer_ptr, timeout_ns)
+}
 
-    unsafe {
-        core::ptr::copy_nonoverlapping(handles_ptr, &mut handles as *mut u32, handle_count);
+fn ipc_receive_impl(
+    handles_ptr: *const u32,
+    handle_count: usize,
+    ipc_buffer_ptr: usize,
+    timeout_ns: u64,
+) -> (ResultCode, usize) {
+    if handle_count > MAX_HANDLES {
+        return (ResultCode::new(Module::Kernel, Reason::InvalidArgument), 0);
+    }
+
+    let mut handles: [u32; MAX_HANDLES] = [0xffffffff; MAX_HANDLES];
+    if let Err(res) = user::read_user_slice(handles_ptr, &mut handles[..handle_count]) {
+        return (res, 0);
     }
 
-    let index = waitable::wait_handles(&handles[..handle_count]);
+    let index = match waitable::wait_handles(&handles[..handle_count], timeout_ns) {
+        Ok(index) => index,
+        Err(res) => return (res, 0),
+    };
+
+    let oneway = match handle::get_handle(handles[index]) {
+        HandleObject::ServerSession(server_session) => server_session
+            .oneway
+            .lock()
+            .pop_front()
+            .map(|message| (message, IPCTraceKind::OneWayToServer)),
+        HandleObject::ClientSession(client_session) => {
+            let message = client_session.oneway.lock().pop_front();
+            match message {
+                Some(message) => Some((message, IPCTraceKind::OneWayToClient)),
+                None if client_session.is_peer_closed() => {
+                    return (
+                        ResultCode::new(Module::Kernel, Reason::SessionClosed),
+                        index,
+                    )
+                }
+                None => return (ResultCode::new(Module::Kernel, Reason::TryAgain), index),
+            }
+        }
+        _ => None,
+    };
+
+    if let Some((message, kind)) = oneway {
+        return match deliver_oneway(message, ipc_buffer_ptr, kind) {
+            Ok(()) => (RESULT_OK, index),
+            Err(res) => (res, index),
+        };
+    }
 
     if let HandleObject::ServerSession(server_session) = handle::get_handle(handles[index]) {
-        let (client_thread, client_buffer_ptr) = server_session.queue.lock().pop().unwrap();
+        let request = server_session.queue.lock().pop_front();
+        let request = match request {
+            Some(request) => request,
+            None if server_session.is_peer_closed() => {
+                return (
+                    ResultCode::new(Module::Kernel, Reason::SessionClosed),
+                    index,
+                )
+            }
+            None => return (ResultCode::new(Module::Kernel, Reason::TryAgain), index),
+        };
         let current_thread = scheduler::get_current_thread();
 
+        let mut trace = ipc_trace::begin(
+            IPCTraceKind::Request,
+            request.session_id,
+            request.sent_ns,
+            || (Endpoint::of(&request.thread), Endpoint::of(&current_thread)),
+        );
+
         // XX todo: figure out how to map from_ptr/to_ptr with respect to caches.
-        do_ipc_transfer(
-            &client_thread,
+        let res = do_ipc_transfer(
+            &request.thread,
             &current_thread,
-            client_buffer_ptr,
+            request.buffer_ptr,
             ipc_buffer_ptr,
+            Some(&mut request.buffers.lock()),
+            trace.as_mut(),
         );
+        match res {
+            Ok(header) => {
+                request.method_id.store(header.id, Ordering::Relaxed);
+                ipc_trace::finish(trace, RESULT_OK);
+            }
+            Err(res) => {
+                ipc_trace::finish(trace, res);
+                // The message never made it here, so bounce the error back to the client too.
+                request.complete(res);
+                return (res, index);
+            }
+        }
 
-        *server_session.client_thread.lock() = Some((client_thread, client_buffer_ptr));
+        let token = server_session.add_in_flight(request);
+        return (RESULT_OK, index | ((token as usize) << 32));
     }
 
     (RESULT_OK, index)
 }
 
-// x0: session handle
-pub fn svc_ipc_reply(session_handle: u32, ipc_buffer_ptr: usize) -> ResultCode {
+// Look up a received request by reply token (see ReplyToken), and optionally stop tracking it.
+fn get_in_flight(reply_token: u64, take: bool) -> Result<Arc<PendingRequest>, ResultCode> {
+    let reply_token = ReplyToken(reply_token);
+    let server_session = match handle::get_handle(reply_token.session().0) {
+        HandleObject::ServerSession(server_session) => server_session,
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    };
+
+    let mut in_flight = server_session.in_flight.lock();
+    let request = if take {
+        in_flight.remove(&reply_token.token())
+    } else {
+        in_flight.get(&reply_token.token()).cloned()
+    };
+
+    match request {
+        Some(request) => Ok(request),
+        None => Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument)),
+    }
+}
+
+// x0: reply token
+pub fn svc_ipc_reply(reply_token: u64, ipc_buffer_ptr: usize) -> ResultCode {
     event!(
         Level::TRACE,
         svc_name = "ipc_reply",
-        session_handle = session_handle,
+        reply_token = reply_token,
         ipc_buffer_ptr = ipc_buffer_ptr
     );
 
-    if let HandleObject::ServerSession(server_session) = handle::get_handle(session_handle) {
-        let current_thread = scheduler::get_current_thread();
-        let mut thread_lock = server_session.client_thread.lock();
-        let (client_thread, client_buffer_ptr) = thread_lock.as_ref().unwrap();
+    if let Err(res) = validate_ipc_buffer(ipc_buffer_ptr) {
+        return res;
+    }
 
-        // XX todo: figure out how to map from_ptr/to_ptr with respect to caches.
-        do_ipc_transfer(
-            &current_thread,
-            &client_thread,
-            ipc_buffer_ptr,
-            *client_buffer_ptr,
-        );
+    let (res, woken) = ipc_reply_impl(reply_token, ipc_buffer_ptr);
+    if woken {
+        scheduler::tick();
+    }
+    res
+}
 
-        *thread_lock = None;
+// Returns whether the client was woken, so the caller can decide when to reschedule.
+fn ipc_reply_impl(reply_token: u64, ipc_buffer_ptr: usize) -> (ResultCode, bool) {
+    match get_in_flight(reply_token, true) {
+        Ok(request) => {
+            let current_thread = scheduler::get_current_thread();
+
+            let mut trace = ipc_trace::begin(
+                IPCTraceKind::Reply,
+                request.session_id,
+                request.sent_ns,
+                || (Endpoint::of(&current_thread), Endpoint::of(&request.thread)),
+            );
+            if let Some(trace) = trace.as_mut() {
+                trace.method_id = request.method_id.load(Ordering::Relaxed);
+            }
 
-        let did_wake = {
-            server_session
-                .client
-                .lock()
-                .upgrade()
-                .unwrap()
-                .signal_one_without_tick()
+            // XX todo: figure out how to map from_ptr/to_ptr with respect to caches.
+            let res = do_ipc_transfer(
+                &current_thread,
+                &request.thread,
+                ipc_buffer_ptr,
+                request.buffer_ptr,
+                None,
+                trace.as_mut(),
+            );
+
+            // Either way, the client isn't getting any other reply.
+            let res = match res {
+                Ok(_) => RESULT_OK,
+                Err(res) => res,
+            };
+            ipc_trace::finish(trace, res);
+
+            (res, request.complete(res))
+        }
+        Err(res) => (res, false),
+    }
+}
+
+// Reply to reply_token (unless its token is 0), then receive into the same buffer.
+// This saves servers a syscall and a reschedule per request.
+pub fn svc_ipc_reply_and_receive(
+    reply_token: u64,
+    handles_ptr: *const u32,
+    handle_count: usize,
+    ipc_buffer_ptr: usize,
+    timeout_ns: u64,
+) -> (ResultCode, usize) {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_reply_and_receive",
+        reply_token = reply_token,
+        handles_ptr = handles_ptr as usize,
+        handle_count = handle_count,
+        ipc_buffer_ptr = ipc_buffer_ptr,
+        timeout = timeout_ns
+    );
+
+    if let Err(res) = validate_ipc_buffer(ipc_buffer_ptr) {
+        return (res, 0);
+    }
+
+    if ReplyToken(reply_token).token() != 0 {
+        // Don't tick here: the client gets to run once we block in the receive.
+        let (res, _) = ipc_reply_impl(reply_token, ipc_buffer_ptr);
+        if res != RESULT_OK {
+            return (res, 0);
+        }
+    }
+
+    ipc_receive_impl(handles_ptr, handle_count, ipc_buffer_ptr, timeout_ns)
+}
+
+// Copy a one-way message out of the sender, taking hold of any handles in it.
+fn capture_oneway(ipc_buffer_ptr: usize, session_id: u64) -> Result<OneWayMessage, ResultCode> {
+    let proc_locked = scheduler::get_current_process();
+    let mut process = proc_locked.lock();
+
+    let mut message = OneWayMessage {
+        buffer: [0; IPC_BUFFER_LEN],
+        handles: core::array::from_fn(|_| None),
+        session_id: session_id,
+        sender: Endpoint {
+            pid: process.id,
+            name: process.name,
+        },
+        sent_ns: ipc_trace::send_time(),
+    };
+    process
+        .address_space
+        .copy_from_user(ipc_buffer_ptr, &mut message.buffer)?;
+
+    let header = read_message_header(&message.buffer)?;
+
+    // Look everything up before moving any handles out, so a bad message doesn't lose handles.
+    let mut moved: SmallVec<[u32; MAX_TRANSLATE]> = SmallVec::new();
+    let mut seen_handles = SmallVec::new();
+    for i in 0..header.translate_count {
+        let off = header.size + i * 16;
+        match TranslateEntry::read(message.buffer[off..off + 16].try_into().unwrap()) {
+            Some(TranslateEntry::None) => {}
+            Some(TranslateEntry::CopyHandle(handle)) => {
+                check_unique_handle(&mut seen_handles, handle.0)?;
+                message.handles[i] = Some(
+                    process
+                        .handle_table
+                        .get_object_with_rights(handle.0, HandleRights::TRANSFER)?,
+                );
+            }
+            Some(TranslateEntry::MoveHandle(handle)) => {
+                check_unique_handle(&mut seen_handles, handle.0)?;
+                message.handles[i] = Some(
+                    process
+                        .handle_table
+                        .get_object_with_rights(handle.0, HandleRights::TRANSFER)?,
+                );
+                moved.push(handle.0);
+            }
+            // There's no request for the receiver to access buffers through.
+            _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidMessage)),
+        }
+    }
+
+    // Every handle was looked up just now with the process locked, and none is in there twice, so
+    // these can't fail.
+    for handle in moved {
+        process.handle_table.close(handle);
+    }
+    Ok(message)
+}
+
+// Hand a one-way message to the current process, installing its handles.
+fn deliver_oneway(
+    message: OneWayMessage,
+    ipc_buffer_ptr: usize,
+    kind: IPCTraceKind,
+) -> Result<(), ResultCode> {
+    let mut trace = ipc_trace::begin(kind, message.session_id, message.sent_ns, || {
+        (message.sender, Endpoint::current())
+    });
+
+    let res = deliver_oneway_impl(message, ipc_buffer_ptr, trace.as_mut());
+    ipc_trace::finish(
+        trace,
+        match res {
+            Ok(()) => RESULT_OK,
+            Err(res) => res,
+        },
+    );
+    res
+}
+
+fn deliver_oneway_impl(
+    mut message: OneWayMessage,
+    ipc_buffer_ptr: usize,
+    mut trace: Option<&mut IPCTraceRecord>,
+) -> Result<(), ResultCode> {
+    let header = read_message_header(&message.buffer)?;
+    if let Some(trace) = trace.as_mut() {
+        ipc_trace::set_header(trace, &header);
+    }
+
+    let proc_locked = scheduler::get_current_process();
+    let mut process = proc_locked.lock();
+    let mut new_handles: [Option<Handle>; MAX_TRANSLATE] = [None; MAX_TRANSLATE];
+    for i in 0..header.translate_count {
+        let off = header.size + i * 16;
+        if let Some(trace) = trace.as_mut() {
+            if let Some(entry) =
+                TranslateEntry::read(message.buffer[off..off + 16].try_into().unwrap())
+            {
+                ipc_trace::set_translate(
+                    trace,
+                    i,
+                    &entry,
+                    message.handles[i].as_ref().map(|x| &x.0),
+                );
+            }
+        }
+
+        let (obj, rights) = match message.handles[i].take() {
+            Some(x) => x,
+            None => continue,
         };
 
-        drop(thread_lock);
-        if did_wake {
-            scheduler::tick();
+        // The message is gone once this fails, so the receiver gets the error rather than half of
+        // the handles.
+        let new_handle = match process.handle_table.get_handle_with_rights(obj, rights) {
+            Ok(handle) => Handle(handle),
+            Err(res) => {
+                for handle in new_handles.iter().flatten() {
+                    process.handle_table.close(handle.0);
+                }
+                return Err(res);
+            }
+        };
+        new_handles[i] = Some(new_handle);
+
+        let new_entry =
+            match TranslateEntry::read(message.buffer[off..off + 16].try_into().unwrap()) {
+                Some(TranslateEntry::MoveHandle(_)) => TranslateEntry::MoveHandle(new_handle),
+                _ => TranslateEntry::CopyHandle(new_handle),
+            };
+        TranslateEntry::write(&mut message.buffer[off..off + 16], new_entry);
+    }
+
+    let res = process
+        .address_space
+        .copy_to_user(ipc_buffer_ptr, &message.buffer);
+    if res.is_err() {
+        for handle in new_handles.iter().flatten() {
+            process.handle_table.close(handle.0);
         }
+    }
+    res
+}
 
-        RESULT_OK
-    } else {
-        ResultCode::new(Module::Kernel, Reason::InvalidHandle)
+// Send a message that doesn't get a reply. This works in both directions: clients send to their
+// server, and servers can push to their client. The peer picks it up with ipc_receive.
+pub fn svc_ipc_send(session_handle: u32, ipc_buffer_ptr: usize) -> ResultCode {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_send",
+        session_handle = session_handle,
+        ipc_buffer_ptr = ipc_buffer_ptr
+    );
+
+    let session_closed = ResultCode::new(Module::Kernel, Reason::SessionClosed);
+    let res = match handle::get_handle(session_handle) {
+        HandleObject::ClientSession(client_session) => match client_session.server.upgrade() {
+            Some(server) => capture_oneway(ipc_buffer_ptr, server.id)
+                .and_then(|message| queue_oneway(&server.oneway, message))
+                .map(|_| server.signal_one_without_tick()),
+            None => Err(session_closed),
+        },
+        HandleObject::ServerSession(server_session) => {
+            let client = server_session.client.lock().upgrade();
+            match client {
+                Some(client) => capture_oneway(ipc_buffer_ptr, client.id)
+                    .and_then(|message| queue_oneway(&client.oneway, message))
+                    .map(|_| client.signal_one_without_tick()),
+                None => Err(session_closed),
+            }
+        }
+        _ => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    };
+
+    match res {
+        Ok(woken) => {
+            if woken {
+                scheduler::tick();
+            }
+            RESULT_OK
+        }
+        Err(res) => res,
     }
 }
 
@@ -387,17 +1228,38 @@ pub fn svc_ipc_accept(port_handle: u32) -> (ResultCode, u32) {
     );
 
     if let HandleObject::Port(port) = handle::get_handle(port_handle) {
-        let server_session = port.queue.lock().pop().unwrap();
+        let server_session = match port.queue.lock().pop_front() {
+            Some(server_session) => server_session,
+            None => {
+                return (
+                    ResultCode::new(Module::Kernel, Reason::TryAgain),
+                    0xffffffff,
+                )
+            }
+        };
 
-        // wake the client
-        server_session.connect_wait.signal_one(true);
+        let res = {
+            let current_process = scheduler::get_current_process();
+            let mut process = current_process.lock();
+            process
+                .handle_table
+                .get_handle(HandleObject::ServerSession(server_session.clone()))
+        };
 
-        let current_process = scheduler::get_current_process();
-        let mut process = current_process.lock();
-        let handle_value = process
-            .handle_table
-            .get_handle(HandleObject::ServerSession(server_session));
-        (RESULT_OK, handle_value)
+        // Only tell the client it's connected once the server really has the session. If we
+        // couldn't take it, the client's connect fails with SessionClosed instead.
+        match res {
+            Ok(handle_value) => {
+                server_session.accepted.store(true, Ordering::Release);
+                server_session.connect_wait.signal_one(true);
+                (RESULT_OK, handle_value)
+            }
+            Err(res) => {
+                server_session.peer_closed.store(true, Ordering::Release);
+                server_session.connect_wait.signal_one(false);
+                (res, 0xffffffff)
+            }
+        }
     } else {
         (
             ResultCode::new(Module::Kernel, Reason::InvalidHandle),
@@ -411,25 +1273,174 @@ pub fn svc_create_session(
     client_session_out: *mut u32,
 ) -> ResultCode {
     let proc_locked = scheduler::get_current_process();
-    let mut process = proc_locked.lock();
 
     let server_session = Arc::new(ServerSession::new());
-    let client_session = Arc::new(ClientSession::new(server_session.clone()));
+    let client_session = Arc::new(ClientSession::new(&server_session));
 
     // TODO: ugh, i really wanted OnceCell here
     *server_session.client.lock() = Arc::downgrade(&client_session);
+    server_session.accepted.store(true, Ordering::Release);
 
-    let server_session_handle = process
-        .handle_table
-        .get_handle(HandleObject::ServerSession(server_session));
-    let client_session_handle = process
-        .handle_table
-        .get_handle(HandleObject::ClientSession(client_session));
+    let (server_session_handle, client_session_handle) = {
+        let mut process = proc_locked.lock();
+        let server_session_handle = match process
+            .handle_table
+            .get_handle(HandleObject::ServerSession(server_session))
+        {
+            Ok(x) => x,
+            Err(res) => return res,
+        };
 
-    unsafe {
-        *server_session_out = server_session_handle;
-        *client_session_out = client_session_handle;
+        match process
+            .handle_table
+            .get_handle(HandleObject::ClientSession(client_session))
+        {
+            Ok(client_session_handle) => (server_session_handle, client_session_handle),
+            Err(res) => {
+                process.handle_table.close(server_session_handle);
+                return res;
+            }
+        }
+    };
+
+    let res = user::write_user(server_session_out, &server_session_handle)
+        .and_then(|_| user::write_user(client_session_out, &client_session_handle));
+
+    if let Err(res) = res {
+        // Nobody will ever see these handles, so don't leak them.
+        let mut process = proc_locked.lock();
+        process.handle_table.close(server_session_handle);
+        process.handle_table.close(client_session_handle);
+        return res;
     }
 
     RESULT_OK
 }
+
+// Find a buffer from the request currently being handled on a server session.
+fn get_request_buffer(
+    reply_token: u64,
+    index: usize,
+    write: bool,
+) -> Result<(Arc<PendingRequest>, BufferDescriptor), ResultCode> {
+    let request = get_in_flight(reply_token, false)?;
+
+    if index >= MAX_TRANSLATE {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument));
+    }
+
+    let entry = request.buffers.lock()[index];
+    let desc = match (entry, write) {
+        (TranslateEntry::SendBuffer(desc), false)
+        | (TranslateEntry::ReceiveBuffer(desc), true)
+        | (TranslateEntry::ExchangeBuffer(desc), _) => desc,
+        (TranslateEntry::SendBuffer(_), true) | (TranslateEntry::ReceiveBuffer(_), false) => {
+            return Err(ResultCode::new(Module::Kernel, Reason::NotAllowed))
+        }
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument)),
+    };
+
+    Ok((request, desc))
+}
+
+const COPY_CHUNK_LEN: usize = 0x1000;
+
+// Copy between two processes through a kernel buffer, so we only hold one process lock at a time.
+fn copy_between_threads(
+    from_thread: &Arc<Thread>,
+    from_ptr: usize,
+    to_thread: &Arc<Thread>,
+    to_ptr: usize,
+    len: usize,
+) -> Result<(), ResultCode> {
+    let mut chunk = vec![0u8; core::cmp::min(len, COPY_CHUNK_LEN)];
+
+    let mut offset = 0;
+    while offset < len {
+        let chunk_len = core::cmp::min(len - offset, COPY_CHUNK_LEN);
+        from_thread
+            .process
+            .lock()
+            .address_space
+            .copy_from_user(from_ptr + offset, &mut chunk[..chunk_len])?;
+        to_thread
+            .process
+            .lock()
+            .address_space
+            .copy_to_user(to_ptr + offset, &chunk[..chunk_len])?;
+        offset += chunk_len;
+    }
+    Ok(())
+}
+
+pub fn svc_ipc_read_buffer(
+    reply_token: u64,
+    index: usize,
+    buffer_ptr: usize,
+    len: usize,
+) -> ResultCode {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_read_buffer",
+        reply_token = reply_token,
+        index = index,
+        buffer_ptr = buffer_ptr,
+        len = len
+    );
+
+    let (request, desc) = match get_request_buffer(reply_token, index, false) {
+        Ok(x) => x,
+        Err(res) => return res,
+    };
+
+    if len > desc.size {
+        return ResultCode::new(Module::Kernel, Reason::InvalidArgument);
+    }
+
+    match copy_between_threads(
+        &request.thread,
+        desc.address,
+        &scheduler::get_current_thread(),
+        buffer_ptr,
+        len,
+    ) {
+        Ok(()) => RESULT_OK,
+        Err(res) => res,
+    }
+}
+
+pub fn svc_ipc_write_buffer(
+    reply_token: u64,
+    index: usize,
+    buffer_ptr: usize,
+    len: usize,
+) -> ResultCode {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_write_buffer",
+        reply_token = reply_token,
+        index = index,
+        buffer_ptr = buffer_ptr,
+        len = len
+    );
+
+    let (request, desc) = match get_request_buffer(reply_token, index, true) {
+        Ok(x) => x,
+        Err(res) => return res,
+    };
+
+    if len > desc.size {
+        return ResultCode::new(Module::Kernel, Reason::InvalidArgument);
+    }
+
+    match copy_between_threads(
+        &scheduler::get_current_thread(),
+        buffer_ptr,
+        &request.thread,
+        desc.address,
+        len,
+    ) {
+        Ok(()) => RESULT_OK,
+        Err(res) => res,
+    }
+}
@@ -0,0 +1,215 @@
+use tracing::{event, Level};
+
+use crate::handle::HandleObject;
+use crate::process::Thread;
+use crate::scheduler;
+use crate::svc::introspection::check_introspect;
+use crate::svc::user;
+use crate::timer;
+use alloc::collections::VecDeque;
+use common::ipc::*;
+use common::ipc_trace::*;
+use common::name::pack_name;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+// Once this many records are waiting to be read, each new one pushes out the oldest.
+const IPC_TRACE_LEN: usize = 256;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct IPCTrace {
+    records: VecDeque<IPCTraceRecord>,
+    next_sequence: u64,
+}
+
+static IPC_TRACE: Mutex<IPCTrace> = Mutex::new(IPCTrace {
+    records: VecDeque::new(),
+    next_sequence: 0,
+});
+
+// One end of a message.
+#[derive(Debug, Copy, Clone)]
+pub struct Endpoint {
+    pub pid: usize,
+    pub name: &'static str,
+}
+
+impl Endpoint {
+    pub fn of(thread: &Thread) -> Endpoint {
+        let process = thread.process.lock();
+        Endpoint {
+            pid: process.id,
+            name: process.name,
+        }
+    }
+
+    pub fn current() -> Endpoint {
+        Endpoint::of(&scheduler::get_current_thread())
+    }
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// When a message is sent, so that its latency can be worked out when it arrives. Reading the
+// timer isn't free, so this is 0 unless tracing is on.
+pub fn send_time() -> u64 {
+    if is_enabled() {
+        timer::get_counter_ns()
+    } else {
+        0
+    }
+}
+
+// Starts a record, if tracing is on. endpoints gives the sender and receiver, and is only called
+// if the record is wanted.
+pub fn begin(
+    kind: IPCTraceKind,
+    session_id: u64,
+    sent_ns: u64,
+    endpoints: impl FnOnce() -> (Endpoint, Endpoint),
+) -> Option<IPCTraceRecord> {
+    if !is_enabled() {
+        return None;
+    }
+
+    let (sender, receiver) = endpoints();
+    let now = timer::get_counter_ns();
+    Some(IPCTraceRecord {
+        timestamp_ns: now,
+        // Tracing might have been turned on after the message was sent.
+        latency_ns: if sent_ns != 0 {
+            now.saturating_sub(sent_ns)
+        } else {
+            0
+        },
+        session_id: session_id,
+        sender_pid: sender.pid as u64,
+        receiver_pid: receiver.pid as u64,
+        sender_name: pack_name(sender.name),
+        receiver_name: pack_name(receiver.name),
+        kind: kind,
+        ..Default::default()
+    })
+}
+
+pub fn set_header(record: &mut IPCTraceRecord, header: &IPCHeader) {
+    // Replies keep the id of their request.
+    if record.kind != IPCTraceKind::Reply {
+        record.method_id = header.id;
+    }
+    record.size = header.size as u32;
+    record.translate_count = header.translate_count as u32;
+}
+
+// object is what a handle entry refers to, for handles that turn out to be sessions.
+pub fn set_translate(
+    record: &mut IPCTraceRecord,
+    index: usize,
+    entry: &TranslateEntry,
+    object: Option<&HandleObject>,
+) {
+    let (ty, buffer_size) = match entry {
+        TranslateEntry::None => (0, 0),
+        TranslateEntry::MoveHandle(_) => (TRANSLATE_TYPE_MOVE_HANDLE, 0),
+        TranslateEntry::CopyHandle(_) => (TRANSLATE_TYPE_COPY_HANDLE, 0),
+        TranslateEntry::SendBuffer(desc) => (TRANSLATE_TYPE_SEND_BUFFER, desc.size),
+        TranslateEntry::ReceiveBuffer(desc) => (TRANSLATE_TYPE_RECEIVE_BUFFER, desc.size),
+        TranslateEntry::ExchangeBuffer(desc) => (TRANSLATE_TYPE_EXCHANGE_BUFFER, desc.size),
+    };
+
+    let session_id = match object {
+        Some(HandleObject::ServerSession(server_session)) => server_session.id,
+        Some(HandleObject::ClientSession(client_session)) => client_session.id,
+        _ => 0,
+    };
+
+    record.translates[index] = IPCTraceTranslate {
+        ty: ty,
+        buffer_size: buffer_size as u64,
+        session_id: session_id,
+    };
+}
+
+// Adds a finished record to the trace. Messages that failed to be delivered are recorded too.
+pub fn finish(record: Option<IPCTraceRecord>, result: ResultCode) {
+    let mut record = match record {
+        Some(record) => record,
+        None => return,
+    };
+    record.result = result.0;
+
+    let mut trace = IPC_TRACE.lock();
+    record.sequence = trace.next_sequence;
+    trace.next_sequence += 1;
+
+    if trace.records.len() >= IPC_TRACE_LEN {
+        trace.records.pop_front();
+    }
+    trace.records.push_back(record);
+}
+
+// The trace shows what every process is saying to every other, so turning it on or reading it takes
+// the introspection capability.
+pub fn svc_ipc_trace_control(capability_handle: u32, op: usize) -> ResultCode {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_trace_control",
+        capability_handle = capability_handle,
+        op = op
+    );
+
+    if let Err(res) = check_introspect(capability_handle) {
+        return res;
+    }
+
+    match IPCTraceControl::try_from(op) {
+        Ok(IPCTraceControl::Disable) => ENABLED.store(false, Ordering::Relaxed),
+        Ok(IPCTraceControl::Enable) => ENABLED.store(true, Ordering::Relaxed),
+        Ok(IPCTraceControl::Clear) => IPC_TRACE.lock().records.clear(),
+        Err(_) => return ResultCode::new(Module::Kernel, Reason::InvalidArgument),
+    }
+    RESULT_OK
+}
+
+// Takes up to count of the oldest records, returning how many there were.
+pub fn svc_ipc_trace_read(
+    capability_handle: u32,
+    records_ptr: *mut IPCTraceRecord,
+    count: usize,
+) -> (ResultCode, usize) {
+    event!(
+        Level::TRACE,
+        svc_name = "ipc_trace_read",
+        capability_handle = capability_handle,
+        records_ptr = records_ptr as usize,
+        count = count
+    );
+
+    if let Err(res) = check_introspect(capability_handle) {
+        return (res, 0);
+    }
+
+    let mut trace = IPC_TRACE.lock();
+    let count = count.min(trace.records.len());
+
+    // Copy them out before taking them off, so a bad pointer doesn't lose any.
+    let (front, back) = trace.records.as_slices();
+    let from_front = count.min(front.len());
+    let res = user::write_user_slice(records_ptr, &front[..from_front]).and_then(|_| {
+        user::write_user_slice(
+            records_ptr.wrapping_add(from_front),
+            &back[..count - from_front],
+        )
+    });
+    if let Err(res) = res {
+        return (res, 0);
+    }
+
+    trace.records.drain(..count);
+    (RESULT_OK, count)
+}
@@ -40,7 +40,7 @@ pub fn svc_map_memory(address: usize, length: usize, permission: u64) -> (Result
 }
 
 pub fn svc_map_device_memory(
-    phys_address: PhysAddr,
+    phys_address: usize,
     virt_address: usize,
     length: usize,
     map_type: usize,
@@ -49,7 +49,7 @@ pub fn svc_map_device_memory(
     event!(
         Level::TRACE,
         svc_name = "map_device_memory",
-        phys_address = phys_address.0,
+        phys_address = phys_address,
         virt_address = virt_address,
         length = length,
         permission = permission
@@ -73,7 +73,7 @@ pub fn svc_map_device_memory(
 
     let page_permission: PagePermission = PagePermission::from_bits(permission).unwrap();
     aspace.alias(
-        phys_address,
+        PhysAddr(phys_address),
         highest_mmap,
         length,
         MapType::from_usize(map_type).unwrap(),
@@ -5,49 +5,17 @@ mod futex;
 mod get_system_info;
 mod get_system_tick;
 mod handle;
+mod introspection;
 pub mod ipc;
+pub mod ipc_trace;
 mod memory;
 mod process;
+pub mod semaphore;
 mod svc_break;
 mod thread;
+pub mod timer;
+mod user;
 mod wait;
 
-pub use debug_output::svc_debug_output;
-pub use exit_process::svc_exit_process;
-pub use handle::svc_close_handle;
-pub use ipc::svc_connect_to_named_port;
-pub use ipc::svc_connect_to_port_handle;
-pub use ipc::svc_create_port;
-pub use svc_break::svc_break;
-
-pub use ipc::svc_create_session;
-pub use ipc::svc_ipc_accept;
-pub use ipc::svc_ipc_receive;
-pub use ipc::svc_ipc_reply;
-pub use ipc::svc_ipc_request;
-
-pub use memory::svc_map_device_memory;
-pub use memory::svc_map_memory;
-pub use memory::svc_query_physical_address;
-
-pub use process::svc_create_thread;
-pub use process::svc_get_process_id;
-pub use process::svc_get_thread_id;
-
-pub use thread::svc_sleep_ns;
-
-pub use futex::svc_futex_wait;
-pub use futex::svc_futex_wake;
-
-pub use get_system_info::svc_get_system_info;
-
-pub use get_system_tick::svc_get_system_tick;
-
-pub use event::svc_bind_interrupt;
-pub use event::svc_clear_event;
-pub use event::svc_create_event;
-pub use event::svc_signal_event;
-pub use event::svc_unbind_interrupt;
-
-pub use wait::svc_wait_many;
-pub use wait::svc_wait_one;
+// The svc_ function for each syscall in syscalls.toml.
+include!(concat!(env!("OUT_DIR"), "/svc_exports.rs"));
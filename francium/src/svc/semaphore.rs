@@ -0,0 +1,113 @@
+use tracing::{event, Level};
+
+use crate::handle;
+use crate::handle::HandleObject;
+use crate::scheduler;
+use crate::waitable::{Waitable, Waiter};
+use alloc::sync::Arc;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+use francium_common::types::HandleRights;
+use spin::Mutex;
+
+#[derive(Debug)]
+pub struct Semaphore {
+    count: Mutex<usize>,
+    max_count: usize,
+    w: Waiter,
+}
+
+impl Semaphore {
+    fn new(initial_count: usize, max_count: usize) -> Semaphore {
+        Semaphore {
+            count: Mutex::new(initial_count),
+            max_count: max_count,
+            w: Waiter::new(),
+        }
+    }
+
+    // Returns the count from before the release.
+    pub fn release(&self, release_count: usize) -> Result<usize, ResultCode> {
+        let woken;
+        let previous_count = {
+            let mut count = self.count.lock();
+            let previous_count = *count;
+
+            match previous_count.checked_add(release_count) {
+                Some(x) if x <= self.max_count => {}
+                _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument)),
+            }
+
+            // Hand units straight to waiters where we can, and bank the rest. The check above means
+            // the rest always fits.
+            woken = self.w.signal_n(release_count);
+            *count = previous_count + (release_count - woken);
+            previous_count
+        };
+
+        if woken != 0 {
+            scheduler::tick();
+        }
+        Ok(previous_count)
+    }
+}
+
+impl Waitable for Semaphore {
+    fn get_waiter(&self) -> &Waiter {
+        &self.w
+    }
+
+    fn post_wait(&self, tag: usize) -> bool {
+        // Take a unit if there is one, otherwise queue up. The waiter never latches.
+        let mut count = self.count.lock();
+        if *count > 0 {
+            *count -= 1;
+            true
+        } else {
+            self.w.post_wait(tag)
+        }
+    }
+}
+
+pub fn svc_create_semaphore(initial_count: usize, max_count: usize) -> (ResultCode, u32) {
+    event!(
+        Level::TRACE,
+        svc_name = "create_semaphore",
+        initial_count = initial_count,
+        max_count = max_count
+    );
+
+    if max_count == 0 || initial_count > max_count {
+        return (ResultCode::new(Module::Kernel, Reason::InvalidArgument), 0);
+    }
+
+    let proc_locked = scheduler::get_current_process();
+    let mut process = proc_locked.lock();
+
+    let sem = Arc::new(Semaphore::new(initial_count, max_count));
+    match process
+        .handle_table
+        .get_handle(HandleObject::Semaphore(sem))
+    {
+        Ok(handle_value) => (RESULT_OK, handle_value),
+        Err(res) => (res, 0),
+    }
+}
+
+// As with events, a producer in another process gets a SIGNAL only duplicate over IPC.
+pub fn svc_release_semaphore(h: u32, release_count: usize) -> (ResultCode, usize) {
+    event!(
+        Level::TRACE,
+        svc_name = "release_semaphore",
+        handle = h,
+        release_count = release_count
+    );
+
+    match handle::get_handle_with_rights(h, HandleRights::SIGNAL) {
+        Ok(HandleObject::Semaphore(sem)) => match sem.release(release_count) {
+            Ok(previous_count) => (RESULT_OK, previous_count),
+            Err(res) => (res, 0),
+        },
+        Ok(_) => (ResultCode::new(Module::Kernel, Reason::InvalidHandle), 0),
+        Err(res) => (res, 0),
+    }
+}
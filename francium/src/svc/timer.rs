@@ -0,0 +1,156 @@
+use tracing::{event, Level};
+
+use crate::handle;
+use crate::handle::HandleObject;
+use crate::scheduler;
+use crate::timer;
+use crate::waitable::{Waitable, Waiter};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+use francium_common::types::HandleRights;
+use spin::Mutex;
+
+#[derive(Debug)]
+struct TimerState {
+    // Bumped every time the timer is set or cancelled, so stale callbacks know to do nothing.
+    generation: u64,
+    timer_id: Option<usize>,
+    period_ns: u64,
+}
+
+// A waitable timer. Becomes signalled at its deadline, and then every period_ns if that is non-zero.
+#[derive(Debug)]
+pub struct Timer {
+    state: Mutex<TimerState>,
+    w: Waiter,
+}
+
+impl Timer {
+    fn new() -> Timer {
+        Timer {
+            state: Mutex::new(TimerState {
+                generation: 0,
+                timer_id: None,
+                period_ns: 0,
+            }),
+            w: Waiter::new(),
+        }
+    }
+
+    fn arm(self: &Arc<Self>, state: &mut TimerState, offset_ns: u64) {
+        // Only hold a weak reference, so a periodic timer doesn't keep itself alive forever.
+        let weak = Arc::downgrade(self);
+        let generation = state.generation;
+
+        state.timer_id = Some(timer::register_timer(
+            offset_ns,
+            Box::new(move || {
+                if let Some(t) = weak.upgrade() {
+                    t.fire(generation);
+                }
+            }),
+        ));
+    }
+
+    fn disarm(&self, state: &mut TimerState) {
+        state.generation += 1;
+        if let Some(id) = state.timer_id.take() {
+            timer::cancel_timer(id);
+        }
+    }
+
+    fn fire(self: &Arc<Self>, generation: u64) {
+        {
+            let mut state = self.state.lock();
+            if state.generation != generation {
+                return;
+            }
+
+            state.timer_id = None;
+            if state.period_ns != 0 {
+                let period_ns = state.period_ns;
+                self.arm(&mut state, period_ns);
+            }
+        }
+
+        // We're in the timer interrupt, so don't reschedule from here.
+        self.signal_one_without_tick();
+    }
+
+    pub fn set(self: &Arc<Self>, initial_ns: u64, period_ns: u64) {
+        let mut state = self.state.lock();
+        self.disarm(&mut state);
+        self.w.clear();
+
+        state.period_ns = period_ns;
+        self.arm(&mut state, initial_ns);
+    }
+
+    pub fn cancel(&self) {
+        let mut state = self.state.lock();
+        self.disarm(&mut state);
+        self.w.clear();
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(id) = self.state.get_mut().timer_id.take() {
+            timer::cancel_timer(id);
+        }
+    }
+}
+
+impl Waitable for Timer {
+    fn get_waiter(&self) -> &Waiter {
+        &self.w
+    }
+}
+
+pub fn svc_create_timer() -> (ResultCode, u32) {
+    event!(Level::TRACE, svc_name = "create_timer");
+
+    let proc_locked = scheduler::get_current_process();
+    let mut process = proc_locked.lock();
+
+    match process
+        .handle_table
+        .get_handle(HandleObject::Timer(Arc::new(Timer::new())))
+    {
+        Ok(handle_value) => (RESULT_OK, handle_value),
+        Err(res) => (res, 0),
+    }
+}
+
+pub fn svc_set_timer(h: u32, initial_ns: u64, period_ns: u64) -> ResultCode {
+    event!(
+        Level::TRACE,
+        svc_name = "set_timer",
+        handle = h,
+        initial_ns = initial_ns,
+        period_ns = period_ns
+    );
+
+    match handle::get_handle_with_rights(h, HandleRights::SIGNAL) {
+        Ok(HandleObject::Timer(t)) => {
+            t.set(initial_ns, period_ns);
+            RESULT_OK
+        }
+        Ok(_) => ResultCode::new(Module::Kernel, Reason::InvalidHandle),
+        Err(res) => res,
+    }
+}
+
+pub fn svc_cancel_timer(h: u32) -> ResultCode {
+    event!(Level::TRACE, svc_name = "cancel_timer", handle = h);
+
+    match handle::get_handle_with_rights(h, HandleRights::SIGNAL) {
+        Ok(HandleObject::Timer(t)) => {
+            t.cancel();
+            RESULT_OK
+        }
+        Ok(_) => ResultCode::new(Module::Kernel, Reason::InvalidHandle),
+        Err(res) => res,
+    }
+}
@@ -0,0 +1,61 @@
+use crate::scheduler;
+use common::introspection::ThreadInfo;
+use common::ipc_trace::IPCTraceRecord;
+use common::os_error::ResultCode;
+use common::system_info::{KernelVersion, MemoryRegion, PhysicalMemory, Platform};
+use francium_common::types::FramebufferInfo;
+
+// Helpers for syscalls to safely touch the current process's memory.
+// Anything outside the process's address space gets InvalidPointer instead of a kernel fault.
+
+pub fn copy_from_user(user_ptr: usize, buf: &mut [u8]) -> Result<(), ResultCode> {
+    let proc = scheduler::get_current_process();
+    let locked = proc.lock();
+    locked.address_space.copy_from_user(user_ptr, buf)
+}
+
+pub fn copy_to_user(user_ptr: usize, buf: &[u8]) -> Result<(), ResultCode> {
+    let proc = scheduler::get_current_process();
+    let locked = proc.lock();
+    locked.address_space.copy_to_user(user_ptr, buf)
+}
+
+// Only use this with plain old data! Any bit pattern has to be a valid T.
+pub fn read_user_slice<T: Copy>(user_ptr: *const T, out: &mut [T]) -> Result<(), ResultCode> {
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(out.as_mut_ptr() as *mut u8, core::mem::size_of_val(out))
+    };
+    copy_from_user(user_ptr as usize, buf)
+}
+
+// Types that get copied out to userspace byte for byte. They must not have any padding, or
+// whatever was in the kernel's memory there goes along with them.
+pub unsafe trait UserData {}
+
+unsafe impl UserData for u32 {}
+unsafe impl UserData for u64 {}
+unsafe impl UserData for usize {}
+unsafe impl UserData for IPCTraceRecord {}
+unsafe impl UserData for ThreadInfo {}
+unsafe impl UserData for MemoryRegion {}
+unsafe impl UserData for Platform {}
+unsafe impl UserData for FramebufferInfo {}
+unsafe impl UserData for PhysicalMemory {}
+unsafe impl UserData for KernelVersion {}
+
+pub fn as_bytes<T: UserData>(value: &T) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+    }
+}
+
+pub fn write_user<T: UserData>(user_ptr: *mut T, value: &T) -> Result<(), ResultCode> {
+    copy_to_user(user_ptr as usize, as_bytes(value))
+}
+
+pub fn write_user_slice<T: UserData>(user_ptr: *mut T, values: &[T]) -> Result<(), ResultCode> {
+    let buf = unsafe {
+        core::slice::from_raw_parts(values.as_ptr() as *const u8, core::mem::size_of_val(values))
+    };
+    copy_to_user(user_ptr as usize, buf)
+}
@@ -1,29 +1,47 @@
+use crate::svc::user;
 use crate::waitable;
-use common::os_error::{ResultCode, RESULT_OK};
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
 use tracing::{event, Level};
 
-pub fn svc_wait_one(handle: u32) -> ResultCode {
-    event!(Level::TRACE, svc_name = "wait_one", handle = handle);
-    waitable::wait_handles(&[handle]);
-    RESULT_OK
+pub fn svc_wait_one(handle: u32, timeout_ns: u64) -> ResultCode {
+    event!(
+        Level::TRACE,
+        svc_name = "wait_one",
+        handle = handle,
+        timeout = timeout_ns
+    );
+
+    match waitable::wait_handles(&[handle], timeout_ns) {
+        Ok(_) => RESULT_OK,
+        Err(res) => res,
+    }
 }
 
 const MAX_HANDLES: usize = 128;
-pub fn svc_wait_many(handles_ptr: *const u32, handle_count: usize) -> (ResultCode, usize) {
+pub fn svc_wait_many(
+    handles_ptr: *const u32,
+    handle_count: usize,
+    timeout_ns: u64,
+) -> (ResultCode, usize) {
     event!(
         Level::TRACE,
         svc_name = "svc_wait_many",
         handles_ptr = handles_ptr as usize,
-        handle_count = handle_count
+        handle_count = handle_count,
+        timeout = timeout_ns
     );
 
-    let mut handles: [u32; MAX_HANDLES] = [0xffffffff; MAX_HANDLES];
-
-    unsafe {
-        core::ptr::copy_nonoverlapping(handles_ptr, &mut handles as *mut u32, handle_count);
+    if handle_count > MAX_HANDLES {
+        return (ResultCode::new(Module::Kernel, Reason::InvalidArgument), 0);
     }
 
-    let index = waitable::wait_handles(&handles[..handle_count]);
+    let mut handles: [u32; MAX_HANDLES] = [0xffffffff; MAX_HANDLES];
+    if let Err(res) = user::read_user_slice(handles_ptr, &mut handles[..handle_count]) {
+        return (res, 0);
+    }
 
-    (RESULT_OK, index)
+    match waitable::wait_handles(&handles[..handle_count], timeout_ns) {
+        Ok(index) => (RESULT_OK, index),
+        Err(res) => (res, 0),
+    }
 }
@@ -3,23 +3,21 @@ use crate::platform::DEFAULT_TIMER;
 use crate::scheduler;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 
 struct TimerEntry {
+    id: usize,
     deadline: u64,
     callback: Box<dyn Fn() -> () + Send>,
 }
 
-impl Drop for TimerEntry {
-    fn drop(&mut self) {
-        (self.callback)()
-    }
-}
-
 lazy_static! {
     static ref TIMER_QUEUE: Mutex<Vec<TimerEntry>> = Mutex::new(Vec::new());
 }
 
+static TIMER_ID: AtomicUsize = AtomicUsize::new(0);
+
 pub fn init() {
     let _locked = DEFAULT_TIMER.lock();
     // uhhhhhh idk
@@ -30,17 +28,38 @@ pub fn tick() {
 
     let current_time = { DEFAULT_TIMER.lock().get_counter_ns() };
 
-    let mut queue_lock = TIMER_QUEUE.lock();
-    queue_lock.retain(|x| x.deadline > current_time);
+    // Don't hold the queue lock while running callbacks, they might want to register or cancel timers.
+    let expired: Vec<TimerEntry> = {
+        let mut queue_lock = TIMER_QUEUE.lock();
+        let (expired, pending) = queue_lock
+            .drain(..)
+            .partition(|x| x.deadline <= current_time);
+        *queue_lock = pending;
+        expired
+    };
+
+    for entry in expired {
+        (entry.callback)();
+    }
 }
 
-pub fn register_timer(offset: u64, callback: Box<dyn Fn() -> () + Send>) {
+// Returns an id that can be passed to cancel_timer.
+pub fn register_timer(offset: u64, callback: Box<dyn Fn() -> () + Send>) -> usize {
     let current_time = { DEFAULT_TIMER.lock().get_counter_ns() };
+    let id = TIMER_ID.fetch_add(1, Ordering::SeqCst);
+
     let mut queue_lock = TIMER_QUEUE.lock();
     queue_lock.push(TimerEntry {
-        deadline: current_time + offset,
+        id: id,
+        deadline: current_time.saturating_add(offset),
         callback: callback,
     });
+    id
+}
+
+// Removes a timer without running its callback. Does nothing if it already fired.
+pub fn cancel_timer(id: usize) {
+    TIMER_QUEUE.lock().retain(|x| x.id != id);
 }
 
 pub fn get_counter_ns() -> u64 {
@@ -1,8 +1,13 @@
 use crate::handle::HandleObject;
 use crate::process::Thread;
 use crate::scheduler;
+use crate::timer;
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use common::constants::TIMEOUT_INFINITE;
+use common::os_error::{Module, Reason, ResultCode};
 use core::sync::atomic::{AtomicBool, Ordering};
+use francium_common::types::HandleRights;
 use smallvec::SmallVec;
 use spin::Mutex;
 
@@ -57,12 +62,19 @@ impl Waiter {
 
     pub fn signal_one(&self, should_tick: bool) -> bool {
         let mut did_wake = false;
-        match self.waiters.lock().pop() {
-            Some(waiter) => {
-                did_wake = true;
-                scheduler::wake_thread(&waiter.0, waiter.1);
+        {
+            let mut waiters_locked = self.waiters.lock();
+            // Skip over any waiters that were already woken by something else (eg. a timeout).
+            while let Some(waiter) = waiters_locked.pop() {
+                if scheduler::wake_thread(&waiter.0, waiter.1) {
+                    did_wake = true;
+                    break;
+                }
             }
-            None => self.pending.store(true, Ordering::Release),
+        }
+
+        if !did_wake {
+            self.pending.store(true, Ordering::Release);
         }
 
         if should_tick && did_wake {
@@ -73,29 +85,75 @@ impl Waiter {
 
     pub fn signal_one_with_callback(&self, callback: &dyn Fn(&Arc<Thread>) -> ()) {
         let mut did_wake = false;
-
-        match self.waiters.lock().pop() {
-            Some(waiter) => {
-                callback(&waiter.0);
-                did_wake = true;
-                scheduler::wake_thread(&waiter.0, waiter.1);
+        {
+            let mut waiters_locked = self.waiters.lock();
+            // As in signal_one, and the callback only sees the waiter that actually gets woken.
+            while let Some(waiter) = waiters_locked.pop() {
+                if scheduler::wake_thread_with_callback(&waiter.0, waiter.1, callback) {
+                    did_wake = true;
+                    break;
+                }
             }
-            None => self.pending.store(true, Ordering::Release),
         }
 
         if did_wake {
             scheduler::tick();
+        } else {
+            self.pending.store(true, Ordering::Release);
         }
     }
 
-    pub fn signal_all(&self) {
-        self.waiters
-            .lock()
-            .drain(..)
-            .map(|x| {
-                scheduler::wake_thread(&x.0, x.1);
-            })
-            .for_each(drop);
+    // Returns how many waiters were woken, not counting any that had already woken up.
+    pub fn signal_all(&self) -> usize {
+        let mut woken = 0;
+        for waiter in self.waiters.lock().drain(..) {
+            if scheduler::wake_thread(&waiter.0, waiter.1) {
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    // Wake up to count waiters, without leaving the waiter pending if there weren't enough.
+    // Returns how many were woken.
+    pub fn signal_n(&self, count: usize) -> usize {
+        let mut woken = 0;
+        let mut waiters_locked = self.waiters.lock();
+        while woken < count {
+            match waiters_locked.pop() {
+                Some(waiter) => {
+                    if scheduler::wake_thread(&waiter.0, waiter.1) {
+                        woken += 1;
+                    }
+                }
+                None => break,
+            }
+        }
+        woken
+    }
+
+    // Move up to count waiters onto another waiter, without waking them. The callback sees each
+    // thread that moves. Returns how many were moved.
+    pub fn requeue_to(
+        &self,
+        other: &Waiter,
+        count: usize,
+        callback: &dyn Fn(&Arc<Thread>),
+    ) -> usize {
+        let mut waiters_locked = self.waiters.lock();
+        let count = core::cmp::min(count, waiters_locked.len());
+        let start = waiters_locked.len() - count;
+
+        let mut other_locked = other.waiters.lock();
+        for waiter in waiters_locked.drain(start..) {
+            callback(&waiter.0);
+            other_locked.push(waiter);
+        }
+        count
+    }
+
+    pub fn has_waiters(&self) -> bool {
+        !self.waiters.lock().is_empty()
     }
 
     pub fn clear(&self) {
@@ -135,11 +193,34 @@ pub trait Waitable {
     }
 }
 
+// Tag a thread is woken with when its wait times out.
+pub const TIMED_OUT_TAG: usize = usize::MAX;
+
+// Suspend the current thread until it gets woken, or until timeout_ns passes.
+// Returns the wake tag, which is TIMED_OUT_TAG if the timeout expired first.
+pub fn suspend_current_thread_timeout(timeout_ns: u64) -> usize {
+    if timeout_ns == TIMEOUT_INFINITE {
+        return scheduler::suspend_current_thread();
+    }
+
+    let thread = scheduler::get_current_thread();
+    let timer_id = timer::register_timer(
+        timeout_ns,
+        Box::new(move || {
+            scheduler::wake_thread(&thread, TIMED_OUT_TAG);
+        }),
+    );
+
+    let tag = scheduler::suspend_current_thread();
+    timer::cancel_timer(timer_id);
+    tag
+}
+
 const MAX_HANDLES: usize = 128;
 const INVALID_HANDLE: HandleObject = HandleObject::Invalid;
 
 // returns index
-pub fn wait_handles(handles: &[u32]) -> usize {
+pub fn wait_handles(handles: &[u32], timeout_ns: u64) -> Result<usize, ResultCode> {
     let mut handle_objects = [INVALID_HANDLE; MAX_HANDLES];
     let handle_objects = &mut handle_objects[0..handles.len()];
 
@@ -148,7 +229,10 @@ pub fn wait_handles(handles: &[u32]) -> usize {
         let process = process_locked.lock();
 
         for (i, handle) in handles.iter().enumerate() {
-            handle_objects[i] = process.handle_table.get_object(*handle);
+            handle_objects[i] = process
+                .handle_table
+                .get_object_with_rights(*handle, HandleRights::WAIT)?
+                .0;
         }
     }
 
@@ -169,7 +253,12 @@ pub fn wait_handles(handles: &[u32]) -> usize {
 
             HandleObject::ServerSession(server_session) => {
                 // XXX: Big hack, we love to see it. Ordering here is important, post_wait has to remove the pending status first.
-                if server_session.post_wait(index) || server_session.queue.lock().len() > 0 {
+                // A closed session stays signalled, so the server can't miss it.
+                if server_session.post_wait(index)
+                    || server_session.queue.lock().len() > 0
+                    || server_session.has_oneway()
+                    || server_session.is_peer_closed()
+                {
                     any_pending = true;
                     tag = index;
                     break;
@@ -177,7 +266,10 @@ pub fn wait_handles(handles: &[u32]) -> usize {
             }
 
             HandleObject::ClientSession(client_session) => {
-                if client_session.post_wait(index) {
+                if client_session.post_wait(index)
+                    || client_session.has_oneway()
+                    || client_session.is_peer_closed()
+                {
                     any_pending = true;
                     tag = index;
                     break;
@@ -201,12 +293,41 @@ pub fn wait_handles(handles: &[u32]) -> usize {
                 }
             }
 
+            HandleObject::Timer(timer) => {
+                if timer.post_wait(index) {
+                    any_pending = true;
+                    tag = index;
+                    break;
+                }
+            }
+
+            HandleObject::Semaphore(semaphore) => {
+                if semaphore.post_wait(index) {
+                    any_pending = true;
+                    tag = index;
+                    break;
+                }
+            }
+
+            HandleObject::IPCRequest(request) => {
+                if request.post_wait(index) || request.is_complete() {
+                    any_pending = true;
+                    tag = index;
+                    break;
+                }
+            }
+
             _ => {}
         }
     }
 
     if !any_pending {
-        tag = scheduler::suspend_current_thread();
+        // A zero timeout is just a poll, don't bother suspending.
+        tag = if timeout_ns == 0 {
+            TIMED_OUT_TAG
+        } else {
+            suspend_current_thread_timeout(timeout_ns)
+        };
     }
 
     for handle in handle_objects.iter() {
@@ -228,9 +349,25 @@ pub fn wait_handles(handles: &[u32]) -> usize {
                 event.remove_wait();
             }
 
+            HandleObject::Timer(timer) => {
+                timer.remove_wait();
+            }
+
+            HandleObject::Semaphore(semaphore) => {
+                semaphore.remove_wait();
+            }
+
+            HandleObject::IPCRequest(request) => {
+                request.remove_wait();
+            }
+
             _ => {}
         }
     }
 
-    tag
+    if tag == TIMED_OUT_TAG {
+        Err(ResultCode::new(Module::Kernel, Reason::TimedOut))
+    } else {
+        Ok(tag)
+    }
 }
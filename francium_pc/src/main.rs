@@ -48,6 +48,8 @@ fn bootloader_main(info: &'static mut bootloader_api::BootInfo) -> ! {
         if m.kind == bootloader_api::info::MemoryRegionKind::Usable {
             println!("using {:?} for memory", m);
             init::setup_physical_allocator(m.start as usize, m.end as usize);
+        } else if m.kind == bootloader_api::info::MemoryRegionKind::Bootloader {
+            init::add_bootloader_region(m.start as usize, m.end as usize);
         }
     }
 
@@ -152,6 +154,7 @@ fn bootloader_main(info: &'static mut bootloader_api::BootInfo) -> ! {
     //scheduler::register_thread(fs_main_thread.clone());
 
     //let test_main_thread = init::load_process(test_buf, "test");
+    //init::grant_introspection(&test_main_thread);
     //scheduler::register_thread(test_main_thread.clone());
 
     let sm_main_thread = init::load_process(sm_buf, "sm");
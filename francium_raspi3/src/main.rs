@@ -34,6 +34,8 @@ pub extern "C" fn rust_main() -> ! {
 
     print_log_sink::init().unwrap();
 
+    platform::framebuffer_init();
+
     platform::scheduler_pre_init();
     scheduler::init(1);
     // todo
@@ -51,6 +53,7 @@ pub extern "C" fn rust_main() -> ! {
     scheduler::register_thread(fs_main_thread.clone());
 
     let test_main_thread = init::load_process(test_buf, "test");
+    init::grant_introspection(&test_main_thread);
     scheduler::register_thread(test_main_thread.clone());
 
     let sm_main_thread = init::load_process(sm_buf, "sm");
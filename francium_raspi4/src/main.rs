@@ -36,6 +36,8 @@ pub extern "C" fn rust_main() -> ! {
 
     print_log_sink::init().unwrap();
 
+    platform::framebuffer_init();
+
     println!("setup pre scheduler");
 
     platform::scheduler_pre_init();
@@ -59,6 +61,7 @@ pub extern "C" fn rust_main() -> ! {
     scheduler::register_thread(fs_main_thread.clone());
 
     let test_main_thread = init::load_process(test_buf, "test");
+    init::grant_introspection(&test_main_thread);
     scheduler::register_thread(test_main_thread.clone());
 
     let sm_main_thread = init::load_process(sm_buf, "sm");
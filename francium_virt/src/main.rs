@@ -12,7 +12,7 @@ pub extern "C" fn rust_main() -> ! {
     platform::platform_specific_init();
 
     let phys_mem_start = platform::PHYS_MEM_BASE + 0x100000;
-    let phys_mem_end = platform::PHYS_MEM_BASE + platform::PHYS_MEM_SIZE;
+    let phys_mem_end = platform::FRAMEBUFFER_PHYS;
 
     init::setup_physical_allocator(phys_mem_start, phys_mem_end);
     init::setup_virtual_memory();
@@ -34,6 +34,8 @@ pub extern "C" fn rust_main() -> ! {
 
     print_log_sink::init().unwrap();
 
+    platform::framebuffer_init();
+
     platform::scheduler_pre_init();
     scheduler::init(platform::get_cpu_count());
     // todo
@@ -51,6 +53,7 @@ pub extern "C" fn rust_main() -> ! {
     scheduler::register_thread(fs_main_thread.clone());
 
     let test_main_thread = init::load_process(test_buf, "test");
+    init::grant_introspection(&test_main_thread);
     scheduler::register_thread(test_main_thread.clone());
 
     let sm_main_thread = init::load_process(sm_buf, "sm");
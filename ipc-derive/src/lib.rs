@@ -0,0 +1,144 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, GenericParam, Index};
+
+// Fields are encoded in declaration order. Enums are a u32 variant index, then the variant's fields.
+// Reading an unknown variant index fails with InvalidMessage.
+#[proc_macro_derive(IPCValue)]
+pub fn derive_ipc_value(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut input = parse_macro_input!(input as DeriveInput);
+
+    for param in input.generics.params.iter_mut() {
+        if let GenericParam::Type(ty) = param {
+            ty.bounds
+                .push(syn::parse_quote!(::process::ipc::message::IPCValue));
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let (read, write) = match &input.data {
+        Data::Struct(data) => {
+            let read_struct = read_fields(quote!(Self), &data.fields);
+            (quote!(Ok(#read_struct)), write_struct(&data.fields))
+        }
+        Data::Enum(data) => {
+            let mut read_arms = Vec::new();
+            let mut write_arms = Vec::new();
+
+            for (index, variant) in data.variants.iter().enumerate() {
+                let index = index as u32;
+                let variant_name = &variant.ident;
+
+                let read_variant = read_fields(quote!(Self::#variant_name), &variant.fields);
+                read_arms.push(quote! {
+                    #index => Ok(#read_variant)
+                });
+
+                let (pattern, write_variant) = write_variant(&variant.fields);
+                write_arms.push(quote! {
+                    Self::#variant_name #pattern => {
+                        <u32 as ::process::ipc::message::IPCValue>::write(msg, &#index);
+                        #write_variant
+                    }
+                });
+            }
+
+            (
+                quote! {
+                    match <u32 as ::process::ipc::message::IPCValue>::read(msg)? {
+                        #(#read_arms,)*
+                        _ => Err(::process::ipc::message::invalid_message()),
+                    }
+                },
+                quote! {
+                    match val {
+                        #(#write_arms)*
+                    }
+                },
+            )
+        }
+        Data::Union(_) => {
+            return syn::Error::new_spanned(&input.ident, "IPCValue can't be derived for unions")
+                .to_compile_error()
+                .into();
+        }
+    };
+
+    quote! {
+        impl #impl_generics ::process::ipc::message::IPCValue for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn read(msg: &mut ::process::ipc::message::IPCMessage) -> ::process::os_error::OSResult<Self> {
+                #read
+            }
+
+            #[allow(unused_variables)]
+            fn write(msg: &mut ::process::ipc::message::IPCMessage, val: &Self) {
+                #write
+            }
+        }
+    }
+    .into()
+}
+
+fn read_fields(constructor: TokenStream, fields: &Fields) -> TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let reads = named.named.iter().map(|field| {
+                let field_name = &field.ident;
+                let ty = &field.ty;
+                quote!(#field_name: <#ty as ::process::ipc::message::IPCValue>::read(msg)?)
+            });
+            quote!(#constructor { #(#reads),* })
+        }
+        Fields::Unnamed(unnamed) => {
+            let reads = unnamed.unnamed.iter().map(|field| {
+                let ty = &field.ty;
+                quote!(<#ty as ::process::ipc::message::IPCValue>::read(msg)?)
+            });
+            quote!(#constructor(#(#reads),*))
+        }
+        Fields::Unit => constructor,
+    }
+}
+
+fn write_struct(fields: &Fields) -> TokenStream {
+    let writes = fields.iter().enumerate().map(|(index, field)| {
+        let ty = &field.ty;
+        let member = match &field.ident {
+            Some(field_name) => quote!(#field_name),
+            None => {
+                let index = Index::from(index);
+                quote!(#index)
+            }
+        };
+        quote!(<#ty as ::process::ipc::message::IPCValue>::write(msg, &val.#member);)
+    });
+    quote!(#(#writes)*)
+}
+
+// Returns the pattern that binds a variant's fields, and the writes for those bindings.
+fn write_variant(fields: &Fields) -> (TokenStream, TokenStream) {
+    let bindings: Vec<_> = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| match &field.ident {
+            Some(field_name) => field_name.clone(),
+            None => format_ident!("__field{}", index),
+        })
+        .collect();
+
+    let writes = fields.iter().zip(bindings.iter()).map(|(field, binding)| {
+        let ty = &field.ty;
+        quote!(<#ty as ::process::ipc::message::IPCValue>::write(msg, #binding);)
+    });
+
+    let pattern = match fields {
+        Fields::Named(_) => quote!({ #(#bindings),* }),
+        Fields::Unnamed(_) => quote!((#(#bindings),*)),
+        Fields::Unit => quote!(),
+    };
+
+    (pattern, quote!(#(#writes)*))
+}
@@ -0,0 +1,40 @@
+use std::env;
+use std::fs;
+use std::process::exit;
+
+// Checks every IPC definition in a directory, so that mistakes show up before a build script hits them.
+fn main() {
+    let dir = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "ipc_definitions".to_string());
+
+    let mut paths: Vec<_> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().map_or(false, |ext| ext == "toml"))
+            .collect(),
+        Err(err) => {
+            eprintln!("{}: {}", dir, err);
+            exit(1);
+        }
+    };
+    paths.sort();
+
+    let mut failed = false;
+    for path in paths {
+        let path = path.to_string_lossy();
+        match ipc_gen_buildtime::check_file(&path) {
+            Ok(hash) => println!("{}: ok, interface hash {:016x}", path, hash),
+            Err(errors) => {
+                for err in errors {
+                    eprintln!("{}: {}", path, err);
+                }
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
+}
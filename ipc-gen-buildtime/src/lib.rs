@@ -7,28 +7,76 @@ use std::path::Path;
 use syn::__private::TokenStream2;
 use syn::{Ident, Path as SynPath, Type};
 
+mod validate;
+
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Ty {
     name: String,
     ty: String,
+    // A &mut [u8] that the server only writes to, so it doesn't need to be copied over first.
+    out: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct MethodInfo {
     name: String,
     id: u32,
     inputs: Vec<Ty>,
     output: String,
     is_async: Option<bool>,
+    // One-way methods don't get a reply, so the client doesn't wait for the server.
+    is_oneway: Option<bool>,
+    // The sub-interface that the returned TranslateMoveHandle is a session for, so that the client
+    // can hand back a typed session.
+    session: Option<String>,
+}
+
+// How an input gets to the server. Byte slices go as buffer descriptors instead of inline.
+#[derive(PartialEq)]
+enum InputKind {
+    Value,
+    SendBuffer,
+    ReceiveBuffer,
+    ExchangeBuffer,
+}
+
+impl InputKind {
+    fn new(ty: &Type, out: bool) -> InputKind {
+        if let Type::Reference(reference) = ty
+            && let Type::Slice(slice) = &*reference.elem
+            && let Type::Path(elem) = &*slice.elem
+            && elem.path.is_ident("u8")
+        {
+            if reference.mutability.is_some() {
+                if out {
+                    InputKind::ReceiveBuffer
+                } else {
+                    InputKind::ExchangeBuffer
+                }
+            } else {
+                InputKind::SendBuffer
+            }
+        } else {
+            InputKind::Value
+        }
+    }
 }
 
 struct Method {
     name: String,
     id: u32,
     input_names: Vec<Ident>,
+    input_types: Vec<Type>,
+    input_kinds: Vec<InputKind>,
     inputs: Vec<TokenStream2>,
     output_type: Type,
+    // What the client returns, which differs from output_type for methods returning a session.
+    client_output_type: Type,
+    session: Option<Ident>,
     is_async: Option<bool>,
+    is_oneway: bool,
 }
 
 impl Method {
@@ -38,139 +86,444 @@ impl Method {
             .iter()
             .map(|x| format_ident!("{}", x.name))
             .collect();
-        let inputs: Vec<_> = info
+        let input_types: Vec<Type> = info
             .inputs
             .iter()
-            .map(|x| {
-                let name = format_ident!("{}", x.name);
-                let ty_: Type = syn::parse_str(&x.ty).unwrap();
-                quote!(#name: #ty_)
-            })
+            .map(|x| syn::parse_str(&x.ty).unwrap())
+            .collect();
+        let input_kinds: Vec<_> = input_types
+            .iter()
+            .zip(info.inputs.iter())
+            .map(|(ty_, input)| InputKind::new(ty_, input.out.unwrap_or(false)))
+            .collect();
+        let inputs: Vec<_> = input_names
+            .iter()
+            .zip(input_types.iter())
+            .map(|(name, ty_)| quote!(#name: #ty_))
             .collect();
 
         let output_type: Type = syn::parse_str(&info.output).unwrap();
 
+        let is_oneway = info.is_oneway.unwrap_or(false);
+
+        let session = info.session.as_ref().map(|x| format_ident!("{}", x));
+        let client_output_type: Type = match &session {
+            // Sending can still fail, eg. with TryAgain when the server has too many queued up.
+            _ if is_oneway => syn::parse_quote!(OSResult<()>),
+            Some(session) => match info.output.replace(' ', "").as_str() {
+                "OSResult<TranslateMoveHandle>" => syn::parse_quote!(OSResult<#session>),
+                "TranslateMoveHandle" => syn::parse_quote!(#session),
+                _ => panic!(
+                    "Method {} returns a session, so it must return a TranslateMoveHandle",
+                    info.name
+                ),
+            },
+            None => output_type.clone(),
+        };
+
         Method {
             name: info.name.clone(),
             id: info.id,
             input_names: input_names,
+            input_types: input_types,
+            input_kinds: input_kinds,
             inputs: inputs,
             output_type: output_type,
+            client_output_type: client_output_type,
+            session: session,
             is_async: info.is_async,
+            is_oneway: is_oneway,
         }
     }
 
-    fn server(&self) -> TokenStream2 {
+    fn returns_result(&self) -> bool {
+        matches!(&self.output_type, Type::Path(x)
+            if x.path.segments.last().map_or(false, |last| last.ident == "OSResult"))
+    }
+
+    // Methods that can fail default to saying so, so a server only has to implement what it
+    // supports. Anything else has to be implemented.
+    fn trait_method(&self) -> TokenStream2 {
+        let method_name = format_ident!("{}", self.name);
+        let inputs = &self.inputs;
+        let output_type = &self.output_type;
+        let asyncness = if self.is_async.unwrap_or(false) {
+            quote!(async)
+        } else {
+            quote!()
+        };
+
+        if self.returns_result() {
+            quote! {
+                #[allow(unused_variables)]
+                #asyncness fn #method_name (&self, #(#inputs),*) -> #output_type {
+                    Err(process::os_error::OSError::new(
+                        process::os_error::Module::LibProcess,
+                        process::os_error::Reason::NotImplemented,
+                    ))
+                }
+            }
+        } else {
+            quote! {
+                #asyncness fn #method_name (&self, #(#inputs),*) -> #output_type;
+            }
+        }
+    }
+
+    fn server(&self, trait_name: &Ident) -> TokenStream2 {
         let method_name = format_ident!("{}", self.name);
         let method_id: u32 = self.id;
         let input_names = &self.input_names;
-        let inputs = &self.inputs;
         let output_type = &self.output_type;
-        let maybe_await = if let Some(is_async) = self.is_async {
-            if is_async {
-                quote!(.await)
+
+        let mut read_inputs = Vec::new();
+        let mut input_values = Vec::new();
+        let mut input_bindings = Vec::new();
+        let mut call_args = Vec::new();
+        let mut write_buffers = Vec::new();
+        for ((name, ty_), kind) in input_names
+            .iter()
+            .zip(self.input_types.iter())
+            .zip(self.input_kinds.iter())
+        {
+            if *kind == InputKind::Value {
+                read_inputs.push(quote!(let #name: #ty_ = request_msg.read()?;));
+                input_values.push(quote!(#name));
+                input_bindings.push(quote!(#name));
+                call_args.push(quote!(#name));
+                continue;
+            }
+
+            // Pull the client's buffer over now, the session may be handling another request by
+            // the time the method runs. There's nothing to pull for a buffer that's only written.
+            let buffer_name = format_ident!("__{}_ipc_buffer", name);
+            read_inputs.push(quote! {
+                let #buffer_name: process::ipc::message::IPCBuffer = request_msg.read()?;
+                let mut #name = vec![0u8; #buffer_name.size];
+            });
+            if *kind != InputKind::ReceiveBuffer {
+                read_inputs.push(quote! {
+                    crate::syscalls::ipc_read_buffer(token, #buffer_name.index, &mut #name)?;
+                });
+            }
+
+            if *kind == InputKind::ExchangeBuffer || *kind == InputKind::ReceiveBuffer {
+                // The buffer's index is needed again to write it back.
+                input_values.push(quote!(#name));
+                input_values.push(quote!(#buffer_name));
+                input_bindings.push(quote!(mut #name));
+                input_bindings.push(quote!(#buffer_name));
+                call_args.push(quote!(&mut #name[..]));
+                write_buffers.push(quote! {
+                    crate::syscalls::ipc_write_buffer(token, #buffer_name.index, &#name)?;
+                });
             } else {
-                quote!()
+                input_values.push(quote!(#name));
+                input_bindings.push(quote!(#name));
+                call_args.push(quote!(&#name[..]));
             }
+        }
+
+        // Anything the client got wrong gets an error reply rather than reaching the method.
+        let read_inputs = quote! {
+            request_msg.read_translates();
+            let inputs = (|| -> process::os_error::OSResult<_> {
+                #(#read_inputs)*
+                Ok((#(#input_values,)*))
+            })();
+            let (#(#input_bindings,)*) = match inputs {
+                Ok(inputs) => inputs,
+                Err(err) => return process::ipc_server::reply_error(token, ipc_buffer, err),
+            };
+        };
+
+        // The client can unmap a buffer while the request is in flight, so writing it back can fail.
+        // That turns the reply into an error.
+        let write_reply = if write_buffers.is_empty() {
+            quote!(reply_msg.write_reply(res);)
         } else {
-            quote!()
+            quote! {
+                let written = (|| -> process::os_error::OSResult<()> {
+                    #(#write_buffers)*
+                    Ok(())
+                })();
+                match written {
+                    Ok(()) => reply_msg.write_reply(res),
+                    Err(err) => reply_msg.write_error_reply(err),
+                }
+            }
         };
 
+        let is_async = self.is_async.unwrap_or(false);
+        if self.is_oneway {
+            // Nobody is waiting for a reply, so don't send one.
+            let call = if is_async {
+                quote! {
+                    tokio::spawn(async move {
+                        #trait_name::#method_name(&*self, #(#call_args),*).await;
+                    });
+                }
+            } else {
+                quote! {
+                    #trait_name::#method_name(&*self, #(#call_args),*);
+                }
+            };
+
+            return quote! {
+                #method_id => {
+                    #read_inputs
+
+                    #call
+                    false
+                }
+            };
+        }
+
+        if !is_async {
+            // Synchronous methods reply straight out of the receive buffer, so that the server loop
+            // can send the reply along with its next receive.
+            return quote! {
+                #method_id => {
+                    #read_inputs
+
+                    let res: #output_type = #trait_name::#method_name(&*self, #(#call_args),*);
+                    let mut reply_msg = process::ipc::message::IPCMessage::new(ipc_buffer);
+                    #write_reply
+                    true
+                }
+            };
+        }
+
         quote! {
             #method_id => {
-                request_msg.read_translates();
-
-                #(let #inputs = request_msg.read();)*
+                #read_inputs
 
+                // The request keeps its own token and reply buffer, so any number of these can be
+                // in flight on one session. The server keeps the session open until it's done.
+                self.get_server().get_server_impl().begin_async(token);
                 tokio::spawn(async move {
-                    let res: #output_type = self.#method_name (#(#input_names),*) #maybe_await;
-                    let mut reply_msg = unsafe { process::ipc::message::IPCMessage::new(&mut IPC_BUFFER) };
-                    reply_msg.write(res);
-                    reply_msg.write_translates();
-                    reply_msg.write_header_for(0);
-
-                    unsafe { crate::syscalls::ipc_reply(h, &mut IPC_BUFFER).unwrap(); }
+                    let res: #output_type = #trait_name::#method_name(&*self, #(#call_args),*).await;
+                    let mut reply_buffer = [0u8; 128];
+                    let mut reply_msg = process::ipc::message::IPCMessage::new(&mut reply_buffer);
+                    #write_reply
+
+                    // The client may have gone away in the meantime, which is no reason to fail.
+                    let _ = crate::syscalls::ipc_reply(token, &mut reply_buffer);
+                    self.get_server().get_server_impl().end_async(token);
                 });
+                false
             }
         }
     }
 
     fn client(&self, handle_accessor: &str) -> syn::__private::TokenStream2 {
         let ipc_handle_accessor: SynPath = syn::parse_str(handle_accessor).unwrap();
+        let rf = self.client_request_failed();
         let method_name = format_ident!("{}", self.name);
         let method_name_with_handle = format_ident!("{}_with_handle", self.name);
 
         let input_names = &self.input_names;
         let inputs = &self.inputs;
-        let output_type = &self.output_type;
+        let output_type = &self.client_output_type;
 
         let body = self.client_body();
+        let async_body = self.client_async_body();
+        let async_wrapper = if self.has_async_client() {
+            let method_name_async = format_ident!("{}_async", self.name);
+            let method_name_with_handle_async = format_ident!("{}_with_handle_async", self.name);
+            quote! {
+                pub async fn #method_name_async ( #(#inputs),* ) -> #output_type {
+                    let __ipc_handle = #ipc_handle_accessor()#rf;
+                    #method_name_with_handle_async(__ipc_handle, #(#input_names),*).await
+                }
+            }
+        } else {
+            quote! {}
+        };
+
         quote! {
             pub fn #method_name ( #(#inputs),* ) -> #output_type {
-                let __ipc_handle = #ipc_handle_accessor();
+                let __ipc_handle = #ipc_handle_accessor()#rf;
                 #method_name_with_handle(__ipc_handle, #(#input_names),*)
             }
 
+            #async_wrapper
+
             #body
+
+            #async_body
         }
     }
 
+    // Sub-interface methods go in the impl of their session struct.
     fn sub_client(&self) -> syn::__private::TokenStream2 {
         let method_name = format_ident!("{}", self.name);
         let method_name_with_handle = format_ident!("{}_with_handle", self.name);
 
         let input_names = &self.input_names;
         let inputs = &self.inputs;
-        let output_type = &self.output_type;
+        let output_type = &self.client_output_type;
 
         let body = self.client_body();
+        let async_body = self.client_async_body();
+        let async_wrapper = if self.has_async_client() {
+            let method_name_async = format_ident!("{}_async", self.name);
+            let method_name_with_handle_async = format_ident!("{}_with_handle_async", self.name);
+            quote! {
+                pub async fn #method_name_async ( &self, #(#inputs),* ) -> #output_type {
+                    Self::#method_name_with_handle_async(self.handle, #(#input_names),*).await
+                }
+            }
+        } else {
+            quote! {}
+        };
 
         quote! {
-            pub fn #method_name ( __ipc_handle: Handle, #(#inputs),* ) -> #output_type {
-                #method_name_with_handle(__ipc_handle, #(#input_names),*)
+            pub fn #method_name ( &self, #(#inputs),* ) -> #output_type {
+                Self::#method_name_with_handle(self.handle, #(#input_names),*)
             }
 
+            #async_wrapper
+
             #body
+
+            #async_body
         }
     }
 
-    fn client_body(&self) -> syn::__private::TokenStream2 {
-        let input_names = &self.input_names;
-        let inputs = &self.inputs;
+    // Buffers are read and written by the server after the request is sent, so an async request
+    // could outlive them if its future was dropped. Only methods without buffers get async stubs.
+    // One-way methods don't block anyway.
+    fn has_async_client(&self) -> bool {
+        !self.is_oneway && self.input_kinds.iter().all(|kind| *kind == InputKind::Value)
+    }
+
+    // Like an error reply, a request that couldn't be made (eg. because the session was closed, or
+    // the kernel rejected the message, or there was no session to make it on) is passed on by
+    // methods returning OSResult. One-way methods always do.
+    fn client_request_failed(&self) -> TokenStream2 {
+        if self.is_oneway || self.returns_result() {
+            quote!(?)
+        } else {
+            quote!(.expect("IPC request failed"))
+        }
+    }
+
+    // A method returning OSResult passes an error reply on. Anything else has no way to, so the
+    // server must have been broken.
+    fn client_dispatch_output(&self) -> TokenStream2 {
         let output_type = &self.output_type;
 
-        let dispatch_output = if let syn::Type::Tuple(x) = &self.output_type && x.elems.is_empty() {
-            quote! {}
+        let returns_result = self.returns_result();
+
+        let out = if returns_result {
+            quote!(out.and_then(|out| out))
         } else {
-            quote! {
-                let out: #output_type = reply_msg.read();
-                out
-            }
+            quote!(out.expect("Invalid IPC reply"))
         };
 
-        let write_inputs = if input_names.len() == 0 {
-            quote! {}
-        } else {
-            quote! { #(request_msg.write(#input_names));*; }
+        let out = match (&self.session, returns_result) {
+            (Some(session), true) => quote!(#out.map(|handle| #session::new(handle.0))),
+            (Some(session), false) => quote!(#session::new(#out.0)),
+            (None, _) => out,
         };
 
+        quote! {
+            let out: crate::os_error::OSResult<#output_type> = reply_msg.read_reply();
+            #out
+        }
+    }
+
+    fn client_async_body(&self) -> syn::__private::TokenStream2 {
+        if !self.has_async_client() {
+            return quote! {};
+        }
+
+        let input_names = &self.input_names;
+        let inputs = &self.inputs;
+        let output_type = &self.client_output_type;
+
+        let dispatch_output = self.client_dispatch_output();
+        let request_failed = self.client_request_failed();
+
+        let method_name = format_ident!("{}_with_handle_async", self.name);
+        let method_id: u32 = self.id;
+
+        quote! {
+            async fn #method_name ( __ipc_handle: Handle, #(#inputs),* ) -> #output_type {
+                // Each request gets its own buffer, since the reply arrives whenever it arrives.
+                let mut __ipc_buffer = Box::new([0u8; 128]);
+                {
+                    let mut request_msg = crate::ipc::message::IPCMessage::new(&mut __ipc_buffer[..]);
+
+                    #(request_msg.write(#input_names);)*
+
+                    request_msg.write_header_for(#method_id);
+                    request_msg.write_translates();
+                }
+
+                let mut __ipc_buffer = crate::ipc::async_request::send(__ipc_handle, __ipc_buffer)
+                    #request_failed
+                    .await
+                    #request_failed;
+
+                let mut reply_msg = crate::ipc::message::IPCMessage::new(&mut __ipc_buffer[..]);
+
+                #dispatch_output
+            }
+        }
+    }
+
+    fn client_body(&self) -> syn::__private::TokenStream2 {
+        let input_names = &self.input_names;
+        let inputs = &self.inputs;
+        let output_type = &self.client_output_type;
+
+        let dispatch_output = self.client_dispatch_output();
+        let request_failed = self.client_request_failed();
+
+        let write_inputs: Vec<_> = input_names
+            .iter()
+            .zip(self.input_kinds.iter())
+            .map(|(name, kind)| {
+                if *kind == InputKind::ReceiveBuffer {
+                    quote!(request_msg.write_receive_buffer(#name);)
+                } else {
+                    quote!(request_msg.write(#name);)
+                }
+            })
+            .collect();
+
         let method_name = format_ident!("{}_with_handle", self.name);
         let method_id: u32 = self.id;
 
+        if self.is_oneway {
+            return quote! {
+                fn #method_name ( __ipc_handle: Handle, #(#inputs),* ) -> #output_type {
+                    let mut request_msg = unsafe { crate::ipc::message::IPCMessage::new(&mut *core::ptr::addr_of_mut!(IPC_BUFFER)) };
+
+                    #(#write_inputs)*
+
+                    request_msg.write_header_for(#method_id);
+                    request_msg.write_translates();
+
+                    unsafe { crate::syscalls::ipc_send(__ipc_handle, &mut *core::ptr::addr_of_mut!(IPC_BUFFER)) }
+                }
+            };
+        }
+
         quote! {
             fn #method_name ( __ipc_handle: Handle, #(#inputs),* ) -> #output_type {
-                let mut request_msg = unsafe { crate::ipc::message::IPCMessage::new(&mut IPC_BUFFER) };
+                let mut request_msg = unsafe { crate::ipc::message::IPCMessage::new(&mut *core::ptr::addr_of_mut!(IPC_BUFFER)) };
 
-                #write_inputs
+                #(#write_inputs)*
 
                 request_msg.write_header_for(#method_id);
                 request_msg.write_translates();
 
-                unsafe { crate::syscalls::ipc_request(__ipc_handle, &mut IPC_BUFFER).unwrap(); }
+                unsafe { crate::syscalls::ipc_request(__ipc_handle, &mut *core::ptr::addr_of_mut!(IPC_BUFFER))#request_failed; }
 
-                let mut reply_msg = unsafe { crate::ipc::message::IPCMessage::new(&mut IPC_BUFFER) };
-                reply_msg.read_header();
-                reply_msg.read_translates();
+                let mut reply_msg = unsafe { crate::ipc::message::IPCMessage::new(&mut *core::ptr::addr_of_mut!(IPC_BUFFER)) };
 
                 #dispatch_output
             }
@@ -179,16 +532,30 @@ impl Method {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct ServerConfig {
     name: String,
     struct_name: String,
+    // A fn() -> OSResult<Handle> that finds the server's main session.
     handle_accessor: String,
+    // Bumped when the meaning of the interface changes without its shape changing.
+    version: Option<u32>,
+    // Types defined outside of libprocess's builtins, which must implement IPCValue.
+    #[serde(default)]
+    types: Vec<String>,
     main_interface: Interface,
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     sub_interfaces: Vec<Interface>,
 }
 
+impl ServerConfig {
+    fn interfaces(&self) -> impl Iterator<Item = &Interface> {
+        std::iter::once(&self.main_interface).chain(self.sub_interfaces.iter())
+    }
+}
+
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Interface {
     //name: Option<String>,
     session_name: String,
@@ -226,29 +593,40 @@ fn generate_server_ipcserver_impl(server: &ServerConfig) -> String {
     server_impl.to_string()
 }
 
-fn generate_server_interface(interface: &Interface) -> String {
-    let server_methods: Vec<_> = interface
-        .methods
-        .iter()
-        .map(|x| Method::new(x).server())
-        .collect();
+fn generate_server_interface(interface: &Interface, interface_hash: u64) -> String {
     let session_name = format_ident!("{}", interface.session_name);
+    let trait_name = format_ident!("{}Interface", interface.session_name);
+
+    let methods: Vec<_> = interface.methods.iter().map(Method::new).collect();
+    let trait_methods: Vec<_> = methods.iter().map(|x| x.trait_method()).collect();
+    let server_methods: Vec<_> = methods.iter().map(|x| x.server(&trait_name)).collect();
 
     let server_impl = quote!(
+        // Implemented by the session, with #[async_trait::async_trait] if any methods are async.
+        #[async_trait::async_trait]
+        trait #trait_name: Send + Sync {
+            #(#trait_methods)*
+        }
+
         impl IPCSession for #session_name {
-            fn process(self: std::sync::Arc<Self>, h: Handle, ipc_buffer: &mut [u8]) {
-                self.process_internal(h, ipc_buffer);
+            fn process(self: std::sync::Arc<Self>, token: ReplyToken, ipc_buffer: &mut [u8]) -> bool {
+                self.process_internal(token, ipc_buffer)
             }
         }
 
         impl #session_name {
-            fn process_internal(self: std::sync::Arc<Self>, h: Handle, ipc_buffer: &mut [u8]) {
+            // token is only needed by methods that take buffers or reply asynchronously.
+            #[allow(unused_variables)]
+            fn process_internal(self: std::sync::Arc<Self>, token: ReplyToken, ipc_buffer: &mut [u8]) -> bool {
                 let mut request_msg = process::ipc::message::IPCMessage::new(ipc_buffer);
-                request_msg.read_header();
+                if let Err(err) = request_msg.read_header() {
+                    return process::ipc_server::reply_error(token, ipc_buffer, err);
+                }
 
                 match request_msg.header.id {
+                    0 => process::ipc_server::reply_interface_version(token, ipc_buffer, #interface_hash),
                     #(#server_methods),*,
-                    _ => { panic!("Unexpected IPC message ID!") }
+                    _ => process::ipc_server::reply_error(token, ipc_buffer, process::ipc::message::invalid_message())
                 }
             }
         }
@@ -256,23 +634,56 @@ fn generate_server_interface(interface: &Interface) -> String {
     server_impl.to_string()
 }
 
+// Loads and validates a definition file, with every problem found.
+fn load_spec(path: &str) -> Result<ServerConfig, Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|err| vec![err.to_string()])?;
+    let spec = toml::from_str::<ServerConfig>(&text).map_err(|err| vec![err.to_string()])?;
+
+    let errors = validate::validate(&spec);
+    if errors.is_empty() {
+        Ok(spec)
+    } else {
+        Err(errors)
+    }
+}
+
+// Build scripts can only give up, but they can at least say why.
+fn load_spec_or_panic(path: &str) -> ServerConfig {
+    match load_spec(path) {
+        Ok(spec) => spec,
+        Err(errors) => panic!(
+            "Invalid IPC definition:\n{}",
+            errors
+                .iter()
+                .map(|err| format!("{}: {}", path, err))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    }
+}
+
+// Checks a definition file, returning the hash that its clients and servers will agree on.
+pub fn check_file(path: &str) -> Result<u64, Vec<String>> {
+    load_spec(path).map(|spec| validate::interface_hash(&spec))
+}
+
 pub fn generate_server(path: &str) {
-    let spec = toml::from_str::<ServerConfig>(&fs::read_to_string(path).unwrap()).unwrap();
+    let spec = load_spec_or_panic(path);
+    let interface_hash = validate::interface_hash(&spec);
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join(spec.name.clone() + "_server_impl.rs");
 
-    let header = "use process::ipc::message::IPC_BUFFER;\n
-    use process::ipc_server::*;
+    let header = "use process::ipc_server::*;
     use std::sync::MutexGuard;"
         .to_string();
 
     let server_impl = generate_server_ipcserver_impl(&spec);
-    let server_main_impl = generate_server_interface(&spec.main_interface);
+    let server_main_impl = generate_server_interface(&spec.main_interface, interface_hash);
     let server_sub_impl = spec
         .sub_interfaces
         .iter()
-        .map(|x| generate_server_interface(x))
+        .map(|x| generate_server_interface(x, interface_hash))
         .collect::<Vec<String>>()
         .join("\n");
     fs::write(
@@ -284,11 +695,55 @@ pub fn generate_server(path: &str) {
     println!("cargo:rerun-if-changed={}", path);
 }
 
+// The client end of a sub-interface session.
+fn generate_session_client(interface: &Interface) -> TokenStream2 {
+    let session_name = format_ident!("{}", interface.session_name);
+    let methods: Vec<_> = interface
+        .methods
+        .iter()
+        .map(|x| Method::new(x).sub_client())
+        .collect();
+
+    quote! {
+        #[derive(Debug)]
+        pub struct #session_name {
+            handle: Handle,
+        }
+
+        impl #session_name {
+            // Takes ownership of the handle, which is closed when the session is dropped.
+            pub fn new(handle: Handle) -> #session_name {
+                #session_name { handle: handle }
+            }
+
+            pub fn handle(&self) -> Handle {
+                self.handle
+            }
+
+            // Gives the handle back without closing it.
+            pub fn into_handle(self) -> Handle {
+                let handle = self.handle;
+                core::mem::forget(self);
+                handle
+            }
+
+            #(#methods)*
+        }
+
+        impl Drop for #session_name {
+            fn drop(&mut self) {
+                let _ = crate::syscalls::close_handle(self.handle);
+            }
+        }
+    }
+}
+
 pub fn generate_client(path: &str) {
-    let spec = toml::from_str::<ServerConfig>(&fs::read_to_string(path).unwrap()).unwrap();
+    let spec = load_spec_or_panic(path);
+    let interface_hash = validate::interface_hash(&spec);
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
-    let dest_path = Path::new(&out_dir).join(spec.name + "_client_impl.rs");
+    let dest_path = Path::new(&out_dir).join(spec.name.clone() + "_client_impl.rs");
 
     let client_methods: Vec<_> = spec
         .main_interface
@@ -297,20 +752,62 @@ pub fn generate_client(path: &str) {
         .map(|x| Method::new(x).client(&spec.handle_accessor))
         .collect();
 
-    let sub_client_methods: Vec<_> = spec
+    let sub_clients: Vec<_> = spec
         .sub_interfaces
         .iter()
-        .map(|x| &x.methods)
-        .flatten()
-        .map(|x| Method::new(x).sub_client())
+        .map(generate_session_client)
+        .collect();
+
+    // Names for every method, so that IPC traces can be decoded.
+    let trace_interfaces: Vec<_> = spec
+        .interfaces()
+        .map(|interface| {
+            let session_name = &interface.session_name;
+            let methods = interface.methods.iter().map(|method| {
+                let id = method.id;
+                let name = &method.name;
+                let session = match &method.session {
+                    Some(session) => quote!(Some(#session)),
+                    None => quote!(None),
+                };
+                quote! {
+                    crate::ipc::trace::TraceMethod { id: #id, name: #name, session: #session }
+                }
+            });
+            quote! {
+                crate::ipc::trace::TraceInterface {
+                    session_name: #session_name,
+                    methods: &[#(#methods),*],
+                }
+            }
+        })
         .collect();
 
     let client_impl = quote! {
         use crate::ipc::message::IPC_BUFFER;
 
+        // The main interface comes first.
+        pub const TRACE_INTERFACES: &[crate::ipc::trace::TraceInterface] = &[#(#trace_interfaces),*];
+
+        pub const INTERFACE_HASH: u64 = #interface_hash;
+
+        // Method 0 asks the server whether it was built from the same definition, so that a
+        // mismatch fails here rather than as garbled messages later on.
+        pub fn check_interface_version(__ipc_handle: Handle) -> crate::os_error::OSResult<()> {
+            let mut request_msg = unsafe { crate::ipc::message::IPCMessage::new(&mut *core::ptr::addr_of_mut!(IPC_BUFFER)) };
+            request_msg.write(INTERFACE_HASH);
+            request_msg.write_header_for(0);
+            request_msg.write_translates();
+
+            unsafe { crate::syscalls::ipc_request(__ipc_handle, &mut *core::ptr::addr_of_mut!(IPC_BUFFER))?; }
+
+            let mut reply_msg = unsafe { crate::ipc::message::IPCMessage::new(&mut *core::ptr::addr_of_mut!(IPC_BUFFER)) };
+            reply_msg.read_reply()
+        }
+
         #(#client_methods)*
 
-        #(#sub_client_methods)*
+        #(#sub_clients)*
     };
 
     fs::write(dest_path, client_impl.to_string()).unwrap();
@@ -0,0 +1,623 @@
+use crate::{Interface, MethodInfo, ServerConfig};
+use quote::quote;
+use std::collections::HashMap;
+use syn::{GenericArgument, Ident, PathArguments, Type};
+
+// Types with an IPCValue impl in libprocess. Anything else has to be listed in the file's `types`.
+const BUILTIN_TYPES: &[&str] = &[
+    "u8",
+    "u16",
+    "u32",
+    "u64",
+    "usize",
+    "bool",
+    "String",
+    "ResultCode",
+    "OSError",
+    "TranslateMoveHandle",
+    "TranslateCopyHandle",
+];
+
+// Types that wrap exactly one other type.
+const WRAPPER_TYPES: &[&str] = &["OSResult", "Option", "Vec"];
+
+// Method 0 is the version check made when a session is opened, and the header only has 8 bits
+// for the id.
+const MIN_METHOD_ID: u32 = 1;
+const MAX_METHOD_ID: u32 = 255;
+
+// Returns every problem with the file, rather than just the first.
+pub fn validate(spec: &ServerConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    for (what, name) in [("name", &spec.name), ("struct_name", &spec.struct_name)] {
+        if syn::parse_str::<Ident>(name).is_err() {
+            errors.push(format!("{} {:?} isn't a valid identifier", what, name));
+        }
+    }
+    if syn::parse_str::<syn::Path>(&spec.handle_accessor).is_err() {
+        errors.push(format!(
+            "handle_accessor {:?} isn't a valid path",
+            spec.handle_accessor
+        ));
+    }
+
+    for name in &spec.types {
+        if syn::parse_str::<Ident>(name).is_err() {
+            errors.push(format!("type {:?} isn't a valid identifier", name));
+        }
+    }
+
+    let mut session_names: HashMap<&str, usize> = HashMap::new();
+    for interface in spec.interfaces() {
+        *session_names.entry(&interface.session_name).or_default() += 1;
+    }
+    for (name, count) in &session_names {
+        if *count > 1 {
+            errors.push(format!("session {} is defined {} times", name, count));
+        }
+    }
+
+    for interface in spec.interfaces() {
+        validate_interface(spec, interface, &mut errors);
+    }
+
+    errors
+}
+
+fn validate_interface(spec: &ServerConfig, interface: &Interface, errors: &mut Vec<String>) {
+    let session = &interface.session_name;
+    if syn::parse_str::<Ident>(session).is_err() {
+        errors.push(format!("session {:?} isn't a valid identifier", session));
+    }
+
+    let mut ids: HashMap<u32, &str> = HashMap::new();
+    let mut names: HashMap<&str, u32> = HashMap::new();
+    for method in &interface.methods {
+        let mut error = |message: String| {
+            errors.push(format!("{}.{}: {}", session, method.name, message));
+        };
+
+        if syn::parse_str::<Ident>(&method.name).is_err() {
+            error("isn't a valid identifier".to_string());
+        }
+
+        if method.id < MIN_METHOD_ID || method.id > MAX_METHOD_ID {
+            error(format!(
+                "id {} is out of range, ids go from {} to {}",
+                method.id, MIN_METHOD_ID, MAX_METHOD_ID
+            ));
+        }
+        if let Some(other) = ids.insert(method.id, &method.name) {
+            error(format!("id {} is already used by {}", method.id, other));
+        }
+        if names.insert(&method.name, method.id).is_some() {
+            error("is defined more than once".to_string());
+        }
+
+        validate_method(spec, method, &mut error);
+    }
+}
+
+fn validate_method(spec: &ServerConfig, method: &MethodInfo, error: &mut impl FnMut(String)) {
+    let mut input_names: HashMap<&str, usize> = HashMap::new();
+    let mut takes_buffers = false;
+    for input in &method.inputs {
+        if syn::parse_str::<Ident>(&input.name).is_err() {
+            error(format!("input {:?} isn't a valid identifier", input.name));
+        }
+        *input_names.entry(&input.name).or_default() += 1;
+
+        if input.out.unwrap_or(false)
+            && !syn::parse_str::<Type>(&input.ty).map_or(false, |ty| is_mut_buffer(&ty))
+        {
+            error(format!("input {}: only &mut [u8] can be out", input.name));
+        }
+
+        match syn::parse_str::<Type>(&input.ty) {
+            Ok(ty) if is_buffer(&ty) => takes_buffers = true,
+            Ok(ty) => check_type(spec, &ty, &mut |message| {
+                error(format!("input {}: {}", input.name, message))
+            }),
+            Err(_) => error(format!(
+                "input {}: {:?} isn't a valid type",
+                input.name, input.ty
+            )),
+        }
+    }
+    for (name, count) in input_names {
+        if count > 1 {
+            error(format!("input {} is given {} times", name, count));
+        }
+    }
+
+    let output = match syn::parse_str::<Type>(&method.output) {
+        Ok(output) => output,
+        Err(_) => {
+            error(format!("output {:?} isn't a valid type", method.output));
+            return;
+        }
+    };
+    if is_buffer(&output) {
+        error("buffers can only be inputs".to_string());
+    } else {
+        check_type(spec, &output, &mut |message| {
+            error(format!("output: {}", message))
+        });
+    }
+
+    if method.is_oneway.unwrap_or(false) {
+        if !matches!(&output, Type::Tuple(x) if x.elems.is_empty()) {
+            error("one-way methods can't return anything".to_string());
+        }
+        if takes_buffers {
+            error("one-way methods can't take buffers".to_string());
+        }
+    }
+
+    if let Some(session) = &method.session {
+        if !spec
+            .sub_interfaces
+            .iter()
+            .any(|x| &x.session_name == session)
+        {
+            error(format!("session {} isn't a sub-interface", session));
+        }
+
+        let output = method.output.replace(' ', "");
+        if output != "TranslateMoveHandle" && output != "OSResult<TranslateMoveHandle>" {
+            error("returns a session, so it must return a TranslateMoveHandle".to_string());
+        }
+    }
+}
+
+// Byte slices are passed as buffers rather than as values.
+fn is_buffer(ty: &Type) -> bool {
+    if let Type::Reference(reference) = ty
+        && let Type::Slice(slice) = &*reference.elem
+        && let Type::Path(elem) = &*slice.elem
+    {
+        elem.path.is_ident("u8")
+    } else {
+        false
+    }
+}
+
+// Only buffers the server can write to can be out.
+fn is_mut_buffer(ty: &Type) -> bool {
+    matches!(ty, Type::Reference(reference) if reference.mutability.is_some()) && is_buffer(ty)
+}
+
+// Checks that a type can go in a message.
+fn check_type(spec: &ServerConfig, ty: &Type, error: &mut impl FnMut(String)) {
+    match ty {
+        Type::Tuple(tuple) => {
+            if tuple.elems.len() > 4 {
+                error(format!(
+                    "{} has more than 4 elements",
+                    quote!(#tuple).to_string()
+                ));
+            }
+            for elem in &tuple.elems {
+                check_type(spec, elem, error);
+            }
+        }
+        Type::Array(array) => check_type(spec, &array.elem, error),
+        Type::Paren(paren) => check_type(spec, &paren.elem, error),
+        Type::Path(path) if path.qself.is_none() && path.path.segments.len() == 1 => {
+            let segment = &path.path.segments[0];
+            let name = segment.ident.to_string();
+
+            if WRAPPER_TYPES.contains(&name.as_str()) {
+                match &segment.arguments {
+                    PathArguments::AngleBracketed(args) if args.args.len() == 1 => {
+                        match &args.args[0] {
+                            GenericArgument::Type(inner) if is_buffer(inner) => {
+                                error(format!("{} can't contain a buffer", name))
+                            }
+                            GenericArgument::Type(inner) => check_type(spec, inner, error),
+                            _ => error(format!("{} takes one type", name)),
+                        }
+                    }
+                    _ => error(format!("{} takes one type", name)),
+                }
+            } else if !BUILTIN_TYPES.contains(&name.as_str()) && !spec.types.contains(&name) {
+                error(format!(
+                    "unknown type {}, declare it in types if it's defined elsewhere",
+                    name
+                ));
+            } else if !segment.arguments.is_empty() {
+                error(format!("{} doesn't take type arguments", name));
+            }
+        }
+        _ => error(format!(
+            "{} can't be sent over IPC",
+            quote!(#ty).to_string()
+        )),
+    }
+}
+
+// FNV-1a over everything that affects what goes over the wire, plus the file's version for
+// changes in meaning that don't.
+pub fn interface_hash(spec: &ServerConfig) -> u64 {
+    let mut description = format!("{}:{}", spec.name, spec.version.unwrap_or(0));
+    for interface in spec.interfaces() {
+        description += &format!(";{}", interface.session_name);
+
+        let mut methods: Vec<_> = interface.methods.iter().collect();
+        methods.sort_by_key(|x| x.id);
+        for method in methods {
+            let inputs: Vec<_> = method
+                .inputs
+                .iter()
+                .map(|x| {
+                    let out = if x.out.unwrap_or(false) { " out" } else { "" };
+                    normalize_type(&x.ty) + out
+                })
+                .collect();
+            description += &format!(
+                ";{}({})->{}{}",
+                method.id,
+                inputs.join(","),
+                normalize_type(&method.output),
+                if method.is_oneway.unwrap_or(false) {
+                    " oneway"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in description.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// So that spacing in the TOML doesn't change the hash.
+fn normalize_type(ty: &str) -> String {
+    match syn::parse_str::<Type>(ty) {
+        Ok(ty) => quote!(#ty).to_string(),
+        Err(_) => ty.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = r#"
+        name = "test"
+        struct_name = "TestServer"
+        handle_accessor = "crate::ipc::test::get_handle_for_test"
+        types = ["Custom"]
+
+        [main_interface]
+        session_name = "TestSession"
+    "#;
+
+    fn parse(methods: &str) -> ServerConfig {
+        toml::from_str(&format!("{}{}", HEADER, methods)).unwrap()
+    }
+
+    fn errors_for(methods: &str) -> Vec<String> {
+        validate(&parse(methods))
+    }
+
+    fn assert_one_error(errors: Vec<String>, expected: &str) {
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains(expected), "{:?}", errors);
+    }
+
+    #[test]
+    fn valid_spec() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "open"
+            id = 1
+            inputs = [{ name = "path", ty = "String" }, { name = "extra", ty = "Option<Custom>" }]
+            output = "OSResult<TranslateMoveHandle>"
+            session = "IFileSession"
+
+            [[main_interface.methods]]
+            name = "notify"
+            id = 2
+            inputs = [{ name = "values", ty = "(u32, [u8; 4])" }]
+            output = "()"
+            is_oneway = true
+
+            [[sub_interfaces]]
+            session_name = "IFileSession"
+
+            [[sub_interfaces.methods]]
+            name = "read"
+            id = 1
+            inputs = [{ name = "buffer", ty = "&mut [u8]", out = true }]
+            output = "OSResult<usize>"
+
+            [[sub_interfaces.methods]]
+            name = "write"
+            id = 2
+            inputs = [{ name = "buffer", ty = "&[u8]" }]
+            output = "OSResult<usize>"
+            "#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn repo_definitions_are_valid() {
+        for name in ["fs", "pcie", "sm"] {
+            let path = format!("../ipc_definitions/{}.toml", name);
+            if let Err(errors) = crate::load_spec(&path) {
+                panic!("{}: {:?}", path, errors);
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_ids() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "first"
+            id = 3
+            inputs = []
+            output = "()"
+
+            [[main_interface.methods]]
+            name = "second"
+            id = 3
+            inputs = []
+            output = "()"
+            "#,
+        );
+        assert_one_error(errors, "TestSession.second: id 3 is already used by first");
+    }
+
+    #[test]
+    fn same_id_in_different_sessions() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "first"
+            id = 1
+            inputs = []
+            output = "()"
+
+            [[sub_interfaces]]
+            session_name = "OtherSession"
+
+            [[sub_interfaces.methods]]
+            name = "first"
+            id = 1
+            inputs = []
+            output = "()"
+            "#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = []
+            output = "()"
+
+            [[main_interface.methods]]
+            name = "method"
+            id = 2
+            inputs = []
+            output = "()"
+            "#,
+        );
+        assert_one_error(errors, "TestSession.method: is defined more than once");
+    }
+
+    #[test]
+    fn out_of_range_ids() {
+        for id in [0, 256] {
+            let errors = errors_for(&format!(
+                r#"
+                [[main_interface.methods]]
+                name = "method"
+                id = {}
+                inputs = []
+                output = "()"
+                "#,
+                id
+            ));
+            assert_one_error(errors, &format!("id {} is out of range", id));
+        }
+
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 255
+            inputs = []
+            output = "()"
+            "#,
+        );
+        assert!(errors.is_empty(), "{:?}", errors);
+    }
+
+    #[test]
+    fn one_way_returns_nothing() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = []
+            output = "OSResult<()>"
+            is_oneway = true
+            "#,
+        );
+        assert_one_error(errors, "one-way methods can't return anything");
+    }
+
+    #[test]
+    fn one_way_takes_no_buffers() {
+        for ty in ["&[u8]", "&mut [u8]"] {
+            let errors = errors_for(&format!(
+                r#"
+                [[main_interface.methods]]
+                name = "method"
+                id = 1
+                inputs = [{{ name = "buffer", ty = "{}" }}]
+                output = "()"
+                is_oneway = true
+                "#,
+                ty
+            ));
+            assert_one_error(errors, "one-way methods can't take buffers");
+        }
+    }
+
+    #[test]
+    fn duplicate_inputs() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = [{ name = "value", ty = "u32" }, { name = "value", ty = "u64" }]
+            output = "()"
+            "#,
+        );
+        assert_one_error(errors, "input value is given 2 times");
+    }
+
+    #[test]
+    fn out_only_on_mutable_buffers() {
+        for ty in ["&[u8]", "u32"] {
+            let errors = errors_for(&format!(
+                r#"
+                [[main_interface.methods]]
+                name = "method"
+                id = 1
+                inputs = [{{ name = "value", ty = "{}", out = true }}]
+                output = "()"
+                "#,
+                ty
+            ));
+            assert_one_error(errors, "input value: only &mut [u8] can be out");
+        }
+    }
+
+    #[test]
+    fn unknown_types() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = [{ name = "value", ty = "Unknown" }]
+            output = "Vec<AlsoUnknown>"
+            "#,
+        );
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("input value: unknown type Unknown"));
+        assert!(errors[1].contains("output: unknown type AlsoUnknown"));
+    }
+
+    #[test]
+    fn buffers_only_as_inputs() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = []
+            output = "&[u8]"
+            "#,
+        );
+        assert_one_error(errors, "buffers can only be inputs");
+
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = [{ name = "value", ty = "Option<&[u8]>" }]
+            output = "()"
+            "#,
+        );
+        assert_one_error(errors, "Option can't contain a buffer");
+    }
+
+    #[test]
+    fn sessions() {
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = []
+            output = "OSResult<TranslateMoveHandle>"
+            session = "MissingSession"
+            "#,
+        );
+        assert_one_error(errors, "session MissingSession isn't a sub-interface");
+
+        let errors = errors_for(
+            r#"
+            [[main_interface.methods]]
+            name = "method"
+            id = 1
+            inputs = []
+            output = "u32"
+            session = "IFileSession"
+
+            [[sub_interfaces]]
+            session_name = "IFileSession"
+            methods = []
+            "#,
+        );
+        assert_one_error(errors, "must return a TranslateMoveHandle");
+
+        let errors = errors_for(
+            r#"
+            methods = []
+
+            [[sub_interfaces]]
+            session_name = "TestSession"
+            methods = []
+            "#,
+        );
+        assert_one_error(errors, "session TestSession is defined 2 times");
+    }
+
+    #[test]
+    fn hash_ignores_spacing() {
+        let method = |ty: &str| {
+            parse(&format!(
+                r#"
+                [[main_interface.methods]]
+                name = "method"
+                id = 1
+                inputs = [{{ name = "buffer", ty = "{}" }}]
+                output = "()"
+                "#,
+                ty
+            ))
+        };
+        assert_eq!(
+            interface_hash(&method("&mut [u8]")),
+            interface_hash(&method("&mut[u8]"))
+        );
+        assert_ne!(
+            interface_hash(&method("&mut [u8]")),
+            interface_hash(&method("&[u8]"))
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+// create_event modes
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive, IntoPrimitive)]
+pub enum EventMode {
+    // Signalling wakes one waiter, or latches until the next wait consumes it.
+    AutoReset = 0,
+    // Signalling wakes every waiter, and stays signalled until cleared.
+    ManualReset = 1,
+}
@@ -0,0 +1,46 @@
+use crate::name::name_str;
+use crate::Handle;
+
+// Processes allowed to look at the rest of the system (list_threads, the IPC trace) are granted a
+// capability with the INTROSPECT right when they're loaded. It goes in first, so it's always the
+// first handle a process has.
+pub const INTROSPECTION_HANDLE: Handle = Handle(0x0001_0000);
+
+pub const THREAD_INFO_NAME_LEN: usize = 16;
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ThreadInfoState {
+    #[default]
+    Created = 0,
+    // Either running, or waiting for a turn on a cpu.
+    Runnable = 1,
+    // Blocked in a syscall, see last_svc_number.
+    Suspended = 2,
+}
+
+// One thread, and the process it belongs to, as seen by list_threads.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ThreadInfo {
+    pub thread_id: u64,
+    pub process_id: u64,
+    pub process_name: [u8; THREAD_INFO_NAME_LEN],
+    // Total time spent on a cpu, up to the last time the thread was switched to or away from.
+    pub cpu_time_ns: u64,
+    pub state: ThreadInfoState,
+    // The last syscall the thread made. For a suspended thread, the one it is blocked in.
+    pub last_svc_number: u32,
+    // Open handles in the whole process.
+    pub handle_count: u32,
+    // Idle threads are per cpu, and belong to the kernel's "idle" process.
+    pub is_idle: bool,
+    // Fills what would otherwise be padding, which the kernel would copy out uninitialized.
+    pub _reserved: [u8; 3],
+}
+
+impl ThreadInfo {
+    pub fn process_name(&self) -> &str {
+        name_str(&self.process_name)
+    }
+}
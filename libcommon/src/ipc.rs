@@ -3,6 +3,9 @@ use crate::Handle;
 pub const MAX_TRANSLATE: usize = 4;
 pub const TRANSLATE_TYPE_MOVE_HANDLE: u64 = 1;
 pub const TRANSLATE_TYPE_COPY_HANDLE: u64 = 2;
+pub const TRANSLATE_TYPE_SEND_BUFFER: u64 = 3;
+pub const TRANSLATE_TYPE_RECEIVE_BUFFER: u64 = 4;
+pub const TRANSLATE_TYPE_EXCHANGE_BUFFER: u64 = 5;
 
 #[derive(Debug)]
 pub struct IPCHeader {
@@ -19,47 +22,115 @@ pub struct TranslateCopyHandle(pub Handle);
 #[derive(Debug)]
 pub struct TranslateMoveHandle(pub Handle);
 
+// Identifies one received request, for replying to it and accessing its buffers.
+// The server session handle is in the low 32 bits, and a per-session token in the high 32 bits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct ReplyToken(pub u64);
+
+impl ReplyToken {
+    // Token 0 never names a request, so ipc_reply_and_receive skips the reply.
+    pub const NONE: ReplyToken = ReplyToken(0);
+
+    pub const fn new(session: Handle, token: u32) -> ReplyToken {
+        ReplyToken(((token as u64) << 32) | session.0 as u64)
+    }
+
+    pub const fn session(&self) -> Handle {
+        Handle(self.0 as u32)
+    }
+
+    pub const fn token(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+}
+
+// Why an ipc_receive returned.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ReceiveResult {
+    // handles[index] is a server session, and its request is in the IPC buffer.
+    // The request stays in flight until it is replied to with the token.
+    Request(usize, ReplyToken),
+    // handles[index] was signalled. If it's a session, its one-way message is in the IPC buffer.
+    Signalled(usize),
+    // handles[index] is a session whose other end has gone away.
+    SessionClosed(usize),
+}
+
+// The largest buffer a message can carry. Servers copy buffers in whole, so this bounds how much a
+// client can make them allocate.
+pub const MAX_BUFFER_SIZE: usize = 1 << 20;
+
+// A range of the client's memory. The server never sees the address, only the size, and reads or
+// writes the contents through the kernel.
+#[derive(Copy, Clone, Debug)]
+pub struct BufferDescriptor {
+    pub address: usize,
+    pub size: usize,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum TranslateEntry {
     None,
     MoveHandle(Handle),
     CopyHandle(Handle),
-    MemoryStatic(),
-    MemoryMap(),
+    // The server can read from this buffer.
+    SendBuffer(BufferDescriptor),
+    // The server can write to this buffer.
+    ReceiveBuffer(BufferDescriptor),
+    // The server can read from and write to this buffer.
+    ExchangeBuffer(BufferDescriptor),
 }
 
 impl TranslateEntry {
-    pub fn read(buffer: &[u8; 16]) -> TranslateEntry {
+    // Buffer entries keep their size in the top 56 bits of the type field.
+    pub fn read(buffer: &[u8; 16]) -> Option<TranslateEntry> {
         let translate_type = u64::from_le_bytes(buffer[0..8].try_into().unwrap());
         let translate_payload = u64::from_le_bytes(buffer[8..16].try_into().unwrap());
 
-        match translate_type {
+        let buffer_descriptor = BufferDescriptor {
+            address: translate_payload as usize,
+            size: (translate_type >> 8) as usize,
+        };
+
+        match translate_type & 0xff {
+            0 => Some(TranslateEntry::None),
             TRANSLATE_TYPE_MOVE_HANDLE => {
-                TranslateEntry::MoveHandle(Handle(translate_payload as u32))
+                Some(TranslateEntry::MoveHandle(Handle(translate_payload as u32)))
             }
             TRANSLATE_TYPE_COPY_HANDLE => {
-                TranslateEntry::CopyHandle(Handle(translate_payload as u32))
+                Some(TranslateEntry::CopyHandle(Handle(translate_payload as u32)))
             }
-            _ => {
-                unimplemented!();
+            TRANSLATE_TYPE_SEND_BUFFER => Some(TranslateEntry::SendBuffer(buffer_descriptor)),
+            TRANSLATE_TYPE_RECEIVE_BUFFER => Some(TranslateEntry::ReceiveBuffer(buffer_descriptor)),
+            TRANSLATE_TYPE_EXCHANGE_BUFFER => {
+                Some(TranslateEntry::ExchangeBuffer(buffer_descriptor))
             }
+            _ => None,
         }
     }
 
     pub fn write(buffer: &mut [u8], entry: TranslateEntry) {
-        match entry {
-            TranslateEntry::MoveHandle(handle) => {
-                buffer[0..8].copy_from_slice(&u64::to_le_bytes(TRANSLATE_TYPE_MOVE_HANDLE));
-                buffer[8..16].copy_from_slice(&u64::to_le_bytes(handle.0 as u64));
-            }
-            TranslateEntry::CopyHandle(handle) => {
-                buffer[0..8].copy_from_slice(&u64::to_le_bytes(TRANSLATE_TYPE_COPY_HANDLE));
-                buffer[8..16].copy_from_slice(&u64::to_le_bytes(handle.0 as u64));
-            }
-            _ => {
-                unimplemented!();
-            }
-        }
+        let (translate_type, translate_payload) = match entry {
+            TranslateEntry::None => (0, 0),
+            TranslateEntry::MoveHandle(handle) => (TRANSLATE_TYPE_MOVE_HANDLE, handle.0 as u64),
+            TranslateEntry::CopyHandle(handle) => (TRANSLATE_TYPE_COPY_HANDLE, handle.0 as u64),
+            TranslateEntry::SendBuffer(desc) => (
+                TRANSLATE_TYPE_SEND_BUFFER | ((desc.size as u64) << 8),
+                desc.address as u64,
+            ),
+            TranslateEntry::ReceiveBuffer(desc) => (
+                TRANSLATE_TYPE_RECEIVE_BUFFER | ((desc.size as u64) << 8),
+                desc.address as u64,
+            ),
+            TranslateEntry::ExchangeBuffer(desc) => (
+                TRANSLATE_TYPE_EXCHANGE_BUFFER | ((desc.size as u64) << 8),
+                desc.address as u64,
+            ),
+        };
+
+        buffer[0..8].copy_from_slice(&u64::to_le_bytes(translate_type));
+        buffer[8..16].copy_from_slice(&u64::to_le_bytes(translate_payload));
     }
 }
 
@@ -75,17 +146,20 @@ impl IPCHeader {
         packed
     }
 
-    pub fn unpack(packed: u32) -> IPCHeader {
+    // Returns None if the magic is wrong.
+    pub fn unpack(packed: u32) -> Option<IPCHeader> {
         let message_id = packed & 0xff;
         let message_size = (packed & (0xff << 8)) >> 8;
         let message_translate_count = (packed & (0xff << 16)) >> 16;
 
-        assert!((packed & (0xff << 24)) >> 24 == 0xaa);
+        if (packed & (0xff << 24)) >> 24 != 0xaa {
+            return None;
+        }
 
-        IPCHeader {
+        Some(IPCHeader {
             id: message_id,
             size: message_size as usize,
             translate_count: message_translate_count as usize,
-        }
+        })
     }
 }
@@ -0,0 +1,91 @@
+use crate::ipc::MAX_TRANSLATE;
+use crate::name::name_str;
+use crate::os_error::{ResultCode, RESULT_OK};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+// ipc_trace_control operations
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq, TryFromPrimitive, IntoPrimitive)]
+pub enum IPCTraceControl {
+    Disable = 0,
+    Enable = 1,
+    // Throw away every record that hasn't been read yet.
+    Clear = 2,
+}
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum IPCTraceKind {
+    #[default]
+    Request = 0,
+    Reply = 1,
+    OneWayToServer = 2,
+    OneWayToClient = 3,
+}
+
+pub const IPC_TRACE_NAME_LEN: usize = 16;
+
+// Something that went along with a message in a translate entry.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IPCTraceTranslate {
+    // One of the TRANSLATE_TYPE_* values, or 0 for none.
+    pub ty: u64,
+    pub buffer_size: u64,
+    // The session that a handle is for, or 0 if it isn't one.
+    pub session_id: u64,
+}
+
+// One message, as seen by the kernel when it was delivered (or failed to be).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IPCTraceRecord {
+    // Counts up for every record, so gaps mean records were overwritten before they were read.
+    pub sequence: u64,
+    pub timestamp_ns: u64,
+    // How long a request or one-way message was queued for, or how long a reply took from the
+    // request being sent.
+    pub latency_ns: u64,
+    // Both ends of a session have the same id.
+    pub session_id: u64,
+    pub sender_pid: u64,
+    pub receiver_pid: u64,
+    pub sender_name: [u8; IPC_TRACE_NAME_LEN],
+    pub receiver_name: [u8; IPC_TRACE_NAME_LEN],
+    pub kind: IPCTraceKind,
+    pub result: u32,
+    // Replies have the id of the request they are for.
+    pub method_id: u32,
+    // Includes the header, but not the translate entries.
+    pub size: u32,
+    pub translate_count: u32,
+    // Fills what would otherwise be padding, which the kernel would copy out uninitialized.
+    pub _reserved: u32,
+    pub translates: [IPCTraceTranslate; MAX_TRANSLATE],
+}
+
+impl IPCTraceRecord {
+    pub fn sender_name(&self) -> &str {
+        name_str(&self.sender_name)
+    }
+
+    pub fn receiver_name(&self) -> &str {
+        name_str(&self.receiver_name)
+    }
+
+    // The process on the server end of the session.
+    pub fn server_name(&self) -> &str {
+        match self.kind {
+            IPCTraceKind::Request | IPCTraceKind::OneWayToServer => self.receiver_name(),
+            IPCTraceKind::Reply | IPCTraceKind::OneWayToClient => self.sender_name(),
+        }
+    }
+
+    pub fn result(&self) -> ResultCode {
+        ResultCode(self.result)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.result() == RESULT_OK
+    }
+}
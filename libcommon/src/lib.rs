@@ -1,7 +1,11 @@
 #![no_std]
 pub mod constants;
+pub mod event;
 pub mod handle;
+pub mod introspection;
 pub mod ipc;
+pub mod ipc_trace;
+pub mod name;
 pub mod os_error;
 pub mod system_info;
 pub use handle::*;
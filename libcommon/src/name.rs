@@ -0,0 +1,18 @@
+// Fixed size, NUL padded names, for structs that get copied between the kernel and userspace.
+
+// Names are cut off at the length of the array, and padded with zeroes.
+pub fn pack_name<const N: usize>(name: &str) -> [u8; N] {
+    let mut packed = [0; N];
+    let len = name.len().min(N);
+    packed[..len].copy_from_slice(&name.as_bytes()[..len]);
+    packed
+}
+
+pub fn name_str(name: &[u8]) -> &str {
+    let len = name.iter().position(|x| *x == 0).unwrap_or(name.len());
+    match core::str::from_utf8(&name[..len]) {
+        Ok(name) => name,
+        // Cut off in the middle of a character.
+        Err(err) => core::str::from_utf8(&name[..err.valid_up_to()]).unwrap(),
+    }
+}
@@ -1,6 +1,6 @@
 use num_enum::TryFromPrimitive;
 
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(transparent)]
 pub struct ResultCode(pub u32);
 pub const RESULT_OK: ResultCode = ResultCode(0);
@@ -32,6 +32,15 @@ pub enum Reason {
     InvalidHandle = 3,
     NotFound = 4,
     TryAgain = 5,
+    TimedOut = 6,
+    InvalidPointer = 7,
+    InvalidArgument = 8,
+    ResourceExhausted = 9,
+    SessionClosed = 10,
+    PortFull = 11,
+    InvalidMessage = 12,
+    VersionMismatch = 13,
+    AlreadyExists = 14,
     Unknown = 0xffff,
 }
 
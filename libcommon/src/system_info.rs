@@ -1,9 +1,11 @@
+use crate::name::{name_str, pack_name};
 use core::fmt;
 use francium_common::types::FramebufferInfo;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-#[repr(C)]
-#[derive(Debug)]
+// usize sized, so MemoryRegion has no padding to leak when the kernel copies it out.
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MemoryRegionType {
     None,
     Bootloader,
@@ -11,7 +13,7 @@ pub enum MemoryRegionType {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct MemoryRegion {
     pub start: usize,
     pub length: usize,
@@ -25,6 +27,47 @@ pub enum SystemInfoType {
     MemoryRegion = 0,
     Platform = 1,
     FramebufferInfo = 2,
+    AcpiRsdpAddress = 3,
+    DeviceTreeAddress = 4,
+    CpuCount = 5,
+    PhysicalMemory = 6,
+    KernelVersion = 7,
+    Uptime = 8,
+}
+
+// In bytes.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PhysicalMemory {
+    pub total: usize,
+    pub free: usize,
+}
+
+pub const KERNEL_VERSION_LEN: usize = 64;
+
+// NUL padded, like process names in IPC traces.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct KernelVersion {
+    pub version: [u8; KERNEL_VERSION_LEN],
+}
+
+impl KernelVersion {
+    pub fn new(version: &str) -> KernelVersion {
+        KernelVersion {
+            version: pack_name(version),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        name_str(&self.version)
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[repr(C)]
@@ -53,4 +96,12 @@ pub enum SystemInfo {
     MemoryRegion(MemoryRegion),
     Platform(Platform),
     FramebufferInfo(FramebufferInfo),
+    // Physical addresses of the firmware's tables. NotFound on platforms that don't have them.
+    AcpiRsdpAddress(usize),
+    DeviceTreeAddress(usize),
+    CpuCount(usize),
+    PhysicalMemory(PhysicalMemory),
+    KernelVersion(KernelVersion),
+    // Nanoseconds since the system timer started counting, which is close enough to boot.
+    Uptime(u64),
 }
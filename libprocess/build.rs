@@ -1,5 +1,7 @@
 use ipc_gen_buildtime::generate_client;
+use syscall_gen_buildtime::generate_user;
 fn main() {
+    generate_user("../syscalls.toml");
     generate_client("../ipc_definitions/sm.toml");
     generate_client("../ipc_definitions/fs.toml");
     generate_client("../ipc_definitions/pcie.toml");
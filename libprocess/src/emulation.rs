@@ -0,0 +1,1235 @@
+// A stand-in for the kernel on the host, so that servers and their clients can run as threads of
+// one ordinary process (eg. under cargo test). Objects follow the kernel's rules, but everything
+// lives behind one lock, and any change wakes every waiter to check again.
+// Dropping the last reference to a session or port changes what its peers see, so object
+// references must only ever be dropped with the lock held.
+
+use common::constants::TIMEOUT_INFINITE;
+use common::event::EventMode;
+use common::ipc::*;
+use common::os_error::{Module, Reason, ResultCode, RESULT_OK};
+use common::{Handle, HandleRights, INVALID_HANDLE};
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Condvar, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+const IPC_BUFFER_LEN: usize = 128;
+const MAX_HANDLES: usize = 128;
+const MAX_ONEWAY_MESSAGES: usize = 64;
+const INTERRUPT_COUNT: usize = 128;
+// How many connections can be waiting to be accepted before connecting fails with PortFull.
+const PORT_BACKLOG: usize = 32;
+
+#[derive(Clone)]
+enum Object {
+    Port(Arc<Port>),
+    ServerSession(Arc<ServerSession>),
+    ClientSession(Arc<ClientSession>),
+    Event(Arc<Event>),
+    Timer(Arc<Timer>),
+    Semaphore(Arc<Semaphore>),
+    IPCRequest(Arc<PendingRequest>),
+}
+
+// A request that is waiting for a reply. Addresses are the client's, same as in the kernel.
+struct PendingRequest {
+    buffer_ptr: usize,
+    buffers: Mutex<[TranslateEntry; MAX_TRANSLATE]>,
+    result: Mutex<Option<ResultCode>>,
+    event: Option<Arc<Event>>,
+}
+
+impl PendingRequest {
+    fn new(buffer_ptr: usize, event: Option<Arc<Event>>) -> PendingRequest {
+        PendingRequest {
+            buffer_ptr: buffer_ptr,
+            buffers: Mutex::new([TranslateEntry::None; MAX_TRANSLATE]),
+            result: Mutex::new(None),
+            event: event,
+        }
+    }
+
+    fn complete(&self, res: ResultCode) {
+        let mut result = self.result.lock();
+        if result.is_none() {
+            *result = Some(res);
+            if let Some(event) = &self.event {
+                event.signal();
+            }
+        }
+    }
+}
+
+struct OneWayMessage {
+    buffer: [u8; IPC_BUFFER_LEN],
+    handles: [Option<(Object, HandleRights)>; MAX_TRANSLATE],
+}
+
+fn queue_oneway(
+    queue: &Mutex<VecDeque<OneWayMessage>>,
+    message: OneWayMessage,
+) -> Result<(), ResultCode> {
+    let mut queue = queue.lock();
+    if queue.len() >= MAX_ONEWAY_MESSAGES {
+        return Err(ResultCode::new(Module::Kernel, Reason::TryAgain));
+    }
+    queue.push_back(message);
+    Ok(())
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ConnectState {
+    Waiting,
+    Accepted,
+    Refused,
+}
+
+struct ServerSession {
+    connect: Mutex<ConnectState>,
+    queue: Mutex<VecDeque<Arc<PendingRequest>>>,
+    client: Mutex<Weak<ClientSession>>,
+    in_flight: Mutex<BTreeMap<u32, Arc<PendingRequest>>>,
+    next_token: AtomicU32,
+    oneway: Mutex<VecDeque<OneWayMessage>>,
+}
+
+impl ServerSession {
+    fn new() -> ServerSession {
+        ServerSession {
+            connect: Mutex::new(ConnectState::Waiting),
+            queue: Mutex::new(VecDeque::new()),
+            client: Mutex::new(Weak::new()),
+            in_flight: Mutex::new(BTreeMap::new()),
+            next_token: AtomicU32::new(1),
+            oneway: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn is_peer_closed(&self) -> bool {
+        self.client.lock().upgrade().is_none()
+    }
+
+    // Token 0 is never handed out, it means "no request".
+    fn add_in_flight(&self, request: Arc<PendingRequest>) -> u32 {
+        let mut in_flight = self.in_flight.lock();
+        loop {
+            let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+            if token != 0 && !in_flight.contains_key(&token) {
+                in_flight.insert(token, request);
+                return token;
+            }
+        }
+    }
+}
+
+impl Drop for ServerSession {
+    fn drop(&mut self) {
+        // Nobody is going to reply to these now.
+        let closed = ResultCode::new(Module::Kernel, Reason::SessionClosed);
+        for request in self.queue.get_mut().drain(..) {
+            request.complete(closed);
+        }
+        for (_, request) in core::mem::take(self.in_flight.get_mut()) {
+            request.complete(closed);
+        }
+        wake_all();
+    }
+}
+
+struct ClientSession {
+    server: Weak<ServerSession>,
+    oneway: Mutex<VecDeque<OneWayMessage>>,
+}
+
+impl ClientSession {
+    fn new(server: Weak<ServerSession>) -> ClientSession {
+        ClientSession {
+            server: server,
+            oneway: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn is_peer_closed(&self) -> bool {
+        self.server.upgrade().is_none()
+    }
+}
+
+impl Drop for ClientSession {
+    fn drop(&mut self) {
+        // The server finds out through its weak reference, once it looks.
+        wake_all();
+    }
+}
+
+struct Port {
+    queue: Mutex<VecDeque<Arc<ServerSession>>>,
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        // Nobody is going to accept these any more.
+        for server_session in self.queue.get_mut().drain(..) {
+            *server_session.connect.lock() = ConnectState::Refused;
+        }
+        wake_all();
+    }
+}
+
+struct Event {
+    mode: EventMode,
+    signalled: Mutex<bool>,
+}
+
+impl Event {
+    fn signal(&self) {
+        *self.signalled.lock() = true;
+    }
+}
+
+struct TimerState {
+    deadline: Option<Instant>,
+    period_ns: u64,
+    pending: bool,
+}
+
+// Timers fire lazily, whenever somebody waiting on them checks. Waiters make sure to check again at
+// the deadline.
+struct Timer {
+    state: Mutex<TimerState>,
+}
+
+struct Semaphore {
+    count: Mutex<usize>,
+    max_count: usize,
+}
+
+impl Object {
+    // Whether a wait on this object is satisfied, consuming the signal like the kernel would.
+    // wake_at is brought forward if the object will become signalled on its own.
+    fn poll(&self, now: Instant, wake_at: &mut Option<Instant>) -> bool {
+        match self {
+            Object::Port(port) => !port.queue.lock().is_empty(),
+            // A closed session stays signalled, so the server can't miss it.
+            Object::ServerSession(server_session) => {
+                !server_session.queue.lock().is_empty()
+                    || !server_session.oneway.lock().is_empty()
+                    || server_session.is_peer_closed()
+            }
+            Object::ClientSession(client_session) => {
+                !client_session.oneway.lock().is_empty() || client_session.is_peer_closed()
+            }
+            Object::Event(event) => {
+                let mut signalled = event.signalled.lock();
+                if *signalled && event.mode == EventMode::AutoReset {
+                    *signalled = false;
+                    true
+                } else {
+                    *signalled
+                }
+            }
+            Object::Timer(timer) => {
+                let mut state = timer.state.lock();
+                if let Some(deadline) = state.deadline {
+                    if deadline <= now {
+                        state.pending = true;
+                        state.deadline = if state.period_ns != 0 {
+                            // Skip any periods we missed, they all latch into the one signal.
+                            let missed = (now - deadline).as_nanos() / state.period_ns as u128;
+                            let offset_ns = state.period_ns.saturating_mul(missed as u64 + 1);
+                            deadline.checked_add(Duration::from_nanos(offset_ns))
+                        } else {
+                            None
+                        };
+                    }
+                }
+
+                if state.pending {
+                    state.pending = false;
+                    true
+                } else {
+                    if let Some(deadline) = state.deadline {
+                        if wake_at.map_or(true, |x| deadline < x) {
+                            *wake_at = Some(deadline);
+                        }
+                    }
+                    false
+                }
+            }
+            Object::Semaphore(semaphore) => {
+                let mut count = semaphore.count.lock();
+                if *count > 0 {
+                    *count -= 1;
+                    true
+                } else {
+                    false
+                }
+            }
+            Object::IPCRequest(request) => request.result.lock().is_some(),
+        }
+    }
+}
+
+struct FutexWaiter {
+    key: usize,
+    id: u64,
+    woken: bool,
+}
+
+struct State {
+    handles: BTreeMap<u32, (Object, HandleRights)>,
+    next_handle: u32,
+    ports: BTreeMap<u64, Arc<Port>>,
+    futex_waiters: Vec<FutexWaiter>,
+    next_futex_id: u64,
+    // Nothing ever raises these, but drivers still get the kernel's rules for binding them.
+    interrupts: BTreeMap<usize, Arc<Event>>,
+}
+
+static STATE: std::sync::Mutex<State> = std::sync::Mutex::new(State {
+    handles: BTreeMap::new(),
+    next_handle: 1,
+    ports: BTreeMap::new(),
+    futex_waiters: Vec::new(),
+    next_futex_id: 0,
+    interrupts: BTreeMap::new(),
+});
+static CHANGED: Condvar = Condvar::new();
+
+type Guard = MutexGuard<'static, State>;
+
+fn lock() -> Guard {
+    STATE.lock().unwrap()
+}
+
+// Something changed that a waiter might be interested in.
+fn wake_all() {
+    CHANGED.notify_all();
+}
+
+fn deadline_after(timeout_ns: u64) -> Option<Instant> {
+    if timeout_ns == TIMEOUT_INFINITE {
+        None
+    } else {
+        Instant::now().checked_add(Duration::from_nanos(timeout_ns))
+    }
+}
+
+// Wait until check returns something, checking again every time anything changes. The lock is
+// handed back either way, so callers can clean up after a timeout without racing anybody.
+fn wait_until<T>(
+    mut state: Guard,
+    deadline: Option<Instant>,
+    mut check: impl FnMut(&mut State, Instant, &mut Option<Instant>) -> Option<T>,
+) -> (Guard, Result<T, ResultCode>) {
+    loop {
+        let now = Instant::now();
+        let mut wake_at = deadline;
+        if let Some(x) = check(&mut state, now, &mut wake_at) {
+            return (state, Ok(x));
+        }
+
+        if deadline.map_or(false, |deadline| deadline <= now) {
+            return (
+                state,
+                Err(ResultCode::new(Module::Kernel, Reason::TimedOut)),
+            );
+        }
+
+        state = match wake_at {
+            Some(wake_at) => {
+                CHANGED
+                    .wait_timeout(state, wake_at.saturating_duration_since(now))
+                    .unwrap()
+                    .0
+            }
+            None => CHANGED.wait(state).unwrap(),
+        };
+    }
+}
+
+impl State {
+    fn insert(&mut self, object: Object, rights: HandleRights) -> Handle {
+        loop {
+            let handle = self.next_handle;
+            self.next_handle = self.next_handle.wrapping_add(1);
+            if handle != INVALID_HANDLE.0 && !self.handles.contains_key(&handle) {
+                self.handles.insert(handle, (object, rights));
+                return Handle(handle);
+            }
+        }
+    }
+
+    fn get(&self, handle: Handle) -> Result<Object, ResultCode> {
+        match self.handles.get(&handle.0) {
+            Some((object, _)) => Ok(object.clone()),
+            None => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+        }
+    }
+
+    fn get_with_rights(
+        &self,
+        handle: Handle,
+        required: HandleRights,
+    ) -> Result<(Object, HandleRights), ResultCode> {
+        match self.handles.get(&handle.0) {
+            Some((object, rights)) if rights.contains(required) => Ok((object.clone(), *rights)),
+            Some(_) => Err(ResultCode::new(Module::Kernel, Reason::NotAllowed)),
+            None => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+        }
+    }
+
+    fn get_server_session(&self, handle: Handle) -> Result<Arc<ServerSession>, ResultCode> {
+        match self.get(handle)? {
+            Object::ServerSession(server_session) => Ok(server_session),
+            _ => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+        }
+    }
+
+    fn get_client_session(&self, handle: Handle) -> Result<Arc<ClientSession>, ResultCode> {
+        match self.get(handle)? {
+            Object::ClientSession(client_session) => Ok(client_session),
+            _ => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+        }
+    }
+
+    fn get_event(&self, handle: Handle, required: HandleRights) -> Result<Arc<Event>, ResultCode> {
+        match self.get_with_rights(handle, required)?.0 {
+            Object::Event(event) => Ok(event),
+            _ => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+        }
+    }
+
+    fn get_timer(&self, handle: Handle) -> Result<Arc<Timer>, ResultCode> {
+        match self.get_with_rights(handle, HandleRights::SIGNAL)?.0 {
+            Object::Timer(timer) => Ok(timer),
+            _ => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+        }
+    }
+
+    fn get_waitables(&self, handles: &[Handle]) -> Result<Vec<Object>, ResultCode> {
+        if handles.len() > MAX_HANDLES {
+            return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument));
+        }
+
+        handles
+            .iter()
+            .map(|handle| Ok(self.get_with_rights(*handle, HandleRights::WAIT)?.0))
+            .collect()
+    }
+}
+
+pub fn create_port(tag: u64) -> Result<Handle, ResultCode> {
+    let mut state = lock();
+    let port = Arc::new(Port {
+        queue: Mutex::new(VecDeque::new()),
+    });
+
+    // if not a private port
+    if tag != 0 {
+        if state.ports.contains_key(&tag) {
+            return Err(ResultCode::new(Module::Kernel, Reason::AlreadyExists));
+        }
+        state.ports.insert(tag, port.clone());
+        wake_all();
+    }
+
+    Ok(state.insert(Object::Port(port), HandleRights::ALL))
+}
+
+fn connect_to_port_impl(
+    state: Guard,
+    port: Arc<Port>,
+    deadline: Option<Instant>,
+) -> Result<Handle, ResultCode> {
+    let server_session = Arc::new(ServerSession::new());
+    let client_session = Arc::new(ClientSession::new(Arc::downgrade(&server_session)));
+    *server_session.client.lock() = Arc::downgrade(&client_session);
+
+    {
+        let mut queue = port.queue.lock();
+        if queue.len() >= PORT_BACKLOG {
+            return Err(ResultCode::new(Module::Kernel, Reason::PortFull));
+        }
+        queue.push_back(server_session.clone());
+    }
+    wake_all();
+
+    // Don't keep the port alive while we wait, or we'd never find out it was closed.
+    let weak_port = Arc::downgrade(&port);
+    drop(port);
+
+    let (mut state, res) = wait_until(state, deadline, |_, _, _| {
+        match *server_session.connect.lock() {
+            ConnectState::Waiting => None,
+            connect => Some(connect),
+        }
+    });
+
+    match res {
+        Ok(ConnectState::Accepted) => {}
+        Ok(_) => return Err(ResultCode::new(Module::Kernel, Reason::SessionClosed)),
+        Err(res) => {
+            // The port still has us queued (it would have refused us if it was gone).
+            if let Some(port) = weak_port.upgrade() {
+                port.queue
+                    .lock()
+                    .retain(|x| !Arc::ptr_eq(x, &server_session));
+            }
+            return Err(res);
+        }
+    }
+
+    Ok(state.insert(Object::ClientSession(client_session), HandleRights::ALL))
+}
+
+// The timeout covers both waiting for the port to exist, and waiting to be accepted.
+pub fn connect_to_named_port(tag: u64, timeout_ns: u64) -> Result<Handle, ResultCode> {
+    let deadline = deadline_after(timeout_ns);
+    let (state, port) = wait_until(lock(), deadline, |state, _, _| {
+        state.ports.get(&tag).cloned()
+    });
+    connect_to_port_impl(state, port?, deadline)
+}
+
+pub fn connect_to_port_handle(handle: Handle, timeout_ns: u64) -> Result<Handle, ResultCode> {
+    let state = lock();
+    let port = match state.get(handle)? {
+        Object::Port(port) => port,
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    };
+    connect_to_port_impl(state, port, deadline_after(timeout_ns))
+}
+
+pub fn close_handle(handle: Handle) -> Result<(), ResultCode> {
+    let mut state = lock();
+    match state.handles.remove(&handle.0) {
+        Some(_) => Ok(()),
+        None => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    }
+}
+
+// Rights can only ever be removed, never added.
+pub fn duplicate_handle(handle: Handle, rights: HandleRights) -> Result<Handle, ResultCode> {
+    let mut state = lock();
+    let (object, current) = state.get_with_rights(handle, HandleRights::DUPLICATE)?;
+    Ok(state.insert(object, current & rights))
+}
+
+unsafe fn read_ipc_buffer(ipc_buffer_ptr: usize) -> [u8; IPC_BUFFER_LEN] {
+    *(ipc_buffer_ptr as *const [u8; IPC_BUFFER_LEN])
+}
+
+unsafe fn write_ipc_buffer(ipc_buffer_ptr: usize, ipc_buffer: &[u8; IPC_BUFFER_LEN]) {
+    *(ipc_buffer_ptr as *mut [u8; IPC_BUFFER_LEN]) = *ipc_buffer;
+}
+
+fn read_message_header(ipc_buffer: &[u8; IPC_BUFFER_LEN]) -> Result<IPCHeader, ResultCode> {
+    let invalid_message = ResultCode::new(Module::Kernel, Reason::InvalidMessage);
+
+    let packed_header = u32::from_le_bytes(ipc_buffer[0..4].try_into().unwrap());
+    let header = match IPCHeader::unpack(packed_header) {
+        Some(header) => header,
+        None => return Err(invalid_message),
+    };
+
+    // The size includes the header itself.
+    if header.size < 4
+        || header.translate_count > MAX_TRANSLATE
+        || header.size + header.translate_count * 16 > IPC_BUFFER_LEN
+    {
+        return Err(invalid_message);
+    }
+    Ok(header)
+}
+
+// Buffers are only allowed in requests, where request_buffers is where to keep them.
+fn do_ipc_transfer(
+    state: &mut State,
+    from_ptr: usize,
+    to_ptr: usize,
+    mut request_buffers: Option<&mut [TranslateEntry; MAX_TRANSLATE]>,
+) -> Result<(), ResultCode> {
+    let mut ipc_buffer = unsafe { read_ipc_buffer(from_ptr) };
+
+    let invalid_message = ResultCode::new(Module::Kernel, Reason::InvalidMessage);
+    let header = read_message_header(&ipc_buffer)?;
+
+    // Check every entry before acting on any, so a bad message doesn't lose handles.
+    let mut entries = [TranslateEntry::None; MAX_TRANSLATE];
+    let mut objects: [Option<(Object, HandleRights)>; MAX_TRANSLATE] =
+        core::array::from_fn(|_| None);
+    for i in 0..header.translate_count {
+        let off = header.size + i * 16;
+        let entry = match TranslateEntry::read(ipc_buffer[off..off + 16].try_into().unwrap()) {
+            Some(entry) => entry,
+            None => return Err(invalid_message),
+        };
+
+        match entry {
+            TranslateEntry::None => {}
+            TranslateEntry::CopyHandle(handle) | TranslateEntry::MoveHandle(handle) => {
+                objects[i] = Some(state.get_with_rights(handle, HandleRights::TRANSFER)?);
+            }
+            TranslateEntry::SendBuffer(desc)
+            | TranslateEntry::ReceiveBuffer(desc)
+            | TranslateEntry::ExchangeBuffer(desc) => {
+                if request_buffers.is_none() {
+                    return Err(invalid_message);
+                }
+                if desc.size != 0 && desc.address == 0 {
+                    return Err(ResultCode::new(Module::Kernel, Reason::InvalidPointer));
+                }
+            }
+        }
+        entries[i] = entry;
+    }
+
+    for i in 0..header.translate_count {
+        let off = header.size + i * 16;
+        let entry = entries[i];
+
+        // Both ends share one handle table here, but they still get a handle of their own.
+        let new_entry = match entry {
+            TranslateEntry::None => TranslateEntry::None,
+            TranslateEntry::CopyHandle(_) | TranslateEntry::MoveHandle(_) => {
+                let (object, rights) = objects[i].take().unwrap();
+                let new_handle = state.insert(object, rights);
+
+                if let TranslateEntry::MoveHandle(handle) = entry {
+                    state.handles.remove(&handle.0);
+                    TranslateEntry::MoveHandle(new_handle)
+                } else {
+                    TranslateEntry::CopyHandle(new_handle)
+                }
+            }
+            TranslateEntry::SendBuffer(desc)
+            | TranslateEntry::ReceiveBuffer(desc)
+            | TranslateEntry::ExchangeBuffer(desc) => {
+                request_buffers.as_mut().unwrap()[i] = entry;
+
+                // The server only gets to know the size.
+                let hidden = BufferDescriptor {
+                    address: 0,
+                    size: desc.size,
+                };
+                match entry {
+                    TranslateEntry::SendBuffer(_) => TranslateEntry::SendBuffer(hidden),
+                    TranslateEntry::ReceiveBuffer(_) => TranslateEntry::ReceiveBuffer(hidden),
+                    _ => TranslateEntry::ExchangeBuffer(hidden),
+                }
+            }
+        };
+        TranslateEntry::write(&mut ipc_buffer[off..off + 16], new_entry);
+    }
+
+    unsafe {
+        write_ipc_buffer(to_ptr, &ipc_buffer);
+    }
+    Ok(())
+}
+
+fn wait_for_request(state: Guard, request: &PendingRequest) -> Result<(), ResultCode> {
+    let (_state, res) = wait_until(state, None, |_, _, _| *request.result.lock());
+    match res? {
+        RESULT_OK => Ok(()),
+        res => Err(res),
+    }
+}
+
+pub fn ipc_request(session_handle: Handle, ipc_buffer_ptr: usize) -> Result<(), ResultCode> {
+    let state = lock();
+    let client_session = state.get_client_session(session_handle)?;
+    let server = match client_session.server.upgrade() {
+        Some(server) => server,
+        None => return Err(ResultCode::new(Module::Kernel, Reason::SessionClosed)),
+    };
+
+    let request = Arc::new(PendingRequest::new(ipc_buffer_ptr, None));
+    server.queue.lock().push_back(request.clone());
+    wake_all();
+
+    // Don't keep the server session alive while we wait, or we'd never find out it was closed.
+    drop(server);
+    drop(client_session);
+    wait_for_request(state, &request)
+}
+
+pub fn ipc_request_async(
+    session_handle: Handle,
+    ipc_buffer_ptr: usize,
+    event_handle: Handle,
+) -> Result<Handle, ResultCode> {
+    let mut state = lock();
+    let event = if event_handle == INVALID_HANDLE {
+        None
+    } else {
+        Some(state.get_event(event_handle, HandleRights::SIGNAL)?)
+    };
+
+    let client_session = state.get_client_session(session_handle)?;
+    let server = match client_session.server.upgrade() {
+        Some(server) => server,
+        None => return Err(ResultCode::new(Module::Kernel, Reason::SessionClosed)),
+    };
+
+    let request = Arc::new(PendingRequest::new(ipc_buffer_ptr, event));
+    let request_handle = state.insert(Object::IPCRequest(request.clone()), HandleRights::ALL);
+    server.queue.lock().push_back(request);
+    wake_all();
+    Ok(request_handle)
+}
+
+// TryAgain while there is no reply yet.
+pub fn ipc_request_result(request_handle: Handle) -> Result<(), ResultCode> {
+    let state = lock();
+    match state.get(request_handle)? {
+        Object::IPCRequest(request) => match *request.result.lock() {
+            Some(RESULT_OK) => Ok(()),
+            Some(res) => Err(res),
+            None => Err(ResultCode::new(Module::Kernel, Reason::TryAgain)),
+        },
+        _ => Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    }
+}
+
+fn capture_oneway(state: &mut State, ipc_buffer_ptr: usize) -> Result<OneWayMessage, ResultCode> {
+    let mut message = OneWayMessage {
+        buffer: unsafe { read_ipc_buffer(ipc_buffer_ptr) },
+        handles: core::array::from_fn(|_| None),
+    };
+    let header = read_message_header(&message.buffer)?;
+
+    // Look everything up before moving any handles out, so a bad message doesn't lose handles.
+    let mut moved = Vec::new();
+    for i in 0..header.translate_count {
+        let off = header.size + i * 16;
+        match TranslateEntry::read(message.buffer[off..off + 16].try_into().unwrap()) {
+            Some(TranslateEntry::None) => {}
+            Some(TranslateEntry::CopyHandle(handle)) => {
+                message.handles[i] = Some(state.get_with_rights(handle, HandleRights::TRANSFER)?);
+            }
+            Some(TranslateEntry::MoveHandle(handle)) => {
+                message.handles[i] = Some(state.get_with_rights(handle, HandleRights::TRANSFER)?);
+                moved.push(handle);
+            }
+            // There's no request for the receiver to access buffers through.
+            _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidMessage)),
+        }
+    }
+
+    for handle in moved {
+        state.handles.remove(&handle.0);
+    }
+    Ok(message)
+}
+
+fn deliver_oneway(
+    state: &mut State,
+    mut message: OneWayMessage,
+    ipc_buffer_ptr: usize,
+) -> Result<(), ResultCode> {
+    let header = read_message_header(&message.buffer)?;
+
+    for i in 0..header.translate_count {
+        let (object, rights) = match message.handles[i].take() {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let off = header.size + i * 16;
+        let new_handle = state.insert(object, rights);
+        let new_entry =
+            match TranslateEntry::read(message.buffer[off..off + 16].try_into().unwrap()) {
+                Some(TranslateEntry::MoveHandle(_)) => TranslateEntry::MoveHandle(new_handle),
+                _ => TranslateEntry::CopyHandle(new_handle),
+            };
+        TranslateEntry::write(&mut message.buffer[off..off + 16], new_entry);
+    }
+
+    unsafe {
+        write_ipc_buffer(ipc_buffer_ptr, &message.buffer);
+    }
+    Ok(())
+}
+
+pub fn ipc_send(session_handle: Handle, ipc_buffer_ptr: usize) -> Result<(), ResultCode> {
+    let mut state = lock();
+    let session_closed = ResultCode::new(Module::Kernel, Reason::SessionClosed);
+    match state.get(session_handle)? {
+        Object::ClientSession(client_session) => match client_session.server.upgrade() {
+            Some(server) => {
+                let message = capture_oneway(&mut state, ipc_buffer_ptr)?;
+                queue_oneway(&server.oneway, message)?;
+            }
+            None => return Err(session_closed),
+        },
+        Object::ServerSession(server_session) => {
+            let client = server_session.client.lock().upgrade();
+            match client {
+                Some(client) => {
+                    let message = capture_oneway(&mut state, ipc_buffer_ptr)?;
+                    queue_oneway(&client.oneway, message)?;
+                }
+                None => return Err(session_closed),
+            }
+        }
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    }
+
+    wake_all();
+    Ok(())
+}
+
+// Look up a received request by reply token, and optionally stop tracking it.
+fn get_in_flight(
+    state: &State,
+    reply_token: ReplyToken,
+    take: bool,
+) -> Result<Arc<PendingRequest>, ResultCode> {
+    let server_session = state.get_server_session(reply_token.session())?;
+    let mut in_flight = server_session.in_flight.lock();
+    let request = if take {
+        in_flight.remove(&reply_token.token())
+    } else {
+        in_flight.get(&reply_token.token()).cloned()
+    };
+
+    match request {
+        Some(request) => Ok(request),
+        None => Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument)),
+    }
+}
+
+fn ipc_reply_impl(
+    state: &mut State,
+    reply_token: ReplyToken,
+    ipc_buffer_ptr: usize,
+) -> Result<(), ResultCode> {
+    let request = get_in_flight(state, reply_token, true)?;
+
+    // Either way, the client isn't getting any other reply.
+    let res = match do_ipc_transfer(state, ipc_buffer_ptr, request.buffer_ptr, None) {
+        Ok(()) => RESULT_OK,
+        Err(res) => res,
+    };
+    request.complete(res);
+    wake_all();
+
+    match res {
+        RESULT_OK => Ok(()),
+        res => Err(res),
+    }
+}
+
+pub fn ipc_reply(reply_token: ReplyToken, ipc_buffer_ptr: usize) -> Result<(), ResultCode> {
+    ipc_reply_impl(&mut lock(), reply_token, ipc_buffer_ptr)
+}
+
+fn ipc_receive_impl(
+    state: Guard,
+    handles: &[Handle],
+    ipc_buffer_ptr: usize,
+    timeout_ns: u64,
+) -> Result<ReceiveResult, ResultCode> {
+    let objects = state.get_waitables(handles)?;
+    let (mut state, index) = wait_until(state, deadline_after(timeout_ns), |_, now, wake_at| {
+        objects.iter().position(|x| x.poll(now, wake_at))
+    });
+    let res = index.and_then(|index| {
+        receive_from(&mut state, &objects[index], handles, index, ipc_buffer_ptr)
+    });
+
+    drop(objects);
+    drop(state);
+    res
+}
+
+fn receive_from(
+    state: &mut State,
+    object: &Object,
+    handles: &[Handle],
+    index: usize,
+    ipc_buffer_ptr: usize,
+) -> Result<ReceiveResult, ResultCode> {
+    match object {
+        Object::ServerSession(server_session) => {
+            // One-way messages go first, they're already waiting in the kernel.
+            if let Some(message) = server_session.oneway.lock().pop_front() {
+                deliver_oneway(state, message, ipc_buffer_ptr)?;
+                return Ok(ReceiveResult::Signalled(index));
+            }
+
+            // Nothing queued means the session was only signalled for being closed.
+            let request = match server_session.queue.lock().pop_front() {
+                Some(request) => request,
+                None => return Ok(ReceiveResult::SessionClosed(index)),
+            };
+
+            let res = do_ipc_transfer(
+                state,
+                request.buffer_ptr,
+                ipc_buffer_ptr,
+                Some(&mut request.buffers.lock()),
+            );
+            if let Err(res) = res {
+                // The message never made it here, so bounce the error back to the client too.
+                request.complete(res);
+                wake_all();
+                return Err(res);
+            }
+
+            let token = server_session.add_in_flight(request);
+            Ok(ReceiveResult::Request(
+                index,
+                ReplyToken::new(handles[index], token),
+            ))
+        }
+        Object::ClientSession(client_session) => match client_session.oneway.lock().pop_front() {
+            Some(message) => {
+                deliver_oneway(state, message, ipc_buffer_ptr)?;
+                Ok(ReceiveResult::Signalled(index))
+            }
+            None => Ok(ReceiveResult::SessionClosed(index)),
+        },
+        _ => Ok(ReceiveResult::Signalled(index)),
+    }
+}
+
+pub fn ipc_receive(
+    handles: &[Handle],
+    ipc_buffer_ptr: usize,
+    timeout_ns: u64,
+) -> Result<ReceiveResult, ResultCode> {
+    ipc_receive_impl(lock(), handles, ipc_buffer_ptr, timeout_ns)
+}
+
+pub fn ipc_reply_and_receive(
+    reply_token: ReplyToken,
+    handles: &[Handle],
+    ipc_buffer_ptr: usize,
+    timeout_ns: u64,
+) -> Result<ReceiveResult, ResultCode> {
+    let mut state = lock();
+    if reply_token.token() != 0 {
+        ipc_reply_impl(&mut state, reply_token, ipc_buffer_ptr)?;
+    }
+    ipc_receive_impl(state, handles, ipc_buffer_ptr, timeout_ns)
+}
+
+pub fn ipc_accept(port_handle: Handle) -> Result<Handle, ResultCode> {
+    let mut state = lock();
+    let port = match state.get(port_handle)? {
+        Object::Port(port) => port,
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    };
+
+    let server_session = match port.queue.lock().pop_front() {
+        Some(server_session) => server_session,
+        None => return Err(ResultCode::new(Module::Kernel, Reason::TryAgain)),
+    };
+
+    // wake the client
+    *server_session.connect.lock() = ConnectState::Accepted;
+    wake_all();
+
+    Ok(state.insert(Object::ServerSession(server_session), HandleRights::ALL))
+}
+
+pub fn create_session() -> Result<(Handle, Handle), ResultCode> {
+    let mut state = lock();
+    let server_session = Arc::new(ServerSession::new());
+    let client_session = Arc::new(ClientSession::new(Arc::downgrade(&server_session)));
+    *server_session.client.lock() = Arc::downgrade(&client_session);
+    *server_session.connect.lock() = ConnectState::Accepted;
+
+    let server_handle = state.insert(Object::ServerSession(server_session), HandleRights::ALL);
+    let client_handle = state.insert(Object::ClientSession(client_session), HandleRights::ALL);
+    Ok((server_handle, client_handle))
+}
+
+// Find a buffer from a received request, checking that it can be accessed this way.
+fn get_request_buffer(
+    reply_token: ReplyToken,
+    index: usize,
+    write: bool,
+    len: usize,
+) -> Result<BufferDescriptor, ResultCode> {
+    let state = lock();
+    let request = get_in_flight(&state, reply_token, false)?;
+
+    if index >= MAX_TRANSLATE {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument));
+    }
+
+    let entry = request.buffers.lock()[index];
+    let desc = match (entry, write) {
+        (TranslateEntry::SendBuffer(desc), false)
+        | (TranslateEntry::ReceiveBuffer(desc), true)
+        | (TranslateEntry::ExchangeBuffer(desc), _) => desc,
+        (TranslateEntry::SendBuffer(_), true) | (TranslateEntry::ReceiveBuffer(_), false) => {
+            return Err(ResultCode::new(Module::Kernel, Reason::NotAllowed))
+        }
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument)),
+    };
+
+    if len > desc.size {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument));
+    }
+    Ok(desc)
+}
+
+// The client is blocked (or has promised not to touch the buffer) until the request is replied
+// to, so the buffer can be copied without holding the lock.
+pub fn ipc_read_buffer(
+    reply_token: ReplyToken,
+    index: usize,
+    buffer: &mut [u8],
+) -> Result<(), ResultCode> {
+    let desc = get_request_buffer(reply_token, index, false, buffer.len())?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            desc.address as *const u8,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+        );
+    }
+    Ok(())
+}
+
+pub fn ipc_write_buffer(
+    reply_token: ReplyToken,
+    index: usize,
+    buffer: &[u8],
+) -> Result<(), ResultCode> {
+    let desc = get_request_buffer(reply_token, index, true, buffer.len())?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(buffer.as_ptr(), desc.address as *mut u8, buffer.len());
+    }
+    Ok(())
+}
+
+pub fn create_event(mode: EventMode) -> Result<Handle, ResultCode> {
+    let event = Arc::new(Event {
+        mode: mode,
+        signalled: Mutex::new(false),
+    });
+    Ok(lock().insert(Object::Event(event), HandleRights::ALL))
+}
+
+pub fn bind_interrupt(handle: Handle, index: usize) -> Result<(), ResultCode> {
+    if index >= INTERRUPT_COUNT {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument));
+    }
+
+    let mut state = lock();
+    let event = state.get_event(handle, HandleRights::BIND_INTERRUPT)?;
+    if state.interrupts.contains_key(&index) {
+        return Err(ResultCode::new(Module::Kernel, Reason::Unknown));
+    }
+    state.interrupts.insert(index, event);
+    Ok(())
+}
+
+pub fn unbind_interrupt(handle: Handle, index: usize) -> Result<(), ResultCode> {
+    if index >= INTERRUPT_COUNT {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument));
+    }
+
+    let mut state = lock();
+    let event = state.get_event(handle, HandleRights::BIND_INTERRUPT)?;
+    match state.interrupts.get(&index) {
+        Some(bound) if Arc::ptr_eq(bound, &event) => {
+            state.interrupts.remove(&index);
+            Ok(())
+        }
+        Some(_) => Err(ResultCode::new(Module::Kernel, Reason::NotAllowed)),
+        None => Err(ResultCode::new(Module::Kernel, Reason::NotFound)),
+    }
+}
+
+pub fn signal_event(handle: Handle) -> Result<(), ResultCode> {
+    let state = lock();
+    state.get_event(handle, HandleRights::SIGNAL)?.signal();
+    wake_all();
+    Ok(())
+}
+
+// Clearing only needs WAIT, same as in the kernel.
+pub fn clear_event(handle: Handle) -> Result<(), ResultCode> {
+    let state = lock();
+    *state
+        .get_event(handle, HandleRights::WAIT)?
+        .signalled
+        .lock() = false;
+    Ok(())
+}
+
+pub fn create_timer() -> Result<Handle, ResultCode> {
+    let timer = Arc::new(Timer {
+        state: Mutex::new(TimerState {
+            deadline: None,
+            period_ns: 0,
+            pending: false,
+        }),
+    });
+    Ok(lock().insert(Object::Timer(timer), HandleRights::ALL))
+}
+
+pub fn set_timer(handle: Handle, initial_ns: u64, period_ns: u64) -> Result<(), ResultCode> {
+    let state = lock();
+    let timer = state.get_timer(handle)?;
+    *timer.state.lock() = TimerState {
+        deadline: deadline_after(initial_ns),
+        period_ns: period_ns,
+        pending: false,
+    };
+
+    // Anybody already waiting needs to pick up the new deadline.
+    wake_all();
+    Ok(())
+}
+
+pub fn cancel_timer(handle: Handle) -> Result<(), ResultCode> {
+    let state = lock();
+    let timer = state.get_timer(handle)?;
+    let mut timer_state = timer.state.lock();
+    timer_state.deadline = None;
+    timer_state.pending = false;
+    Ok(())
+}
+
+pub fn create_semaphore(initial_count: usize, max_count: usize) -> Result<Handle, ResultCode> {
+    if max_count == 0 || initial_count > max_count {
+        return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument));
+    }
+
+    let semaphore = Arc::new(Semaphore {
+        count: Mutex::new(initial_count),
+        max_count: max_count,
+    });
+    Ok(lock().insert(Object::Semaphore(semaphore), HandleRights::ALL))
+}
+
+// Returns the count from before the release.
+pub fn release_semaphore(handle: Handle, release_count: usize) -> Result<usize, ResultCode> {
+    let state = lock();
+    let semaphore = match state.get_with_rights(handle, HandleRights::SIGNAL)?.0 {
+        Object::Semaphore(semaphore) => semaphore,
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidHandle)),
+    };
+
+    let mut count = semaphore.count.lock();
+    let previous_count = *count;
+    match previous_count.checked_add(release_count) {
+        Some(x) if x <= semaphore.max_count => *count = x,
+        _ => return Err(ResultCode::new(Module::Kernel, Reason::InvalidArgument)),
+    }
+
+    wake_all();
+    Ok(previous_count)
+}
+
+pub fn wait_many(handles: &[Handle], timeout_ns: u64) -> Result<usize, ResultCode> {
+    let state = lock();
+    let objects = state.get_waitables(handles)?;
+    let (state, index) = wait_until(state, deadline_after(timeout_ns), |_, now, wake_at| {
+        objects.iter().position(|x| x.poll(now, wake_at))
+    });
+
+    drop(objects);
+    drop(state);
+    index
+}
+
+// Futexes are keyed by address, which is all the sharing there is within one process.
+pub fn futex_wait(addr: &AtomicU32, expected: u32, timeout_ns: u64) -> Result<(), ResultCode> {
+    let key = addr as *const AtomicU32 as usize;
+
+    // Check the value under the lock, so we can't miss a wake.
+    let mut state = lock();
+    if addr.load(Ordering::SeqCst) != expected {
+        return Err(ResultCode::new(Module::Kernel, Reason::TryAgain));
+    }
+
+    if timeout_ns == 0 {
+        return Err(ResultCode::new(Module::Kernel, Reason::TimedOut));
+    }
+
+    let id = state.next_futex_id;
+    state.next_futex_id += 1;
+    state.futex_waiters.push(FutexWaiter {
+        key: key,
+        id: id,
+        woken: false,
+    });
+
+    let (mut state, res) = wait_until(state, deadline_after(timeout_ns), |state, _, _| {
+        state
+            .futex_waiters
+            .iter()
+            .find(|x| x.id == id && x.woken)
+            .map(|_| ())
+    });
+
+    // Woken or not, we're not waiting any more.
+    state.futex_waiters.retain(|x| x.id != id);
+    res
+}
+
+fn futex_wake_locked(state: &mut State, key: usize, count: usize) -> usize {
+    let mut woken = 0;
+    for waiter in state.futex_waiters.iter_mut() {
+        if woken == count {
+            break;
+        }
+
+        if waiter.key == key && !waiter.woken {
+            waiter.woken = true;
+            woken += 1;
+        }
+    }
+
+    if woken != 0 {
+        wake_all();
+    }
+    woken
+}
+
+pub fn futex_wake(addr: &AtomicU32, count: usize) -> Result<usize, ResultCode> {
+    let key = addr as *const AtomicU32 as usize;
+    Ok(futex_wake_locked(&mut lock(), key, count))
+}
+
+pub fn futex_requeue(
+    addr: &AtomicU32,
+    expected: u32,
+    wake_count: usize,
+    target_addr: &AtomicU32,
+    requeue_count: usize,
+) -> Result<usize, ResultCode> {
+    let key = addr as *const AtomicU32 as usize;
+    let target_key = target_addr as *const AtomicU32 as usize;
+
+    let mut state = lock();
+    if addr.load(Ordering::SeqCst) != expected {
+        return Err(ResultCode::new(Module::Kernel, Reason::TryAgain));
+    }
+
+    let woken = futex_wake_locked(&mut state, key, wake_count);
+
+    let mut requeued = 0;
+    for waiter in state.futex_waiters.iter_mut() {
+        if requeued == requeue_count {
+            break;
+        }
+
+        if waiter.key == key && !waiter.woken {
+            waiter.key = target_key;
+            requeued += 1;
+        }
+    }
+    Ok(woken)
+}
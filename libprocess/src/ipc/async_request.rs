@@ -0,0 +1,129 @@
+use crate::os_error::OSError;
+use crate::syscalls;
+use common::{Handle, INVALID_HANDLE};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+
+// All outstanding requests share one completion event. A single thread waits on it, and wakes
+// whichever requests have finished.
+struct Completions {
+    event: Handle,
+    waiting: Mutex<Vec<(Handle, Waker)>>,
+}
+
+static COMPLETIONS: OnceLock<Completions> = OnceLock::new();
+
+fn get_completions() -> &'static Completions {
+    COMPLETIONS.get_or_init(|| {
+        let event = syscalls::create_event().unwrap();
+        std::thread::spawn(move || completion_thread(event));
+
+        Completions {
+            event: event,
+            waiting: Mutex::new(Vec::new()),
+        }
+    })
+}
+
+fn completion_thread(event: Handle) {
+    loop {
+        syscalls::wait_one(event).unwrap();
+
+        // Nothing can signal the event before COMPLETIONS is set.
+        let completions = COMPLETIONS.get().unwrap();
+        completions
+            .waiting
+            .lock()
+            .unwrap()
+            .retain(|(request, waker)| {
+                if syscalls::ipc_request_result(*request).is_some() {
+                    waker.wake_by_ref();
+                    false
+                } else {
+                    true
+                }
+            });
+    }
+}
+
+// A request that has been sent, which resolves to the buffer with the reply in it.
+pub struct IPCRequestFuture {
+    buffer: Option<Box<[u8; 128]>>,
+    request: Handle,
+}
+
+pub fn send(session_handle: Handle, buffer: Box<[u8; 128]>) -> Result<IPCRequestFuture, OSError> {
+    let completions = get_completions();
+    let buffer_ptr = Box::into_raw(buffer);
+
+    // The buffer is only handed back once the request is done with it.
+    match unsafe { syscalls::ipc_request_async(session_handle, buffer_ptr, completions.event) } {
+        Ok(request) => Ok(IPCRequestFuture {
+            buffer: Some(unsafe { Box::from_raw(buffer_ptr) }),
+            request: request,
+        }),
+        Err(err) => {
+            drop(unsafe { Box::from_raw(buffer_ptr) });
+            Err(err)
+        }
+    }
+}
+
+impl IPCRequestFuture {
+    fn forget_request(&mut self) {
+        let completions = get_completions();
+        let mut waiting = completions.waiting.lock().unwrap();
+        waiting.retain(|(request, _)| *request != self.request);
+        drop(waiting);
+
+        syscalls::close_handle(self.request).unwrap();
+        self.request = INVALID_HANDLE;
+    }
+}
+
+impl Future for IPCRequestFuture {
+    type Output = Result<Box<[u8; 128]>, OSError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let completions = get_completions();
+
+        // Check under the lock, so the completion thread can't miss us.
+        let mut waiting = completions.waiting.lock().unwrap();
+        match syscalls::ipc_request_result(this.request) {
+            Some(res) => {
+                drop(waiting);
+                this.forget_request();
+                Poll::Ready(res.map(|_| this.buffer.take().unwrap()))
+            }
+            None => {
+                match waiting
+                    .iter_mut()
+                    .find(|(request, _)| *request == this.request)
+                {
+                    Some(entry) => entry.1 = cx.waker().clone(),
+                    None => waiting.push((this.request, cx.waker().clone())),
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for IPCRequestFuture {
+    fn drop(&mut self) {
+        if self.request == INVALID_HANDLE {
+            return;
+        }
+
+        // The kernel may still write the reply into the buffer, so leak it rather than free it.
+        if syscalls::ipc_request_result(self.request).is_none() {
+            if let Some(buffer) = self.buffer.take() {
+                Box::leak(buffer);
+            }
+        }
+        self.forget_request();
+    }
+}
@@ -5,16 +5,18 @@ use spin::Mutex;
 
 static FS_HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
 
-fn get_handle_for_fs() -> Handle {
+fn get_handle_for_fs() -> OSResult<Handle> {
     let mut locked = FS_HANDLE.lock();
     match *locked {
-        Some(x) => x,
+        Some(x) => Ok(x),
         None => {
-            let handle = crate::ipc::sm::get_service_handle(crate::syscalls::make_tag("fs"))
-                .unwrap()
-                .0;
+            let handle = crate::ipc::sm::get_service_handle(crate::syscalls::make_tag("fs"))?.0;
+            if let Err(err) = check_interface_version(handle) {
+                let _ = crate::syscalls::close_handle(handle);
+                return Err(err);
+            }
             *locked = Some(handle);
-            handle
+            Ok(handle)
         }
     }
 }
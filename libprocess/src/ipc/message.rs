@@ -1,6 +1,8 @@
-use crate::os_error::{OSError, OSResult, ResultCode, RESULT_OK};
+use crate::os_error::{Module, OSError, OSResult, Reason, ResultCode, RESULT_OK};
 use common::ipc::*;
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
+
+pub use ipc_derive::IPCValue;
 
 #[thread_local]
 pub static mut IPC_BUFFER: [u8; 128] = [0; 128];
@@ -14,17 +16,17 @@ pub struct IPCMessage<'a> {
     pub buffer: &'a mut [u8],
 }
 
+// Reads fail rather than panic on anything the other side could have got wrong.
 pub trait IPCValue {
-    fn read(_msg: &mut IPCMessage) -> Self
+    fn read(msg: &mut IPCMessage) -> OSResult<Self>
     where
-        Self: Sized,
-    {
-        unimplemented!();
-    }
+        Self: Sized;
 
-    fn write(_msg: &mut IPCMessage, _val: &Self) {
-        unimplemented!();
-    }
+    fn write(msg: &mut IPCMessage, val: &Self);
+}
+
+pub fn invalid_message() -> OSError {
+    OSError::new(Module::LibProcess, Reason::InvalidMessage)
 }
 
 impl IPCMessage<'_> {
@@ -44,9 +46,20 @@ impl IPCMessage<'_> {
         }
     }
 
-    pub fn read_header(&mut self) {
+    pub fn read_header(&mut self) -> OSResult<()> {
         let packed = u32::from_le_bytes(self.buffer[0..4].try_into().unwrap());
-        self.header = IPCHeader::unpack(packed);
+        let header = IPCHeader::unpack(packed).ok_or_else(invalid_message)?;
+
+        // The size includes the header itself.
+        if header.size < 4
+            || header.translate_count > MAX_TRANSLATE
+            || header.size + header.translate_count * 16 > self.buffer.len()
+        {
+            return Err(invalid_message());
+        }
+
+        self.header = header;
+        Ok(())
     }
 
     pub fn write_header_for(&mut self, method_id: u32) {
@@ -60,6 +73,17 @@ impl IPCMessage<'_> {
         self.buffer[0..4].copy_from_slice(&u32::to_le_bytes(packed));
     }
 
+    // A buffer that the server only writes to. Plain &mut [u8] values go as exchange buffers, which
+    // the server reads first.
+    pub fn write_receive_buffer(&mut self, value: &mut [u8]) {
+        self.translate_entries[self.current_translate] =
+            TranslateEntry::ReceiveBuffer(BufferDescriptor {
+                address: value.as_ptr() as usize,
+                size: value.len(),
+            });
+        self.current_translate += 1;
+    }
+
     pub fn write_translates(&mut self) {
         for i in 0..self.current_translate {
             let entry = self.translate_entries[i];
@@ -73,28 +97,72 @@ impl IPCMessage<'_> {
         for i in 0..self.header.translate_count {
             let off = self.header.size + i * 16;
             let buffer = &self.buffer[off..off + 16];
-            self.translate_entries[i] = TranslateEntry::read(buffer.try_into().unwrap());
+            self.translate_entries[i] =
+                TranslateEntry::read(buffer.try_into().unwrap()).unwrap_or(TranslateEntry::None);
         }
     }
 
-    pub fn read<T: IPCValue>(&mut self) -> T {
+    pub fn read<T: IPCValue>(&mut self) -> OSResult<T> {
         T::read(self)
     }
 
     pub fn write<T: IPCValue>(&mut self, a: T) {
         T::write(self, &a)
     }
+
+    // Takes the next length bytes of the message, if there are that many left.
+    pub fn read_bytes(&mut self, length: usize) -> OSResult<&[u8]> {
+        let end = match self.read_offset.checked_add(length) {
+            Some(end) if end <= self.header.size => end,
+            _ => return Err(invalid_message()),
+        };
+
+        let bytes = &self.buffer[self.read_offset..end];
+        self.read_offset = end;
+        Ok(bytes)
+    }
+
+    pub fn read_translate(&mut self) -> OSResult<TranslateEntry> {
+        if self.current_translate >= self.header.translate_count {
+            return Err(invalid_message());
+        }
+
+        let entry = self.translate_entries[self.current_translate];
+        self.current_translate += 1;
+        Ok(entry)
+    }
+
+    // Replies lead with a status, so that a server can turn down a request it couldn't make sense
+    // of whatever the method returns.
+    pub fn write_reply<T: IPCValue>(&mut self, val: T) {
+        self.write(RESULT_OK);
+        self.write(val);
+        self.write_translates();
+        self.write_header_for(0);
+    }
+
+    pub fn write_error_reply(&mut self, err: OSError) {
+        self.write(err);
+        self.write_translates();
+        self.write_header_for(0);
+    }
+
+    pub fn read_reply<T: IPCValue>(&mut self) -> OSResult<T> {
+        self.read_header()?;
+        self.read_translates();
+
+        let status: ResultCode = self.read()?;
+        if status != RESULT_OK {
+            return Err(OSError::from_result_code(status));
+        }
+        self.read()
+    }
 }
 
 impl IPCValue for u64 {
-    fn read(msg: &mut IPCMessage) -> u64 {
-        let val = u64::from_le_bytes(
-            msg.buffer[msg.read_offset..msg.read_offset + 8]
-                .try_into()
-                .unwrap(),
-        );
-        msg.read_offset += 8;
-        val
+    fn read(msg: &mut IPCMessage) -> OSResult<u64> {
+        let bytes = msg.read_bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
     }
 
     fn write(msg: &mut IPCMessage, val: &u64) {
@@ -104,14 +172,9 @@ impl IPCValue for u64 {
 }
 
 impl IPCValue for u32 {
-    fn read(msg: &mut IPCMessage) -> u32 {
-        let val = u32::from_le_bytes(
-            msg.buffer[msg.read_offset..msg.read_offset + 4]
-                .try_into()
-                .unwrap(),
-        );
-        msg.read_offset += 4;
-        val
+    fn read(msg: &mut IPCMessage) -> OSResult<u32> {
+        let bytes = msg.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
     }
 
     fn write(msg: &mut IPCMessage, val: &u32) {
@@ -121,14 +184,9 @@ impl IPCValue for u32 {
 }
 
 impl IPCValue for u16 {
-    fn read(msg: &mut IPCMessage) -> u16 {
-        let val = u16::from_le_bytes(
-            msg.buffer[msg.read_offset..msg.read_offset + 2]
-                .try_into()
-                .unwrap(),
-        );
-        msg.read_offset += 2;
-        val
+    fn read(msg: &mut IPCMessage) -> OSResult<u16> {
+        let bytes = msg.read_bytes(2)?;
+        Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
     }
 
     fn write(msg: &mut IPCMessage, val: &u16) {
@@ -138,10 +196,8 @@ impl IPCValue for u16 {
 }
 
 impl IPCValue for u8 {
-    fn read(msg: &mut IPCMessage) -> u8 {
-        let val = msg.buffer[msg.read_offset];
-        msg.read_offset += 1;
-        val
+    fn read(msg: &mut IPCMessage) -> OSResult<u8> {
+        Ok(msg.read_bytes(1)?[0])
     }
 
     fn write(msg: &mut IPCMessage, val: &u8) {
@@ -152,14 +208,9 @@ impl IPCValue for u8 {
 
 // TODO: sizeof(usize)=4?
 impl IPCValue for usize {
-    fn read(msg: &mut IPCMessage) -> usize {
-        let val = u64::from_le_bytes(
-            msg.buffer[msg.read_offset..msg.read_offset + 8]
-                .try_into()
-                .unwrap(),
-        );
-        msg.read_offset += 8;
-        val as usize
+    fn read(msg: &mut IPCMessage) -> OSResult<usize> {
+        let val = u64::read(msg)?;
+        usize::try_from(val).map_err(|_| invalid_message())
     }
 
     fn write(msg: &mut IPCMessage, val: &usize) {
@@ -170,10 +221,12 @@ impl IPCValue for usize {
 }
 
 impl IPCValue for bool {
-    fn read(msg: &mut IPCMessage) -> bool {
-        let val = msg.buffer[msg.read_offset];
-        msg.read_offset += 1;
-        val != 0
+    fn read(msg: &mut IPCMessage) -> OSResult<bool> {
+        match u8::read(msg)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_message()),
+        }
     }
 
     fn write(msg: &mut IPCMessage, val: &bool) {
@@ -187,12 +240,11 @@ impl IPCValue for bool {
 }
 
 impl IPCValue for String {
-    fn read(msg: &mut IPCMessage) -> String {
-        let length = usize::read(msg);
-        let bytes: Vec<u8> = msg.buffer[msg.read_offset..msg.read_offset + length].to_vec();
-        msg.read_offset += length;
+    fn read(msg: &mut IPCMessage) -> OSResult<String> {
+        let length = usize::read(msg)?;
+        let bytes = msg.read_bytes(length)?.to_vec();
 
-        String::from_utf8(bytes).unwrap()
+        String::from_utf8(bytes).map_err(|_| invalid_message())
     }
 
     fn write(msg: &mut IPCMessage, val: &String) {
@@ -206,8 +258,8 @@ impl IPCValue for String {
 }
 
 impl IPCValue for ResultCode {
-    fn read(msg: &mut IPCMessage) -> ResultCode {
-        ResultCode(u32::read(msg))
+    fn read(msg: &mut IPCMessage) -> OSResult<ResultCode> {
+        Ok(ResultCode(u32::read(msg)?))
     }
 
     fn write(msg: &mut IPCMessage, val: &ResultCode) {
@@ -216,8 +268,8 @@ impl IPCValue for ResultCode {
 }
 
 impl IPCValue for OSError {
-    fn read(msg: &mut IPCMessage) -> OSError {
-        OSError::from_result_code(ResultCode::read(msg))
+    fn read(msg: &mut IPCMessage) -> OSResult<OSError> {
+        Ok(OSError::from_result_code(ResultCode::read(msg)?))
     }
 
     fn write(msg: &mut IPCMessage, val: &OSError) {
@@ -226,19 +278,10 @@ impl IPCValue for OSError {
 }
 
 impl IPCValue for TranslateMoveHandle {
-    fn read(msg: &mut IPCMessage) -> TranslateMoveHandle {
-        if let TranslateEntry::MoveHandle(handle) = msg.translate_entries[msg.current_translate] {
-            msg.current_translate += 1;
-            TranslateMoveHandle(handle)
-        } else {
-            println!(
-                "{:?} of {:?}",
-                msg.current_translate, msg.header.translate_count
-            );
-            panic!(
-                "Invalid translate! Expected Move, got {:?}",
-                msg.translate_entries[msg.current_translate]
-            );
+    fn read(msg: &mut IPCMessage) -> OSResult<TranslateMoveHandle> {
+        match msg.read_translate()? {
+            TranslateEntry::MoveHandle(handle) => Ok(TranslateMoveHandle(handle)),
+            _ => Err(invalid_message()),
         }
     }
 
@@ -249,19 +292,10 @@ impl IPCValue for TranslateMoveHandle {
 }
 
 impl IPCValue for TranslateCopyHandle {
-    fn read(msg: &mut IPCMessage) -> TranslateCopyHandle {
-        if let TranslateEntry::CopyHandle(handle) = msg.translate_entries[msg.current_translate] {
-            msg.current_translate += 1;
-            TranslateCopyHandle(handle)
-        } else {
-            println!(
-                "{:?} of {:?}",
-                msg.current_translate, msg.header.translate_count
-            );
-            panic!(
-                "Invalid translate! Expected Copy, got {:?}",
-                msg.translate_entries[msg.current_translate]
-            );
+    fn read(msg: &mut IPCMessage) -> OSResult<TranslateCopyHandle> {
+        match msg.read_translate()? {
+            TranslateEntry::CopyHandle(handle) => Ok(TranslateCopyHandle(handle)),
+            _ => Err(invalid_message()),
         }
     }
 
@@ -271,14 +305,81 @@ impl IPCValue for TranslateCopyHandle {
     }
 }
 
+// Client side buffers. The kernel hands the server the size, and copies the contents on demand.
+impl IPCValue for &[u8] {
+    // Borrowed buffers only go out. Servers get an IPCBuffer instead.
+    fn read(_msg: &mut IPCMessage) -> OSResult<Self> {
+        Err(invalid_message())
+    }
+
+    fn write(msg: &mut IPCMessage, value: &&[u8]) {
+        msg.translate_entries[msg.current_translate] =
+            TranslateEntry::SendBuffer(BufferDescriptor {
+                address: value.as_ptr() as usize,
+                size: value.len(),
+            });
+        msg.current_translate += 1;
+    }
+}
+
+impl IPCValue for &mut [u8] {
+    fn read(_msg: &mut IPCMessage) -> OSResult<Self> {
+        Err(invalid_message())
+    }
+
+    fn write(msg: &mut IPCMessage, value: &&mut [u8]) {
+        msg.translate_entries[msg.current_translate] =
+            TranslateEntry::ExchangeBuffer(BufferDescriptor {
+                address: value.as_ptr() as usize,
+                size: value.len(),
+            });
+        msg.current_translate += 1;
+    }
+}
+
+// Server side view of a client buffer, for ipc_read_buffer / ipc_write_buffer.
+#[derive(Debug)]
+pub struct IPCBuffer {
+    pub index: usize,
+    pub size: usize,
+}
+
+impl IPCValue for IPCBuffer {
+    fn read(msg: &mut IPCMessage) -> OSResult<IPCBuffer> {
+        let index = msg.current_translate;
+        match msg.read_translate()? {
+            TranslateEntry::SendBuffer(desc)
+            | TranslateEntry::ReceiveBuffer(desc)
+            | TranslateEntry::ExchangeBuffer(desc) => {
+                // The kernel checks this too, but the server is about to allocate this much.
+                if desc.size > MAX_BUFFER_SIZE {
+                    return Err(invalid_message());
+                }
+                Ok(IPCBuffer {
+                    index: index,
+                    size: desc.size,
+                })
+            }
+            _ => Err(invalid_message()),
+        }
+    }
+
+    // The index only means something in the message the buffer came in on, so it can't be passed
+    // on. Leave the slot empty rather than point the receiver at the wrong buffer.
+    fn write(msg: &mut IPCMessage, _value: &IPCBuffer) {
+        msg.translate_entries[msg.current_translate] = TranslateEntry::None;
+        msg.current_translate += 1;
+    }
+}
+
 impl<T: IPCValue> IPCValue for OSResult<T> {
-    fn read(msg: &mut IPCMessage) -> OSResult<T> {
+    fn read(msg: &mut IPCMessage) -> OSResult<OSResult<T>> {
         // read error code
-        let res = ResultCode::read(msg);
+        let res = ResultCode::read(msg)?;
         if res == RESULT_OK {
-            Ok(T::read(msg))
+            Ok(Ok(T::read(msg)?))
         } else {
-            Err(OSError::from_result_code(res))
+            Ok(Err(OSError::from_result_code(res)))
         }
     }
 
@@ -295,13 +396,12 @@ impl<T: IPCValue> IPCValue for OSResult<T> {
 }
 
 impl<T: IPCValue> IPCValue for Option<T> {
-    fn read(msg: &mut IPCMessage) -> Option<T> {
-        // read error code
-        let present = bool::read(msg);
+    fn read(msg: &mut IPCMessage) -> OSResult<Option<T>> {
+        let present = bool::read(msg)?;
         if present {
-            Some(T::read(msg))
+            Ok(Some(T::read(msg)?))
         } else {
-            None
+            Ok(None)
         }
     }
 
@@ -317,36 +417,52 @@ impl<T: IPCValue> IPCValue for Option<T> {
 }
 
 impl IPCValue for () {
-    fn read(_msg: &mut IPCMessage) {}
+    fn read(_msg: &mut IPCMessage) -> OSResult<()> {
+        Ok(())
+    }
 
     fn write(_msg: &mut IPCMessage, _: &()) {}
 }
 
-impl<T> IPCValue for (T,)
-where
-    T: IPCValue,
-{
-    fn read(msg: &mut IPCMessage) -> (T,) {
-        (T::read(msg),)
-    }
+macro_rules! impl_ipc_value_tuple {
+    ($($name:ident $index:tt),+) => {
+        impl<$($name),+> IPCValue for ($($name,)+)
+        where
+            $($name: IPCValue,)+
+        {
+            fn read(msg: &mut IPCMessage) -> OSResult<($($name,)+)> {
+                Ok(($($name::read(msg)?,)+))
+            }
 
-    fn write(msg: &mut IPCMessage, val: &(T,)) {
-        T::write(msg, &val.0)
-    }
+            fn write(msg: &mut IPCMessage, val: &($($name,)+)) {
+                $($name::write(msg, &val.$index);)+
+            }
+        }
+    };
 }
 
-impl<T, U> IPCValue for (T, U)
+impl_ipc_value_tuple!(T 0);
+impl_ipc_value_tuple!(T 0, U 1);
+impl_ipc_value_tuple!(T 0, U 1, V 2);
+impl_ipc_value_tuple!(T 0, U 1, V 2, W 3);
+
+impl<T, const N: usize> IPCValue for [T; N]
 where
     T: IPCValue,
-    U: IPCValue,
 {
-    fn read(msg: &mut IPCMessage) -> (T, U) {
-        (T::read(msg), U::read(msg))
+    fn read(msg: &mut IPCMessage) -> OSResult<[T; N]> {
+        let mut items = Vec::with_capacity(N);
+        for _ in 0..N {
+            items.push(T::read(msg)?);
+        }
+
+        items.try_into().map_err(|_| invalid_message())
     }
 
-    fn write(msg: &mut IPCMessage, val: &(T, U)) {
-        T::write(msg, &val.0);
-        U::write(msg, &val.1);
+    fn write(msg: &mut IPCMessage, value: &[T; N]) {
+        for item in value {
+            T::write(msg, item)
+        }
     }
 }
 
@@ -354,15 +470,17 @@ impl<T> IPCValue for Vec<T>
 where
     T: IPCValue,
 {
-    fn read(msg: &mut IPCMessage) -> Vec<T> {
-        let length = usize::read(msg);
+    fn read(msg: &mut IPCMessage) -> OSResult<Vec<T>> {
+        let length = usize::read(msg)?;
 
-        let mut new_vec = Vec::with_capacity(length);
+        // Don't trust the length for the allocation, the rest of the message is all there is.
+        let mut new_vec =
+            Vec::with_capacity(length.min(msg.header.size.saturating_sub(msg.read_offset)));
         for _ in 0..length {
-            new_vec.push(T::read(msg))
+            new_vec.push(T::read(msg)?)
         }
 
-        new_vec
+        Ok(new_vec)
     }
 
     fn write(msg: &mut IPCMessage, value: &Vec<T>) {
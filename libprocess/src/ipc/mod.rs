@@ -1,6 +1,8 @@
+pub mod async_request;
 pub mod fs;
 pub mod message;
 pub mod pcie;
 pub mod sm;
+pub mod trace;
 
 pub use common::ipc::*;
@@ -2,7 +2,11 @@ use common::ipc::*;
 use common::Handle;
 use spin::Mutex;
 
-#[derive(Copy, Clone, Default, Debug)]
+use crate::ipc::message::IPCValue;
+use crate::os_error::OSResult;
+//use common::ipc::TranslateMoveHandle;
+
+#[derive(Copy, Clone, Default, Debug, IPCValue)]
 pub struct PCIDeviceInfo {
     pub bus: u8,
     pub device: u8,
@@ -10,41 +14,20 @@ pub struct PCIDeviceInfo {
     pub device_id: u16,
 }
 
-use crate::os_error::OSResult;
-//use common::ipc::TranslateMoveHandle;
-use crate::ipc::message::IPCMessage;
-use crate::ipc::message::IPCValue;
-impl IPCValue for PCIDeviceInfo {
-    fn read(msg: &mut IPCMessage) -> PCIDeviceInfo {
-        PCIDeviceInfo {
-            bus: u8::read(msg),
-            device: u8::read(msg),
-            vendor_id: u16::read(msg),
-            device_id: u16::read(msg),
-        }
-    }
-
-    fn write(msg: &mut IPCMessage, val: &PCIDeviceInfo) {
-        u8::write(msg, &val.bus);
-        u8::write(msg, &val.device);
-
-        u16::write(msg, &val.vendor_id);
-        u16::write(msg, &val.device_id);
-    }
-}
-
 static PCIE_HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
 
-fn get_handle_for_pcie() -> Handle {
+fn get_handle_for_pcie() -> OSResult<Handle> {
     let mut locked = PCIE_HANDLE.lock();
     match *locked {
-        Some(x) => x,
+        Some(x) => Ok(x),
         None => {
-            let handle = crate::ipc::sm::get_service_handle(crate::syscalls::make_tag("pcie"))
-                .unwrap()
-                .0;
+            let handle = crate::ipc::sm::get_service_handle(crate::syscalls::make_tag("pcie"))?.0;
+            if let Err(err) = check_interface_version(handle) {
+                let _ = crate::syscalls::close_handle(handle);
+                return Err(err);
+            }
             *locked = Some(handle);
-            handle
+            Ok(handle)
         }
     }
 }
@@ -6,14 +6,18 @@ use spin::Mutex;
 
 static SM_HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
 
-fn get_handle_for_sm() -> Handle {
+fn get_handle_for_sm() -> OSResult<Handle> {
     let mut locked = SM_HANDLE.lock();
     match *locked {
-        Some(x) => x,
+        Some(x) => Ok(x),
         None => {
-            let handle = syscalls::connect_to_named_port("sm").unwrap();
+            let handle = syscalls::connect_to_named_port("sm")?;
+            if let Err(err) = check_interface_version(handle) {
+                let _ = crate::syscalls::close_handle(handle);
+                return Err(err);
+            }
             *locked = Some(handle);
-            handle
+            Ok(handle)
         }
     }
 }
@@ -0,0 +1,167 @@
+use crate::os_error::OSError;
+use crate::syscalls;
+use common::ipc::*;
+use common::Handle;
+pub use common::ipc_trace::*;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Method names from ipc_definitions, generated along with each client.
+pub struct TraceMethod {
+    pub id: u32,
+    pub name: &'static str,
+    // The sub-interface of the session this method returns, if any.
+    pub session: Option<&'static str>,
+}
+
+pub struct TraceInterface {
+    pub session_name: &'static str,
+    pub methods: &'static [TraceMethod],
+}
+
+// Servers are found by process name, which is the name of their definition.
+const DEFINITIONS: &[(&str, &[TraceInterface])] = &[
+    ("sm", crate::ipc::sm::TRACE_INTERFACES),
+    ("fs", crate::ipc::fs::TRACE_INTERFACES),
+    ("pcie", crate::ipc::pcie::TRACE_INTERFACES),
+];
+
+// Turns trace records into method names. Sub-interface sessions are only recognised from the
+// reply that handed them out, so records should be decoded in order.
+pub struct TraceDecoder {
+    sessions: HashMap<u64, &'static TraceInterface>,
+}
+
+impl TraceDecoder {
+    pub fn new() -> TraceDecoder {
+        TraceDecoder {
+            sessions: HashMap::new(),
+        }
+    }
+
+    // Which interface a record's session speaks, if it's in ipc_definitions.
+    fn interface(&self, record: &IPCTraceRecord) -> Option<&'static TraceInterface> {
+        if let Some(interface) = self.sessions.get(&record.session_id) {
+            return Some(interface);
+        }
+
+        // Otherwise it's the main interface.
+        DEFINITIONS
+            .iter()
+            .find(|(name, _)| *name == record.server_name())
+            .and_then(|(_, interfaces)| interfaces.first())
+    }
+
+    // The interface and method a record is for, as "Interface.method".
+    pub fn method_name(&mut self, record: &IPCTraceRecord) -> Option<String> {
+        let interface = self.interface(record)?;
+        if record.method_id == 0 {
+            return Some(format!(
+                "{}.check_interface_version",
+                interface.session_name
+            ));
+        }
+
+        let method = interface
+            .methods
+            .iter()
+            .find(|method| method.id == record.method_id)?;
+
+        if record.kind == IPCTraceKind::Reply && record.is_ok() {
+            if let Some(session) = method.session {
+                self.learn_session(record, session);
+            }
+        }
+        Some(format!("{}.{}", interface.session_name, method.name))
+    }
+
+    fn learn_session(&mut self, record: &IPCTraceRecord, session_name: &str) {
+        let interface = DEFINITIONS
+            .iter()
+            .find(|(name, _)| *name == record.server_name())
+            .and_then(|(_, interfaces)| {
+                interfaces
+                    .iter()
+                    .find(|interface| interface.session_name == session_name)
+            });
+
+        if let Some(interface) = interface {
+            for translate in &record.translates[..record.translate_count as usize] {
+                if translate.session_id != 0 {
+                    self.sessions.insert(translate.session_id, interface);
+                }
+            }
+        }
+    }
+
+    pub fn describe(&mut self, record: &IPCTraceRecord) -> String {
+        let method = match self.method_name(record) {
+            Some(name) => name,
+            None => format!("method {}", record.method_id),
+        };
+
+        let mut out = format!(
+            "#{} {}ns {:?} {}({}) -> {}({}) session {}: {}, {} bytes",
+            record.sequence,
+            record.timestamp_ns,
+            record.kind,
+            record.sender_name(),
+            record.sender_pid,
+            record.receiver_name(),
+            record.receiver_pid,
+            record.session_id,
+            method,
+            record.size
+        );
+
+        for translate in &record.translates[..record.translate_count as usize] {
+            let _ = match translate.ty {
+                TRANSLATE_TYPE_MOVE_HANDLE | TRANSLATE_TYPE_COPY_HANDLE => {
+                    let verb = if translate.ty == TRANSLATE_TYPE_MOVE_HANDLE {
+                        "move"
+                    } else {
+                        "copy"
+                    };
+                    if translate.session_id != 0 {
+                        write!(out, ", {} session {}", verb, translate.session_id)
+                    } else {
+                        write!(out, ", {} handle", verb)
+                    }
+                }
+                TRANSLATE_TYPE_SEND_BUFFER => write!(out, ", send {} bytes", translate.buffer_size),
+                TRANSLATE_TYPE_RECEIVE_BUFFER => {
+                    write!(out, ", receive {} bytes", translate.buffer_size)
+                }
+                TRANSLATE_TYPE_EXCHANGE_BUFFER => {
+                    write!(out, ", exchange {} bytes", translate.buffer_size)
+                }
+                _ => Ok(()),
+            };
+        }
+
+        if record.latency_ns != 0 {
+            let _ = write!(out, ", took {}ns", record.latency_ns);
+        }
+        if !record.is_ok() {
+            let _ = write!(
+                out,
+                ", failed with {:?}",
+                OSError::from_result_code(record.result())
+            );
+        }
+        out
+    }
+}
+
+// Reads everything that has been traced so far.
+pub fn read_all(capability: Handle) -> Result<Vec<IPCTraceRecord>, OSError> {
+    let mut records = Vec::new();
+    let mut chunk = [IPCTraceRecord::default(); 16];
+    loop {
+        let count = syscalls::ipc_trace_read(capability, &mut chunk)?;
+        records.extend_from_slice(&chunk[..count]);
+        if count < chunk.len() {
+            return Ok(records);
+        }
+    }
+}
@@ -1,4 +1,8 @@
+use crate::ipc::message::IPCMessage;
+use crate::os_error::{Module, OSError, Reason, ResultCode};
 use crate::syscalls;
+use common::ipc::ReceiveResult;
+pub use common::ipc::ReplyToken;
 use common::Handle;
 use std::collections::HashMap;
 use std::sync::{Arc, MutexGuard};
@@ -33,7 +37,11 @@ pub struct ServerImpl {
     handles: Vec<Handle>,
     should_stop: bool,
     sessions: HashMap<Handle, Arc<dyn IPCSession>>,
-    new_session_event: Handle
+    new_session_event: Handle,
+    // Async requests not replied to yet, by session. A session whose client has gone stays open
+    // until these are done, so their replies and buffer accesses don't use a closed handle.
+    in_flight: HashMap<Handle, usize>,
+    closing: Vec<Handle>,
 }
 
 impl std::fmt::Debug for ServerImpl {
@@ -50,7 +58,9 @@ impl ServerImpl {
             handles: vec![port, new_session_event],
             should_stop: false,
             sessions: HashMap::new(),
-            new_session_event: new_session_event
+            new_session_event: new_session_event,
+            in_flight: HashMap::new(),
+            closing: Vec::new(),
         }
     }
 
@@ -59,6 +69,63 @@ impl ServerImpl {
         self.handles.push(h);
         syscalls::signal_event(self.new_session_event).unwrap();
     }
+
+    // Called by generated code before handing a request to an async method, and once it's been
+    // replied to.
+    pub fn begin_async(&mut self, token: ReplyToken) {
+        *self.in_flight.entry(token.session()).or_insert(0) += 1;
+    }
+
+    pub fn end_async(&mut self, token: ReplyToken) {
+        let session = token.session();
+        match self.in_flight.get_mut(&session) {
+            Some(count) if *count > 1 => *count -= 1,
+            _ => {
+                self.in_flight.remove(&session);
+                if let Some(index) = self.closing.iter().position(|h| *h == session) {
+                    self.closing.remove(index);
+                    syscalls::close_handle(session).unwrap();
+                }
+            }
+        }
+    }
+
+    fn close_session(&mut self, session: Handle) {
+        if self.in_flight.contains_key(&session) {
+            self.closing.push(session);
+        } else {
+            syscalls::close_handle(session).unwrap();
+        }
+    }
+
+    // A receive that fails with InvalidHandle doesn't say which handle, so look for sessions that
+    // have gone and forget them. Returns false if the port itself has gone.
+    fn drop_dead_sessions(&mut self) -> bool {
+        let is_dead = |h: Handle| match syscalls::wait_one_timeout(h, 0) {
+            Err(err) => is_kernel_error(&err, Reason::InvalidHandle),
+            Ok(()) => false,
+        };
+
+        if is_dead(self.handles[0]) {
+            return false;
+        }
+
+        // The new session event is ours and never closed, so only sessions need checking.
+        let dead: Vec<Handle> = self.handles[2..]
+            .iter()
+            .copied()
+            .filter(|h| is_dead(*h))
+            .collect();
+        self.handles.retain(|h| !dead.contains(h));
+        for h in dead {
+            self.sessions.remove(&h);
+        }
+        true
+    }
+}
+
+fn is_kernel_error(err: &OSError, reason: Reason) -> bool {
+    OSError::to_result_code(err) == ResultCode::new(Module::Kernel, reason)
 }
 
 pub trait IPCServer {
@@ -66,44 +133,112 @@ pub trait IPCServer {
     fn accept_main_session_in_trait(self: &Arc<Self>) -> Arc<dyn IPCSession>;
 
     fn process_forever(self: Arc<Self>) {
-        loop {
-            let mut ipc_buffer: [u8; 128] = [0; 128];
+        let mut ipc_buffer: [u8; 128] = [0; 128];
+        // A reply waiting in ipc_buffer, to be sent along with the next receive.
+        let mut reply_token = ReplyToken::NONE;
 
+        loop {
             let server = self.get_server_impl();
             /* ugh i hate this but w/e */
-            let (index, mut ipc_buffer) = tokio::task::block_in_place(|| {
+            let (result, new_ipc_buffer) = tokio::task::block_in_place(|| {
                 let copied_handles = server.handles.clone();
                 drop(server);
 
-                let i = syscalls::ipc_receive(&copied_handles, &mut ipc_buffer).unwrap();
-                (i, ipc_buffer)
+                let r =
+                    syscalls::ipc_reply_and_receive(reply_token, &copied_handles, &mut ipc_buffer);
+                (r, ipc_buffer)
             });
+            ipc_buffer = new_ipc_buffer;
+            reply_token = ReplyToken::NONE;
 
             let mut server = self.get_server_impl();
-            if index == 0 {
-                // server handle is signalled!
-                let new_session = syscalls::ipc_accept(server.handles[0]).unwrap();
-                let session = self.accept_main_session_in_trait();
-                server.sessions.insert(new_session, session);
-                server.handles.push(new_session);
-                drop(server);
-            } else if index == 1 {
-                // new session, do nothing
-                syscalls::clear_event(server.new_session_event).unwrap();
-                drop(server);
-            } else {
-                // a client has a message for us!
-                // todo: maybe move message into here?
+            match result {
+                Ok(ReceiveResult::Signalled(0)) => {
+                    // server handle is signalled!
+                    // The client may have timed out in the meantime, leaving nothing to accept.
+                    if let Ok(new_session) = syscalls::ipc_accept(server.handles[0]) {
+                        let session = self.accept_main_session_in_trait();
+                        server.sessions.insert(new_session, session);
+                        server.handles.push(new_session);
+                    }
+                    drop(server);
+                }
+                Ok(ReceiveResult::Signalled(1)) => {
+                    // new session, do nothing
+                    syscalls::clear_event(server.new_session_event).unwrap();
+                    drop(server);
+                }
+                Ok(ReceiveResult::Request(index, token)) => {
+                    // a client has a request for us!
+                    // The request stays in flight until its token is replied to, so an async
+                    // method can finish it later while we go on receiving.
 
-                let handle = server.handles[index];
-                let session = server.sessions[&handle].clone();
+                    // Move the session to the back, so busy clients can't starve later ones.
+                    let handle = server.handles.remove(index);
+                    server.handles.push(handle);
+                    let session = server.sessions[&handle].clone();
 
-                drop(server);
-                session.process(handle, &mut ipc_buffer);
+                    drop(server);
+                    if session.process(token, &mut ipc_buffer) {
+                        reply_token = token;
+                    }
+                }
+                Ok(ReceiveResult::Signalled(index)) => {
+                    // a client has a one-way message for us, which gets no reply.
+                    let handle = server.handles.remove(index);
+                    server.handles.push(handle);
+                    let session = server.sessions[&handle].clone();
+
+                    drop(server);
+                    session.process(ReplyToken::new(handle, 0), &mut ipc_buffer);
+                }
+                Ok(ReceiveResult::SessionClosed(index)) => {
+                    // The client went away, so forget about the session.
+                    let handle = server.handles.remove(index);
+                    server.sessions.remove(&handle);
+                    server.close_session(handle);
+                    drop(server);
+                }
+                Err(err)
+                    if is_kernel_error(&err, Reason::InvalidHandle)
+                        || is_kernel_error(&err, Reason::SessionClosed) =>
+                {
+                    // Either one of our sessions has gone without us seeing it close, or a client
+                    // sent a bad handle and has already had its message bounced.
+                    if !server.drop_dead_sessions() {
+                        println!("IPC server stopping, its port has gone");
+                        break;
+                    }
+                    drop(server);
+                }
+                Err(err)
+                    if [
+                        Reason::TryAgain,
+                        Reason::InvalidMessage,
+                        Reason::InvalidPointer,
+                        Reason::NotAllowed,
+                        Reason::ResourceExhausted,
+                    ]
+                    .iter()
+                    .any(|reason| is_kernel_error(&err, *reason)) =>
+                {
+                    // Either the reply failed, or a message that failed to transfer has already been
+                    // bounced back to its client. Neither happens again on the next receive.
+                    drop(server);
+                }
+                Err(err) => {
+                    // Anything else would just fail again, so don't spin on it.
+                    println!("IPC server stopping: {:?}", err);
+                    break;
+                }
             }
 
             let server = self.get_server_impl();
             if server.should_stop {
+                // Don't leave a client hanging.
+                if reply_token != ReplyToken::NONE {
+                    syscalls::ipc_reply(reply_token, &mut ipc_buffer).unwrap();
+                }
                 break;
             }
         }
@@ -111,5 +246,52 @@ pub trait IPCServer {
 }
 
 pub trait IPCSession: Send + Sync {
-    fn process(self: Arc<Self>, h: Handle, ipc_buffer: &mut [u8]);
+    // Returns true if a reply has been written into ipc_buffer, for the server loop to send.
+    // Otherwise the request stays in flight until something replies to token.
+    // One-way messages get a token of 0, which can't be replied to.
+    fn process(self: Arc<Self>, token: ReplyToken, ipc_buffer: &mut [u8]) -> bool;
+}
+
+// Turns down a request with an error reply instead of calling the method. Returns whether there's a
+// reply in ipc_buffer, like IPCSession::process.
+pub fn reply_error(token: ReplyToken, ipc_buffer: &mut [u8], err: OSError) -> bool {
+    // One-way messages have nobody to reply to.
+    if token.token() == 0 {
+        return false;
+    }
+
+    let mut reply_msg = IPCMessage::new(ipc_buffer);
+    reply_msg.write_error_reply(err);
+    true
+}
+
+// Answers method 0, which every interface reserves for clients to check that they were built from
+// the same definition as the server.
+pub fn reply_interface_version(
+    token: ReplyToken,
+    ipc_buffer: &mut [u8],
+    interface_hash: u64,
+) -> bool {
+    let mut request_msg = IPCMessage::new(ipc_buffer);
+    let client_hash = request_msg.read_header().and_then(|_| {
+        request_msg.read_translates();
+        request_msg.read::<u64>()
+    });
+
+    match client_hash {
+        Ok(hash) if hash == interface_hash => {
+            if token.token() == 0 {
+                return false;
+            }
+            let mut reply_msg = IPCMessage::new(ipc_buffer);
+            reply_msg.write_reply(());
+            true
+        }
+        Ok(_) => reply_error(
+            token,
+            ipc_buffer,
+            OSError::new(Module::LibProcess, Reason::VersionMismatch),
+        ),
+        Err(err) => reply_error(token, ipc_buffer, err),
+    }
 }
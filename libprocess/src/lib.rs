@@ -3,6 +3,8 @@
 #![feature(thread_local)]
 
 extern crate alloc;
+// Lets derived code name this crate as `process`, same as everywhere else.
+extern crate self as process;
 
 //pub mod print;
 pub mod ipc_server;
@@ -13,6 +15,8 @@ pub mod syscalls;
 #[cfg(not(target_os = "francium"))]
 #[path = "syscalls_emulated.rs"]
 pub mod syscalls;
+#[cfg(not(target_os = "francium"))]
+mod emulation;
 
 //pub mod allocator;
 pub mod ipc;
@@ -1,11 +1,31 @@
-use crate::os_error::{OSError, ResultCode, RESULT_OK};
+// Host builds run everything in one process, with crate::emulation standing in for the kernel.
+// Anything that needs real hardware fails with NotImplemented.
+
+use crate::emulation;
+use crate::os_error::{Module, OSError, Reason, ResultCode};
+pub use common::constants::TIMEOUT_INFINITE;
+use common::event::EventMode;
+use common::introspection::ThreadInfo;
+use common::ipc::{ReceiveResult, ReplyToken};
+use common::ipc_trace::{IPCTraceControl, IPCTraceRecord};
 use common::system_info::*;
-use common::{Handle, INVALID_HANDLE};
+use common::{Handle, HandleRights};
 use common::{MapType, PagePermission};
 use core::cmp::min;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::cell::Cell;
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+fn not_implemented() -> OSError {
+    OSError::new(Module::Kernel, Reason::NotImplemented)
+}
 
 pub fn print(s: &str) {
-    todo!();
+    let mut stdout = std::io::stdout();
+    stdout.write_all(s.as_bytes()).unwrap();
+    stdout.flush().unwrap();
 }
 
 pub fn make_tag(s: &str) -> u64 {
@@ -18,117 +38,313 @@ pub fn make_tag(s: &str) -> u64 {
 }
 
 pub fn create_port(s: &str) -> Result<Handle, OSError> {
-    todo!();
+    emulation::create_port(make_tag(s)).map_err(OSError::from_result_code)
 }
 
 pub fn connect_to_named_port(s: &str) -> Result<Handle, OSError> {
-    todo!();
+    connect_to_named_port_timeout(s, TIMEOUT_INFINITE)
+}
+
+// The timeout covers waiting for the port to be created as well as waiting to be accepted.
+pub fn connect_to_named_port_timeout(s: &str, timeout_ns: u64) -> Result<Handle, OSError> {
+    emulation::connect_to_named_port(make_tag(s), timeout_ns).map_err(OSError::from_result_code)
 }
 
 pub fn connect_to_port_handle(h: Handle) -> Result<Handle, OSError> {
-    todo!();
+    connect_to_port_handle_timeout(h, TIMEOUT_INFINITE)
+}
+
+pub fn connect_to_port_handle_timeout(h: Handle, timeout_ns: u64) -> Result<Handle, OSError> {
+    emulation::connect_to_port_handle(h, timeout_ns).map_err(OSError::from_result_code)
 }
 
 pub fn close_handle(h: Handle) -> Result<(), OSError> {
-    todo!();
+    emulation::close_handle(h).map_err(OSError::from_result_code)
 }
 
+// The new handle gets the intersection of the old handle's rights and `rights`.
+pub fn duplicate_handle(h: Handle, rights: HandleRights) -> Result<Handle, OSError> {
+    emulation::duplicate_handle(h, rights).map_err(OSError::from_result_code)
+}
+
+// There's only the one process, so this takes every emulated server down with it.
 pub fn exit_process() -> ! {
-    todo!();
+    std::process::exit(0);
 }
 
 pub fn ipc_request(session_handle: Handle, ipc_buffer: &mut [u8; 128]) -> Result<(), OSError> {
-    todo!();
+    emulation::ipc_request(session_handle, ipc_buffer.as_mut_ptr() as usize)
+        .map_err(OSError::from_result_code)
+}
+
+pub fn ipc_reply(reply_token: ReplyToken, ipc_buffer: &mut [u8; 128]) -> Result<(), OSError> {
+    emulation::ipc_reply(reply_token, ipc_buffer.as_mut_ptr() as usize)
+        .map_err(OSError::from_result_code)
+}
+
+pub fn ipc_receive(
+    sessions: &[Handle],
+    ipc_buffer: &mut [u8; 128],
+) -> Result<ReceiveResult, OSError> {
+    ipc_receive_timeout(sessions, ipc_buffer, TIMEOUT_INFINITE)
+}
+
+pub fn ipc_receive_timeout(
+    sessions: &[Handle],
+    ipc_buffer: &mut [u8; 128],
+    timeout_ns: u64,
+) -> Result<ReceiveResult, OSError> {
+    emulation::ipc_receive(sessions, ipc_buffer.as_mut_ptr() as usize, timeout_ns)
+        .map_err(OSError::from_result_code)
 }
 
-pub fn ipc_reply(session_handle: Handle, ipc_buffer: &mut [u8; 128]) -> Result<(), OSError> {
-    todo!();
+// Send a one-way message, from either end of a session. The peer gets it from ipc_receive.
+pub fn ipc_send(session_handle: Handle, ipc_buffer: &mut [u8; 128]) -> Result<(), OSError> {
+    emulation::ipc_send(session_handle, ipc_buffer.as_mut_ptr() as usize)
+        .map_err(OSError::from_result_code)
 }
 
-pub fn ipc_receive(sessions: &[Handle], ipc_buffer: &mut [u8; 128]) -> Result<usize, OSError> {
-    todo!();
+// Send a request without waiting for the reply. The returned request handle, and event_handle
+// if it isn't INVALID_HANDLE, are signalled once the reply is in ipc_buffer.
+// Safety: ipc_buffer must stay valid until the request completes, even if the handle is closed.
+pub unsafe fn ipc_request_async(
+    session_handle: Handle,
+    ipc_buffer: *mut [u8; 128],
+    event_handle: Handle,
+) -> Result<Handle, OSError> {
+    emulation::ipc_request_async(session_handle, ipc_buffer as usize, event_handle)
+        .map_err(OSError::from_result_code)
+}
+
+// Returns None if the request hasn't completed yet.
+pub fn ipc_request_result(request_handle: Handle) -> Option<Result<(), OSError>> {
+    match emulation::ipc_request_result(request_handle) {
+        Ok(()) => Some(Ok(())),
+        Err(res) if res == ResultCode::new(Module::Kernel, Reason::TryAgain) => None,
+        Err(res) => Some(Err(OSError::from_result_code(res))),
+    }
+}
+
+// Reply to reply_token (unless it is ReplyToken::NONE), then receive into the same buffer.
+pub fn ipc_reply_and_receive(
+    reply_token: ReplyToken,
+    sessions: &[Handle],
+    ipc_buffer: &mut [u8; 128],
+) -> Result<ReceiveResult, OSError> {
+    emulation::ipc_reply_and_receive(
+        reply_token,
+        sessions,
+        ipc_buffer.as_mut_ptr() as usize,
+        TIMEOUT_INFINITE,
+    )
+    .map_err(OSError::from_result_code)
 }
 
 pub fn ipc_accept(session_handle: Handle) -> Result<Handle, OSError> {
-    todo!();
+    emulation::ipc_accept(session_handle).map_err(OSError::from_result_code)
+}
+
+// Copy from a buffer the client sent with the request that reply_token names.
+pub fn ipc_read_buffer(
+    reply_token: ReplyToken,
+    index: usize,
+    buffer: &mut [u8],
+) -> Result<(), OSError> {
+    emulation::ipc_read_buffer(reply_token, index, buffer).map_err(OSError::from_result_code)
+}
+
+// Copy into a buffer the client sent with the request that reply_token names.
+pub fn ipc_write_buffer(
+    reply_token: ReplyToken,
+    index: usize,
+    buffer: &[u8],
+) -> Result<(), OSError> {
+    emulation::ipc_write_buffer(reply_token, index, buffer).map_err(OSError::from_result_code)
+}
+
+// There's no kernel to watch the messages go by.
+pub fn ipc_trace_control(_capability: Handle, _op: IPCTraceControl) -> Result<(), OSError> {
+    Err(not_implemented())
+}
+
+pub fn ipc_trace_read(
+    _capability: Handle,
+    _records: &mut [IPCTraceRecord],
+) -> Result<usize, OSError> {
+    Err(not_implemented())
+}
+
+// The host's threads aren't ours to look at.
+pub fn list_threads(_capability: Handle, _threads: &mut [ThreadInfo]) -> Result<usize, OSError> {
+    Err(not_implemented())
 }
 
 pub fn get_process_id() -> u64 {
-    todo!();
+    std::process::id() as u64
 }
 
+// Memory comes from the host allocator, is always readable and writable, and is never freed (there
+// is no unmap to free it with).
 pub fn map_memory(
     address: usize,
     length: usize,
-    permission: PagePermission,
+    _permission: PagePermission,
 ) -> Result<usize, OSError> {
-    todo!();
+    if address != 0 {
+        return Err(not_implemented());
+    }
+
+    let layout = match std::alloc::Layout::from_size_align(length.max(1), 0x1000) {
+        Ok(layout) => layout,
+        Err(_) => return Err(OSError::new(Module::Kernel, Reason::InvalidArgument)),
+    };
+
+    let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        Err(OSError::new(Module::Kernel, Reason::ResourceExhausted))
+    } else {
+        Ok(ptr as usize)
+    }
 }
 
 pub fn sleep_ns(ns: u64) {
-    todo!();
+    std::thread::sleep(Duration::from_nanos(ns));
+}
+
+thread_local! {
+    static THREAD_POINTER: Cell<usize> = Cell::new(0);
+    static THREAD_ID: u64 = {
+        static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+        NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed)
+    };
 }
 
-pub use common::constants::{GET_FS, SET_FS};
+// The host already has thread locals of its own, so the thread pointer is only remembered.
+pub fn set_thread_pointer(addr: usize) -> Result<(), OSError> {
+    THREAD_POINTER.with(|pointer| pointer.set(addr));
+    Ok(())
+}
 
-pub fn bodge(key: u32, addr: usize) -> usize {
-    todo!();
+pub fn get_thread_pointer() -> usize {
+    THREAD_POINTER.with(|pointer| pointer.get())
 }
 
 pub fn get_thread_id() -> u64 {
-    todo!();
+    THREAD_ID.with(|id| *id)
+}
+
+pub fn futex_wait(addr: &AtomicU32, expected: u32, timeout_ns: u64) -> Result<(), OSError> {
+    emulation::futex_wait(addr, expected, timeout_ns).map_err(OSError::from_result_code)
+}
+
+pub fn futex_wake(addr: &AtomicU32, count: usize) -> Result<usize, OSError> {
+    emulation::futex_wake(addr, count).map_err(OSError::from_result_code)
+}
+
+pub fn futex_requeue(
+    addr: &AtomicU32,
+    expected: u32,
+    wake_count: usize,
+    target_addr: &AtomicU32,
+    requeue_count: usize,
+) -> Result<usize, OSError> {
+    emulation::futex_requeue(addr, expected, wake_count, target_addr, requeue_count)
+        .map_err(OSError::from_result_code)
 }
 
 pub fn map_device_memory(
-    phys_addr: usize,
-    virt_addr: usize,
-    length: usize,
-    ty: MapType,
-    permission: PagePermission,
+    _phys_addr: usize,
+    _virt_addr: usize,
+    _length: usize,
+    _ty: MapType,
+    _permission: PagePermission,
 ) -> Result<usize, OSError> {
-    todo!();
+    Err(not_implemented())
 }
 
-pub fn get_system_info(ty: SystemInfoType, index: usize) -> Result<SystemInfo, OSError> {
-    todo!();
+// Only the questions the host can answer. Uptime is since the first time anything asked.
+pub fn get_system_info(ty: SystemInfoType, _index: usize) -> Result<SystemInfo, OSError> {
+    match ty {
+        SystemInfoType::CpuCount => Ok(SystemInfo::CpuCount(
+            std::thread::available_parallelism().map_or(1, |n| n.get()),
+        )),
+        SystemInfoType::Uptime => Ok(SystemInfo::Uptime(get_system_tick())),
+        _ => Err(not_implemented()),
+    }
 }
 
 pub fn get_system_tick() -> u64 {
-    todo!();
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_nanos() as u64
 }
 
+// There's no physical memory to speak of, so addresses map to themselves.
 pub fn query_physical_address(virt: usize) -> Result<usize, OSError> {
-    todo!();
+    Ok(virt)
 }
 
 pub fn create_event() -> Result<Handle, OSError> {
-    todo!();
+    create_event_with_mode(EventMode::AutoReset)
+}
+
+pub fn create_event_with_mode(mode: EventMode) -> Result<Handle, OSError> {
+    emulation::create_event(mode).map_err(OSError::from_result_code)
 }
 
 pub fn bind_interrupt(handle: Handle, index: usize) -> Result<(), OSError> {
-    todo!();
+    emulation::bind_interrupt(handle, index).map_err(OSError::from_result_code)
 }
 
 pub fn unbind_interrupt(handle: Handle, index: usize) -> Result<(), OSError> {
-    todo!();
+    emulation::unbind_interrupt(handle, index).map_err(OSError::from_result_code)
 }
 
 pub fn wait_one(handle: Handle) -> Result<(), OSError> {
-    todo!();
+    wait_one_timeout(handle, TIMEOUT_INFINITE)
+}
+
+pub fn wait_one_timeout(handle: Handle, timeout_ns: u64) -> Result<(), OSError> {
+    wait_many_timeout(&[handle], timeout_ns).map(|_| ())
 }
 
 pub fn signal_event(handle: Handle) -> Result<(), OSError> {
-    todo!();
+    emulation::signal_event(handle).map_err(OSError::from_result_code)
 }
 
 pub fn clear_event(handle: Handle) -> Result<(), OSError> {
-    todo!();
+    emulation::clear_event(handle).map_err(OSError::from_result_code)
+}
+
+pub fn create_timer() -> Result<Handle, OSError> {
+    emulation::create_timer().map_err(OSError::from_result_code)
+}
+
+// Fires once after initial_ns, then every period_ns if it's non-zero.
+pub fn set_timer(handle: Handle, initial_ns: u64, period_ns: u64) -> Result<(), OSError> {
+    emulation::set_timer(handle, initial_ns, period_ns).map_err(OSError::from_result_code)
+}
+
+pub fn cancel_timer(handle: Handle) -> Result<(), OSError> {
+    emulation::cancel_timer(handle).map_err(OSError::from_result_code)
+}
+
+pub fn create_semaphore(initial_count: usize, max_count: usize) -> Result<Handle, OSError> {
+    emulation::create_semaphore(initial_count, max_count).map_err(OSError::from_result_code)
+}
+
+// Returns the count from before the release.
+pub fn release_semaphore(handle: Handle, release_count: usize) -> Result<usize, OSError> {
+    emulation::release_semaphore(handle, release_count).map_err(OSError::from_result_code)
 }
 
 pub fn wait_many(handles: &[Handle]) -> Result<usize, OSError> {
-    todo!();
+    wait_many_timeout(handles, TIMEOUT_INFINITE)
+}
+
+pub fn wait_many_timeout(handles: &[Handle], timeout_ns: u64) -> Result<usize, OSError> {
+    emulation::wait_many(handles, timeout_ns).map_err(OSError::from_result_code)
 }
 
 pub fn create_session() -> Result<(Handle, Handle), OSError> {
-    todo!();
+    emulation::create_session().map_err(OSError::from_result_code)
 }
@@ -1,71 +1,17 @@
-use crate::os_error::{OSError, ResultCode, RESULT_OK};
+use crate::os_error::{Module, OSError, Reason, ResultCode, RESULT_OK};
+pub use common::constants::TIMEOUT_INFINITE;
+use common::event::EventMode;
+use common::introspection::ThreadInfo;
+use common::ipc::{ReceiveResult, ReplyToken};
+use common::ipc_trace::{IPCTraceControl, IPCTraceRecord};
 use common::system_info::*;
-use common::{Handle, INVALID_HANDLE};
+use common::{Handle, HandleRights, INVALID_HANDLE};
 use common::{MapType, PagePermission};
 use core::cmp::min;
+use core::sync::atomic::AtomicU32;
 
-extern "C" {
-    pub fn syscall_debug_output(s: *const u8, len: usize) -> ResultCode;
-    pub fn syscall_create_port(tag: u64, handle_out: *mut Handle) -> ResultCode;
-    pub fn syscall_connect_to_named_port(tag: u64, handle_out: *mut Handle) -> ResultCode;
-    pub fn syscall_exit_process() -> !;
-    pub fn syscall_close_handle(h: Handle) -> ResultCode;
-    pub fn syscall_ipc_request(session_handle: Handle, ipc_buffer: *mut u8) -> ResultCode;
-    pub fn syscall_ipc_reply(session_handle: Handle, ipc_buffer: *mut u8) -> ResultCode;
-    pub fn syscall_ipc_receive(
-        sessions: *const Handle,
-        num_sessions: usize,
-        ipc_buffer: *mut u8,
-        index_out: *mut usize,
-    ) -> ResultCode;
-    pub fn syscall_ipc_accept(session_handle: Handle, handle_out: *mut Handle) -> ResultCode;
-    pub fn syscall_get_process_id() -> u64;
-    pub fn syscall_connect_to_port_handle(h: u32, handle_out: *mut Handle) -> ResultCode;
-    pub fn syscall_map_memory(
-        address: usize,
-        length: usize,
-        permission: u64,
-        address_out: *mut usize,
-    ) -> ResultCode;
-    pub fn syscall_sleep_ns(ns: u64);
-    pub fn syscall_bodge(key: u32, addr: usize) -> usize;
-    pub fn syscall_get_thread_id() -> u64;
-    pub fn syscall_map_device_memory(
-        phys_addr: usize,
-        virt_addr: usize,
-        length: usize,
-        map_type: usize,
-        permission: u64,
-        address_out: *mut usize,
-    ) -> ResultCode;
-
-    pub fn syscall_get_system_info(
-        ty: usize,
-        index: usize,
-        info_out: *mut SystemInfo,
-    ) -> ResultCode;
-
-    pub fn syscall_get_system_tick() -> u64;
-    pub fn syscall_query_physical_address(virt_addr: usize, phys_out: *mut usize) -> ResultCode;
-
-    pub fn syscall_create_event(handle_out: *mut Handle) -> ResultCode;
-    pub fn syscall_bind_interrupt(handle: Handle, index: usize) -> ResultCode;
-    pub fn syscall_unbind_interrupt(handle: Handle, index: usize) -> ResultCode;
-    pub fn syscall_wait_one(handle: Handle) -> ResultCode;
-    pub fn syscall_signal_event(handle: Handle) -> ResultCode;
-    pub fn syscall_clear_event(handle: Handle) -> ResultCode;
-
-    pub fn syscall_wait_many(
-        sessions: *const Handle,
-        num_sessions: usize,
-        index_out: *mut usize,
-    ) -> ResultCode;
-
-    pub fn syscall_create_session(
-        server_handle: *mut Handle,
-        client_handle_out: *mut Handle,
-    ) -> ResultCode;
-}
+// syscall_ for each syscall in syscalls.toml.
+include!(concat!(env!("OUT_DIR"), "/syscalls_extern.rs"));
 
 pub fn print(s: &str) {
     unsafe {
@@ -95,9 +41,14 @@ pub fn create_port(s: &str) -> Result<Handle, OSError> {
 }
 
 pub fn connect_to_named_port(s: &str) -> Result<Handle, OSError> {
+    connect_to_named_port_timeout(s, TIMEOUT_INFINITE)
+}
+
+// The timeout covers waiting for the port to be created as well as waiting to be accepted.
+pub fn connect_to_named_port_timeout(s: &str, timeout_ns: u64) -> Result<Handle, OSError> {
     let mut handle_out = INVALID_HANDLE;
     unsafe {
-        let res = syscall_connect_to_named_port(make_tag(s), &mut handle_out);
+        let res = syscall_connect_to_named_port(make_tag(s), timeout_ns, &mut handle_out);
         if res == RESULT_OK {
             Ok(handle_out)
         } else {
@@ -107,9 +58,13 @@ pub fn connect_to_named_port(s: &str) -> Result<Handle, OSError> {
 }
 
 pub fn connect_to_port_handle(h: Handle) -> Result<Handle, OSError> {
+    connect_to_port_handle_timeout(h, TIMEOUT_INFINITE)
+}
+
+pub fn connect_to_port_handle_timeout(h: Handle, timeout_ns: u64) -> Result<Handle, OSError> {
     let mut handle_out = INVALID_HANDLE;
     unsafe {
-        let res = syscall_connect_to_port_handle(h.0, &mut handle_out);
+        let res = syscall_connect_to_port_handle(h, timeout_ns, &mut handle_out);
         if res == RESULT_OK {
             Ok(handle_out)
         } else {
@@ -129,6 +84,19 @@ pub fn close_handle(h: Handle) -> Result<(), OSError> {
     }
 }
 
+// The new handle gets the intersection of the old handle's rights and `rights`.
+pub fn duplicate_handle(h: Handle, rights: HandleRights) -> Result<Handle, OSError> {
+    unsafe {
+        let mut handle_out: Handle = INVALID_HANDLE;
+        let res = syscall_duplicate_handle(h, rights.bits(), &mut handle_out);
+        if res == RESULT_OK {
+            Ok(handle_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
 pub fn exit_process() -> ! {
     unsafe {
         syscall_exit_process();
@@ -146,9 +114,9 @@ pub fn ipc_request(session_handle: Handle, ipc_buffer: &mut [u8; 128]) -> Result
     }
 }
 
-pub fn ipc_reply(session_handle: Handle, ipc_buffer: &mut [u8; 128]) -> Result<(), OSError> {
+pub fn ipc_reply(reply_token: ReplyToken, ipc_buffer: &mut [u8; 128]) -> Result<(), OSError> {
     unsafe {
-        let res = syscall_ipc_reply(session_handle, ipc_buffer.as_mut_ptr());
+        let res = syscall_ipc_reply(reply_token, ipc_buffer.as_mut_ptr());
         if res == RESULT_OK {
             Ok(())
         } else {
@@ -157,23 +125,124 @@ pub fn ipc_reply(session_handle: Handle, ipc_buffer: &mut [u8; 128]) -> Result<(
     }
 }
 
-pub fn ipc_receive(sessions: &[Handle], ipc_buffer: &mut [u8; 128]) -> Result<usize, OSError> {
+pub fn ipc_receive(
+    sessions: &[Handle],
+    ipc_buffer: &mut [u8; 128],
+) -> Result<ReceiveResult, OSError> {
+    ipc_receive_timeout(sessions, ipc_buffer, TIMEOUT_INFINITE)
+}
+
+pub fn ipc_receive_timeout(
+    sessions: &[Handle],
+    ipc_buffer: &mut [u8; 128],
+    timeout_ns: u64,
+) -> Result<ReceiveResult, OSError> {
     unsafe {
-        let mut index_out: usize = 0;
+        let mut out: usize = 0;
         let res = syscall_ipc_receive(
             sessions.as_ptr(),
             sessions.len(),
             ipc_buffer.as_mut_ptr(),
-            &mut index_out,
+            timeout_ns,
+            &mut out,
         );
+        receive_result(sessions, res, out)
+    }
+}
+
+// The kernel returns the index in the low 32 bits, and the request's token (if any) in the high 32.
+fn receive_result(
+    sessions: &[Handle],
+    res: ResultCode,
+    out: usize,
+) -> Result<ReceiveResult, OSError> {
+    let index = out & 0xffffffff;
+    let token = (out >> 32) as u32;
+
+    if res == RESULT_OK {
+        if token != 0 {
+            Ok(ReceiveResult::Request(
+                index,
+                ReplyToken::new(sessions[index], token),
+            ))
+        } else {
+            Ok(ReceiveResult::Signalled(index))
+        }
+    } else if res == ResultCode::new(Module::Kernel, Reason::SessionClosed) {
+        Ok(ReceiveResult::SessionClosed(index))
+    } else {
+        Err(OSError::from_result_code(res))
+    }
+}
+
+// Send a one-way message, from either end of a session. The peer gets it from ipc_receive.
+pub fn ipc_send(session_handle: Handle, ipc_buffer: &mut [u8; 128]) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_ipc_send(session_handle, ipc_buffer.as_mut_ptr());
         if res == RESULT_OK {
-            Ok(index_out)
+            Ok(())
         } else {
             Err(OSError::from_result_code(res))
         }
     }
 }
 
+// Send a request without waiting for the reply. The returned request handle, and event_handle
+// if it isn't INVALID_HANDLE, are signalled once the reply is in ipc_buffer.
+// Safety: ipc_buffer must stay valid until the request completes, even if the handle is closed.
+pub unsafe fn ipc_request_async(
+    session_handle: Handle,
+    ipc_buffer: *mut [u8; 128],
+    event_handle: Handle,
+) -> Result<Handle, OSError> {
+    let mut handle_out: Handle = INVALID_HANDLE;
+    let res = syscall_ipc_request_async(
+        session_handle,
+        ipc_buffer as *mut u8,
+        event_handle,
+        &mut handle_out,
+    );
+    if res == RESULT_OK {
+        Ok(handle_out)
+    } else {
+        Err(OSError::from_result_code(res))
+    }
+}
+
+// Returns None if the request hasn't completed yet.
+pub fn ipc_request_result(request_handle: Handle) -> Option<Result<(), OSError>> {
+    unsafe {
+        let res = syscall_ipc_request_result(request_handle);
+        if res == RESULT_OK {
+            Some(Ok(()))
+        } else if res == ResultCode::new(Module::Kernel, Reason::TryAgain) {
+            None
+        } else {
+            Some(Err(OSError::from_result_code(res)))
+        }
+    }
+}
+
+// Reply to reply_token (unless it is ReplyToken::NONE), then receive into the same buffer.
+pub fn ipc_reply_and_receive(
+    reply_token: ReplyToken,
+    sessions: &[Handle],
+    ipc_buffer: &mut [u8; 128],
+) -> Result<ReceiveResult, OSError> {
+    unsafe {
+        let mut out: usize = 0;
+        let res = syscall_ipc_reply_and_receive(
+            reply_token,
+            sessions.as_ptr(),
+            sessions.len(),
+            ipc_buffer.as_mut_ptr(),
+            TIMEOUT_INFINITE,
+            &mut out,
+        );
+        receive_result(sessions, res, out)
+    }
+}
+
 pub fn ipc_accept(session_handle: Handle) -> Result<Handle, OSError> {
     unsafe {
         let mut handle_out: Handle = INVALID_HANDLE;
@@ -186,6 +255,91 @@ pub fn ipc_accept(session_handle: Handle) -> Result<Handle, OSError> {
     }
 }
 
+// Copy from a buffer the client sent with the request that reply_token names.
+pub fn ipc_read_buffer(
+    reply_token: ReplyToken,
+    index: usize,
+    buffer: &mut [u8],
+) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_ipc_read_buffer(reply_token, index, buffer.as_mut_ptr(), buffer.len());
+        if res == RESULT_OK {
+            Ok(())
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+// Copy into a buffer the client sent with the request that reply_token names.
+pub fn ipc_write_buffer(
+    reply_token: ReplyToken,
+    index: usize,
+    buffer: &[u8],
+) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_ipc_write_buffer(reply_token, index, buffer.as_ptr(), buffer.len());
+        if res == RESULT_OK {
+            Ok(())
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+// Turn IPC tracing on or off, or throw away what's been traced. capability needs the INTROSPECT
+// right, see INTROSPECTION_HANDLE.
+pub fn ipc_trace_control(capability: Handle, op: IPCTraceControl) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_ipc_trace_control(capability, op.into());
+        if res == RESULT_OK {
+            Ok(())
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+// Take the oldest IPC trace records, up to records.len() of them. Returns how many there were.
+pub fn ipc_trace_read(
+    capability: Handle,
+    records: &mut [IPCTraceRecord],
+) -> Result<usize, OSError> {
+    unsafe {
+        let mut count_out: usize = 0;
+        let res = syscall_ipc_trace_read(
+            capability,
+            records.as_mut_ptr(),
+            records.len(),
+            &mut count_out,
+        );
+        if res == RESULT_OK {
+            Ok(count_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+// Fill threads with what every thread is up to. Returns how many threads there are, which may be
+// more than fit. capability needs the INTROSPECT right.
+pub fn list_threads(capability: Handle, threads: &mut [ThreadInfo]) -> Result<usize, OSError> {
+    unsafe {
+        let mut count_out: usize = 0;
+        let res = syscall_list_threads(
+            capability,
+            threads.as_mut_ptr(),
+            threads.len(),
+            &mut count_out,
+        );
+        if res == RESULT_OK {
+            Ok(count_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
 pub fn get_process_id() -> u64 {
     unsafe { syscall_get_process_id() }
 }
@@ -212,16 +366,74 @@ pub fn sleep_ns(ns: u64) {
     }
 }
 
-pub use common::constants::{GET_FS, SET_FS};
+// The thread pointer is fs_base on x86_64 and tpidr_el0 on aarch64.
+pub fn set_thread_pointer(addr: usize) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_set_thread_pointer(addr);
+        if res == RESULT_OK {
+            Ok(())
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
 
-pub fn bodge(key: u32, addr: usize) -> usize {
-    unsafe { syscall_bodge(key, addr) }
+pub fn get_thread_pointer() -> usize {
+    unsafe { syscall_get_thread_pointer() }
 }
 
 pub fn get_thread_id() -> u64 {
     unsafe { syscall_get_process_id() }
 }
 
+pub fn futex_wait(addr: &AtomicU32, expected: u32, timeout_ns: u64) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_futex_wait(addr, expected, timeout_ns);
+        if res == RESULT_OK {
+            Ok(())
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+pub fn futex_wake(addr: &AtomicU32, count: usize) -> Result<usize, OSError> {
+    unsafe {
+        let mut woken_out: usize = 0;
+        let res = syscall_futex_wake_count(addr, count, &mut woken_out);
+        if res == RESULT_OK {
+            Ok(woken_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+pub fn futex_requeue(
+    addr: &AtomicU32,
+    expected: u32,
+    wake_count: usize,
+    target_addr: &AtomicU32,
+    requeue_count: usize,
+) -> Result<usize, OSError> {
+    unsafe {
+        let mut woken_out: usize = 0;
+        let res = syscall_futex_requeue(
+            addr,
+            expected,
+            wake_count,
+            target_addr,
+            requeue_count,
+            &mut woken_out,
+        );
+        if res == RESULT_OK {
+            Ok(woken_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
 pub fn map_device_memory(
     phys_addr: usize,
     virt_addr: usize,
@@ -276,9 +488,13 @@ pub fn query_physical_address(virt: usize) -> Result<usize, OSError> {
 }
 
 pub fn create_event() -> Result<Handle, OSError> {
+    create_event_with_mode(EventMode::AutoReset)
+}
+
+pub fn create_event_with_mode(mode: EventMode) -> Result<Handle, OSError> {
     unsafe {
         let mut handle_out: Handle = INVALID_HANDLE;
-        let res = syscall_create_event(&mut handle_out);
+        let res = syscall_create_event(mode.into(), &mut handle_out);
         if res == RESULT_OK {
             Ok(handle_out)
         } else {
@@ -310,8 +526,12 @@ pub fn unbind_interrupt(handle: Handle, index: usize) -> Result<(), OSError> {
 }
 
 pub fn wait_one(handle: Handle) -> Result<(), OSError> {
+    wait_one_timeout(handle, TIMEOUT_INFINITE)
+}
+
+pub fn wait_one_timeout(handle: Handle, timeout_ns: u64) -> Result<(), OSError> {
     unsafe {
-        let res = syscall_wait_one(handle);
+        let res = syscall_wait_one(handle, timeout_ns);
         if res == RESULT_OK {
             Ok(())
         } else {
@@ -342,10 +562,74 @@ pub fn clear_event(handle: Handle) -> Result<(), OSError> {
     }
 }
 
+pub fn create_timer() -> Result<Handle, OSError> {
+    unsafe {
+        let mut handle_out: Handle = INVALID_HANDLE;
+        let res = syscall_create_timer(&mut handle_out);
+        if res == RESULT_OK {
+            Ok(handle_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+// Fires once after initial_ns, then every period_ns if it's non-zero.
+pub fn set_timer(handle: Handle, initial_ns: u64, period_ns: u64) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_set_timer(handle, initial_ns, period_ns);
+        if res == RESULT_OK {
+            Ok(())
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+pub fn cancel_timer(handle: Handle) -> Result<(), OSError> {
+    unsafe {
+        let res = syscall_cancel_timer(handle);
+        if res == RESULT_OK {
+            Ok(())
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+pub fn create_semaphore(initial_count: usize, max_count: usize) -> Result<Handle, OSError> {
+    unsafe {
+        let mut handle_out: Handle = INVALID_HANDLE;
+        let res = syscall_create_semaphore(initial_count, max_count, &mut handle_out);
+        if res == RESULT_OK {
+            Ok(handle_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
+// Returns the count from before the release.
+pub fn release_semaphore(handle: Handle, release_count: usize) -> Result<usize, OSError> {
+    unsafe {
+        let mut previous_count_out: usize = 0;
+        let res = syscall_release_semaphore(handle, release_count, &mut previous_count_out);
+        if res == RESULT_OK {
+            Ok(previous_count_out)
+        } else {
+            Err(OSError::from_result_code(res))
+        }
+    }
+}
+
 pub fn wait_many(handles: &[Handle]) -> Result<usize, OSError> {
+    wait_many_timeout(handles, TIMEOUT_INFINITE)
+}
+
+pub fn wait_many_timeout(handles: &[Handle], timeout_ns: u64) -> Result<usize, OSError> {
     unsafe {
         let mut index_out: usize = 0;
-        let res = syscall_wait_many(handles.as_ptr(), handles.len(), &mut index_out);
+        let res = syscall_wait_many(handles.as_ptr(), handles.len(), timeout_ns, &mut index_out);
         if res == RESULT_OK {
             Ok(index_out)
         } else {
@@ -369,7 +653,19 @@ pub fn create_session() -> Result<(Handle, Handle), OSError> {
 
 use core::arch::global_asm;
 #[cfg(target_arch = "x86_64")]
-global_asm!(include_str!("asm/x86_64_syscalls.s"));
+global_asm!(include_str!(concat!(env!("OUT_DIR"), "/syscalls_x86_64.s")));
+
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!(concat!(
+    env!("OUT_DIR"),
+    "/syscalls_aarch64.s"
+)));
 
+// Reading tpidr_el0 directly is cheaper than get_thread_pointer.
 #[cfg(target_arch = "aarch64")]
-global_asm!(include_str!("asm/aarch64_syscalls.s"));
+global_asm!(
+    ".global get_tpidr_el0_asm
+get_tpidr_el0_asm:
+mrs x0, tpidr_el0
+ret"
+);
@@ -0,0 +1,229 @@
+// These run against crate::emulation, which shares one handle table between every thread. Named
+// ports are global, so each test uses a tag of its own.
+
+use common::ipc::{ReceiveResult, TranslateCopyHandle, TranslateMoveHandle};
+use common::HandleRights;
+use process::ipc::message::IPCMessage;
+use process::os_error::{Module, OSError, Reason, ResultCode};
+use process::syscalls;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const TIMEOUT_1MS: u64 = 1_000_000;
+
+fn expect_error<T: Debug>(res: Result<T, OSError>, reason: Reason) {
+    assert_eq!(
+        OSError::to_result_code(&res.unwrap_err()),
+        ResultCode::new(Module::Kernel, reason)
+    );
+}
+
+#[test]
+fn port_session_round_trip() {
+    let port = syscalls::create_port("t_rt").unwrap();
+
+    let server = thread::spawn(move || {
+        let mut buffer = [0u8; 128];
+        match syscalls::ipc_receive(&[port], &mut buffer).unwrap() {
+            ReceiveResult::Signalled(0) => {}
+            other => panic!("expected a connection, got {:?}", other),
+        }
+        let session = syscalls::ipc_accept(port).unwrap();
+
+        let token = match syscalls::ipc_receive(&[session], &mut buffer).unwrap() {
+            ReceiveResult::Request(0, token) => token,
+            other => panic!("expected a request, got {:?}", other),
+        };
+
+        let mut request = IPCMessage::new(&mut buffer);
+        request.read_header().unwrap();
+        assert_eq!(request.header.id, 7);
+        let value: u32 = request.read().unwrap();
+
+        let mut reply_buffer = [0u8; 128];
+        let mut reply = IPCMessage::new(&mut reply_buffer);
+        reply.write_reply(value + 1);
+        syscalls::ipc_reply(token, &mut reply_buffer).unwrap();
+
+        syscalls::close_handle(session).unwrap();
+    });
+
+    let session = syscalls::connect_to_named_port("t_rt").unwrap();
+
+    let mut buffer = [0u8; 128];
+    let mut request = IPCMessage::new(&mut buffer);
+    request.write(41u32);
+    request.write_translates();
+    request.write_header_for(7);
+    syscalls::ipc_request(session, &mut buffer).unwrap();
+
+    let mut reply = IPCMessage::new(&mut buffer);
+    assert_eq!(reply.read_reply::<u32>().unwrap(), 42);
+
+    server.join().unwrap();
+    syscalls::close_handle(session).unwrap();
+    syscalls::close_handle(port).unwrap();
+}
+
+#[test]
+fn create_port_twice_fails() {
+    let port = syscalls::create_port("t_dup").unwrap();
+    expect_error(syscalls::create_port("t_dup"), Reason::AlreadyExists);
+
+    // The failure mustn't leave anything locked.
+    let other = syscalls::create_port("t_dup2").unwrap();
+    syscalls::close_handle(other).unwrap();
+    syscalls::close_handle(port).unwrap();
+}
+
+#[test]
+fn one_way_send() {
+    let (server_session, client_session) = syscalls::create_session().unwrap();
+
+    let mut buffer = [0u8; 128];
+    let mut message = IPCMessage::new(&mut buffer);
+    message.write(0x1234_5678_9abc_def0u64);
+    message.write_translates();
+    message.write_header_for(3);
+    syscalls::ipc_send(client_session, &mut buffer).unwrap();
+
+    let mut received = [0u8; 128];
+    match syscalls::ipc_receive_timeout(&[server_session], &mut received, TIMEOUT_1MS).unwrap() {
+        ReceiveResult::Signalled(0) => {}
+        other => panic!("expected a one-way message, got {:?}", other),
+    }
+
+    let mut message = IPCMessage::new(&mut received);
+    message.read_header().unwrap();
+    assert_eq!(message.header.id, 3);
+    assert_eq!(message.read::<u64>().unwrap(), 0x1234_5678_9abc_def0);
+
+    // Nothing else was sent.
+    expect_error(
+        syscalls::ipc_receive_timeout(&[server_session], &mut received, TIMEOUT_1MS),
+        Reason::TimedOut,
+    );
+
+    syscalls::close_handle(client_session).unwrap();
+    syscalls::close_handle(server_session).unwrap();
+}
+
+#[test]
+fn handle_move_and_copy() {
+    let (server_session, client_session) = syscalls::create_session().unwrap();
+    let copied = syscalls::create_event().unwrap();
+    let moved = syscalls::create_event().unwrap();
+
+    let mut buffer = [0u8; 128];
+    let mut message = IPCMessage::new(&mut buffer);
+    message.write(TranslateCopyHandle(copied));
+    message.write(TranslateMoveHandle(moved));
+    message.write_translates();
+    message.write_header_for(1);
+    syscalls::ipc_send(client_session, &mut buffer).unwrap();
+
+    // The moved handle is gone from the sender, the copied one isn't.
+    expect_error(syscalls::signal_event(moved), Reason::InvalidHandle);
+    syscalls::clear_event(copied).unwrap();
+
+    let mut received = [0u8; 128];
+    syscalls::ipc_receive_timeout(&[server_session], &mut received, TIMEOUT_1MS).unwrap();
+    let mut message = IPCMessage::new(&mut received);
+    message.read_header().unwrap();
+    message.read_translates();
+    let copy: TranslateCopyHandle = message.read().unwrap();
+    let moved_to: TranslateMoveHandle = message.read().unwrap();
+
+    // The copy names the same event as the original.
+    syscalls::signal_event(copy.0).unwrap();
+    syscalls::wait_one_timeout(copied, TIMEOUT_1MS).unwrap();
+
+    syscalls::signal_event(moved_to.0).unwrap();
+    syscalls::wait_one_timeout(moved_to.0, TIMEOUT_1MS).unwrap();
+
+    for handle in [copy.0, moved_to.0, copied, client_session, server_session] {
+        syscalls::close_handle(handle).unwrap();
+    }
+}
+
+#[test]
+fn signal_only_event_handle() {
+    let (server_session, client_session) = syscalls::create_session().unwrap();
+    let event = syscalls::create_event().unwrap();
+    let signal_only =
+        syscalls::duplicate_handle(event, HandleRights::SIGNAL | HandleRights::TRANSFER).unwrap();
+
+    let mut buffer = [0u8; 128];
+    let mut message = IPCMessage::new(&mut buffer);
+    message.write(TranslateMoveHandle(signal_only));
+    message.write_translates();
+    message.write_header_for(1);
+    syscalls::ipc_send(client_session, &mut buffer).unwrap();
+
+    let mut received = [0u8; 128];
+    syscalls::ipc_receive_timeout(&[server_session], &mut received, TIMEOUT_1MS).unwrap();
+    let mut message = IPCMessage::new(&mut received);
+    message.read_header().unwrap();
+    message.read_translates();
+    let remote: TranslateMoveHandle = message.read().unwrap();
+
+    // The receiving end can signal the event, and only the owner can wait on it.
+    syscalls::signal_event(remote.0).unwrap();
+    expect_error(syscalls::wait_one_timeout(remote.0, 0), Reason::NotAllowed);
+    expect_error(syscalls::clear_event(remote.0), Reason::NotAllowed);
+    syscalls::wait_one_timeout(event, TIMEOUT_1MS).unwrap();
+
+    for handle in [remote.0, event, client_session, server_session] {
+        syscalls::close_handle(handle).unwrap();
+    }
+}
+
+#[test]
+fn event_wait_timeout() {
+    let event = syscalls::create_event().unwrap();
+
+    expect_error(
+        syscalls::wait_one_timeout(event, TIMEOUT_1MS),
+        Reason::TimedOut,
+    );
+
+    syscalls::signal_event(event).unwrap();
+    syscalls::wait_one_timeout(event, TIMEOUT_1MS).unwrap();
+
+    // Auto reset, so the wait used the signal up.
+    expect_error(syscalls::wait_one_timeout(event, 0), Reason::TimedOut);
+
+    syscalls::close_handle(event).unwrap();
+}
+
+#[test]
+fn futex_wait_timeout() {
+    let futex = Arc::new(AtomicU32::new(0));
+
+    expect_error(
+        syscalls::futex_wait(&futex, 0, TIMEOUT_1MS),
+        Reason::TimedOut,
+    );
+
+    expect_error(
+        syscalls::futex_wait(&futex, 1, TIMEOUT_1MS),
+        Reason::TryAgain,
+    );
+
+    let waiter_futex = futex.clone();
+    let waiter = thread::spawn(move || {
+        while waiter_futex.load(Ordering::SeqCst) == 0 {
+            // TryAgain just means the store beat us to it.
+            let _ = syscalls::futex_wait(&waiter_futex, 0, syscalls::TIMEOUT_INFINITE);
+        }
+    });
+
+    futex.store(1, Ordering::SeqCst);
+    syscalls::futex_wake(&futex, usize::MAX).unwrap();
+    waiter.join().unwrap();
+
+    // Nobody is left waiting.
+    assert_eq!(syscalls::futex_wake(&futex, usize::MAX).unwrap(), 0);
+}
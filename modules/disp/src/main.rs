@@ -1,5 +1,6 @@
 use common::system_info::*;
 use francium_common::font;
+use francium_common::types::MapType;
 use process::syscalls;
 
 mod bochs;
@@ -53,7 +54,7 @@ fn main() {
             if let Some(mut bochs) = bochs::BochsAdapter::new() {
                 bochs.set_mode(1024, 768);
                 (1024, 768, 1024, bochs.get_framebuffer())
-            } else if let Some(platform) = platform::PlatformFramebuffer::new() {
+            } else if let Some(platform) = platform::PlatformFramebuffer::new(MapType::NormalCachable) {
                 (platform.info.width, platform.info.height, platform.info.stride, platform.get_framebuffer())
             } else {
                 panic!("No framebuffer found!!");
@@ -61,17 +62,25 @@ fn main() {
         },
         #[cfg(target_arch = "aarch64")]
         Platform::Raspi3 => {
-            let rpi_3_peripheral_base = 0x3f000000;
-            let mut raspi = raspi::MailboxAdapter::new(rpi_3_peripheral_base);
-            raspi.set_mode(1920, 1080);
-            (1920, 1080, 1920, raspi.get_framebuffer())
+            if let Some(platform) = platform::PlatformFramebuffer::new(MapType::NormalUncachable) {
+                (platform.info.width, platform.info.height, platform.info.stride, platform.get_framebuffer())
+            } else {
+                let rpi_3_peripheral_base = 0x3f000000;
+                let mut raspi = raspi::MailboxAdapter::new(rpi_3_peripheral_base);
+                raspi.set_mode(1920, 1080);
+                (1920, 1080, 1920, raspi.get_framebuffer())
+            }
         },
         #[cfg(target_arch = "aarch64")]
         Platform::Raspi4 => {
-            let rpi_4_peripheral_base = 0xfe000000;
-            let mut raspi = raspi::MailboxAdapter::new(rpi_4_peripheral_base);
-            raspi.set_mode(1920, 1080);
-            (1920, 1080, 1920, raspi.get_framebuffer())
+            if let Some(platform) = platform::PlatformFramebuffer::new(MapType::NormalUncachable) {
+                (platform.info.width, platform.info.height, platform.info.stride, platform.get_framebuffer())
+            } else {
+                let rpi_4_peripheral_base = 0xfe000000;
+                let mut raspi = raspi::MailboxAdapter::new(rpi_4_peripheral_base);
+                raspi.set_mode(1920, 1080);
+                (1920, 1080, 1920, raspi.get_framebuffer())
+            }
         },
         _ => unimplemented!()
     };
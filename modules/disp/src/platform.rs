@@ -8,9 +8,10 @@ pub struct PlatformFramebuffer {
 }
 
 impl<'a> PlatformFramebuffer {
-    pub fn new() -> Option<PlatformFramebuffer> {
+    // The raspis scan out without snooping our caches, so they want an uncached mapping.
+    pub fn new(map_type: MapType) -> Option<PlatformFramebuffer> {
         let Ok(SystemInfo::FramebufferInfo(info)) = syscalls::get_system_info(SystemInfoType::FramebufferInfo, 0) else {
-            panic!("No platform framebuffer!");
+            return None;
         };
 
         // TODO: Move this to be shared memory. But that requires the concept of shared memory.
@@ -18,7 +19,7 @@ impl<'a> PlatformFramebuffer {
             info.phys_addr,
             0,
             info.size,
-            MapType::NormalCachable,
+            map_type,
             PagePermission::USER_READ_WRITE,
         )
         .unwrap();
@@ -0,0 +1,145 @@
+use crate::block::BlockDevice;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+// Disk image backed block device, for running fs on the host.
+pub struct BlockFile {
+    file: File,
+    disk_size_bytes: u64,
+}
+
+impl BlockFile {
+    pub fn open(path: &str) -> std::io::Result<BlockFile> {
+        let file = File::options().read(true).write(true).open(path)?;
+        let disk_size_bytes = file.metadata()?.len();
+
+        Ok(BlockFile {
+            file: file,
+            disk_size_bytes: disk_size_bytes,
+        })
+    }
+}
+
+impl BlockDevice for BlockFile {
+    fn read_sector(&mut self, offset: u64, buffer: &mut [u8]) -> u64 {
+        self.file.seek(SeekFrom::Start(offset * 512)).unwrap();
+        // Reads past the end of the image read back as zeroes.
+        let mut done = 0;
+        while done < 512 {
+            match self.file.read(&mut buffer[done..512]).unwrap() {
+                0 => break,
+                n => done += n,
+            }
+        }
+        buffer[done..512].fill(0);
+
+        1
+    }
+
+    fn write_sector(&mut self, offset: u64, buffer: &[u8]) -> u64 {
+        self.file.seek(SeekFrom::Start(offset * 512)).unwrap();
+        self.file.write_all(&buffer[..512]).unwrap();
+
+        1
+    }
+
+    fn get_size(&self) -> u64 {
+        self.disk_size_bytes
+    }
+}
+
+// Image path comes from FS_DISK_IMAGE.
+pub fn scan() -> Vec<Arc<Mutex<dyn BlockDevice + Send>>> {
+    let Ok(path) = std::env::var("FS_DISK_IMAGE") else {
+        return Vec::new();
+    };
+
+    match BlockFile::open(&path) {
+        Ok(block) => vec![Arc::new(Mutex::new(block))],
+        Err(e) => {
+            println!("fs: couldn't open {}: {:?}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockFile;
+    use crate::block::BlockDevice;
+    use crate::block_adapter::BlockAdapter;
+    use crate::fs_worker::*;
+    use std::fs::File;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    // A FAT image with no partition table, holding one file.
+    fn make_image(path: &std::path::Path, contents: &[u8]) {
+        use fatfs::Write;
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        file.set_len(4 << 20).unwrap();
+
+        let mut storage = fatfs::StdIoWrapper::new(file);
+        fatfs::format_volume(&mut storage, fatfs::FormatVolumeOptions::new()).unwrap();
+
+        let fs = fatfs::FileSystem::new(storage, fatfs::FsOptions::new()).unwrap();
+        let mut test_file = fs.root_dir().create_file("hello.txt").unwrap();
+        test_file.write_all(contents).unwrap();
+        test_file.flush().unwrap();
+        drop(test_file);
+        fs.unmount().unwrap();
+    }
+
+    #[test]
+    fn read_through_block_file() {
+        let path = std::env::temp_dir().join(format!("fs-test-{}.img", std::process::id()));
+        let contents: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        make_image(&path, &contents);
+
+        let block: Arc<Mutex<dyn BlockDevice + Send>> =
+            Arc::new(Mutex::new(BlockFile::open(path.to_str().unwrap()).unwrap()));
+        let fs = fatfs::FileSystem::new(
+            fatfs::StdIoWrapper::new(BlockAdapter::new(block, 0)),
+            fatfs::FsOptions::new(),
+        )
+        .unwrap();
+
+        let (tx_request, rx_request) = mpsc::channel();
+        let (tx_response, rx_response) = mpsc::channel();
+        let worker = thread::spawn(move || fs_worker_thread(rx_request, tx_response, fs));
+        let client = FSWorkerClient::new(tx_request, rx_response);
+
+        let file_handle = match client.do_request(FSWorkerRequest::Open("hello.txt".into())) {
+            Ok(FSWorkerResponse::Open(Ok(handle))) => handle,
+            other => panic!("open failed: {:?}", other),
+        };
+
+        // Reads pick up where the last one stopped, and come up short at the end of the file.
+        let mut read = Vec::new();
+        loop {
+            match client.do_request(FSWorkerRequest::Read(file_handle, 1024)) {
+                Ok(FSWorkerResponse::Read(Ok(data))) if data.is_empty() => break,
+                Ok(FSWorkerResponse::Read(Ok(data))) => read.extend_from_slice(&data),
+                other => panic!("read failed: {:?}", other),
+            }
+        }
+        assert_eq!(read, contents);
+
+        match client.do_request(FSWorkerRequest::Open("missing.txt".into())) {
+            Ok(FSWorkerResponse::Open(Err(_))) => {}
+            other => panic!("expected open to fail: {:?}", other),
+        }
+
+        drop(client);
+        worker.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 use std::sync::mpsc;
-use process::os_error::OSResult;
+use fatfs::{Read, Seek, SeekFrom};
+use process::os_error::{Module, OSError, OSResult, Reason};
 use crate::block_adapter::BlockAdapter;
+use crate::map_fatfs_error;
 
 pub struct FSWorkerClient {
     request: mpsc::Sender<FSWorkerRequest>,
@@ -21,7 +23,8 @@ impl FSWorkerClient {
 #[derive(Debug)]
 pub enum FSWorkerRequest {
     Open(String),
-    Read(usize),
+    /* File handle, length */
+    Read(usize, usize),
     Write(usize),
     /* ... */
 }
@@ -30,6 +33,8 @@ pub enum FSWorkerRequest {
 pub enum FSWorkerResponse {
     /* An internal handle to the new file */
     Open(OSResult<usize>),
+    /* Up to the requested length, less at the end of the file */
+    Read(OSResult<Vec<u8>>),
 }
 
 
@@ -40,11 +45,34 @@ type FatFilesystem = fatfs::FileSystem<
 >;
 pub fn fs_worker_thread(request: mpsc::Receiver<FSWorkerRequest>, response: mpsc::Sender<FSWorkerResponse>, fs: FatFilesystem) {
     println!("Hello from fs worker");
-    loop {
-        let req = request.recv().unwrap();
+
+    // Open files as (path, offset), indexed by internal file handle.
+    let mut files: Vec<(String, u64)> = Vec::new();
+    // Runs until the client goes away.
+    while let Ok(req) = request.recv() {
         match req {
             FSWorkerRequest::Open(filename) => {
-                response.send(FSWorkerResponse::Open(Ok(0))).unwrap();
+                let res = match fs.root_dir().open_file(&filename) {
+                    Ok(_) => {
+                        files.push((filename, 0));
+                        Ok(files.len() - 1)
+                    }
+                    Err(e) => Err(map_fatfs_error(e)),
+                };
+                response.send(FSWorkerResponse::Open(res)).unwrap();
+            }
+            FSWorkerRequest::Read(file_handle, length) => {
+                let res = match files.get_mut(file_handle) {
+                    Some((filename, offset)) => {
+                        let res = read_file(&fs, filename, *offset, length);
+                        if let Ok(data) = &res {
+                            *offset += data.len() as u64;
+                        }
+                        res
+                    }
+                    None => Err(OSError::new(Module::Fs, Reason::InvalidHandle)),
+                };
+                response.send(FSWorkerResponse::Read(res)).unwrap();
             }
             _ => {
                 println!("AAAAAAAAAAA");
@@ -52,4 +80,22 @@ pub fn fs_worker_thread(request: mpsc::Receiver<FSWorkerRequest>, response: mpsc
             }
         }
     }
-}
\ No newline at end of file
+}
+
+fn read_file(fs: &FatFilesystem, filename: &str, offset: u64, length: usize) -> OSResult<Vec<u8>> {
+    let mut file = fs.root_dir().open_file(filename).map_err(map_fatfs_error)?;
+    file.seek(SeekFrom::Start(offset)).map_err(map_fatfs_error)?;
+
+    let mut data = vec![0u8; length];
+    let mut total = 0;
+    while total < length {
+        let count = file.read(&mut data[total..]).map_err(map_fatfs_error)?;
+        if count == 0 {
+            break;
+        }
+        total += count;
+    }
+
+    data.truncate(total);
+    Ok(data)
+}
@@ -15,6 +15,8 @@ mod virtio_pci;
 
 mod block;
 mod block_adapter;
+#[cfg(not(target_os = "francium"))]
+mod block_file;
 mod block_virtio;
 mod fs_worker;
 
@@ -60,7 +62,7 @@ impl FSServerStruct {
     }
 }
 
-impl FSSession {
+impl FSSessionInterface for FSSession {
     fn open_file(&self, file_name: String) -> OSResult<TranslateMoveHandle> {
         let server = self.get_server();
         let fs = server.fs_worker.lock().unwrap();
@@ -86,10 +88,19 @@ impl FSSession {
     }
 }
 
-impl IFileSession {
-    fn read_file(&self, length: usize) -> OSResult<usize> {
-        println!("Read file");
-        Ok(0)
+impl IFileSessionInterface for IFileSession {
+    fn read_file(&self, buffer: &mut [u8]) -> OSResult<usize> {
+        let server = self.get_server();
+        let fs = server.fs_worker.lock().unwrap();
+
+        let response = fs.do_request(FSWorkerRequest::Read(self.file_handle, buffer.len()))?;
+        if let FSWorkerResponse::Read(data) = response {
+            let data = data?;
+            buffer[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        } else {
+            Err(OSError::new(Module::Fs, Reason::Unknown))
+        }
     }
 }
 
@@ -101,7 +112,10 @@ async fn main() {
 
     sm::register_port(syscalls::make_tag("fs"), TranslateCopyHandle(port)).unwrap();
 
+    #[cfg(target_os = "francium")]
     let mut blocks = block_virtio::scan();
+    #[cfg(not(target_os = "francium"))]
+    let mut blocks = block_file::scan();
 
     let Some(first_block) = blocks.pop() else {
         // die
@@ -109,7 +123,7 @@ async fn main() {
         syscalls::close_handle(port).unwrap();
         syscalls::exit_process();
     };
-    println!("fs: found virtio block");
+    println!("fs: found block device");
 
     let adapted = Box::new(BlockAdapter::new(first_block.clone(), 0));
 
@@ -13,7 +13,7 @@ mod pcie_acpi;
 #[cfg(target_arch = "aarch64")]
 mod pcie_dt;
 
-use common::Handle;
+use common::{Handle, HandleRights};
 use process::ipc::pcie::PCIDeviceInfo;
 use process::ipc_server::IPCServer;
 use process::os_error::*;
@@ -30,17 +30,60 @@ define_server!(PCIEServerStruct {
     interrupt_map: Option<PCIInterruptMap>,
 });
 
-define_session!(PCIESession {}, PCIEServerStruct);
+define_session!(PCIESession {
+    // Devices this client was handed an interrupt event for, once per call.
+    interrupts: Mutex<Vec<u32>>,
+}, PCIEServerStruct);
 
 impl PCIEServerStruct {
     fn accept_main_session(self: &Arc<PCIEServerStruct>) -> Arc<PCIESession> {
         Arc::new(PCIESession {
             __server: self.clone(),
+            interrupts: Mutex::new(Vec::new()),
         })
     }
+
+    // Lets go of one client's use of a function's interrupt, unbinding it once nobody is left.
+    fn release_interrupt(&self, device: u32) -> OSResult<()> {
+        let bus_id: u8 = ((device & (0xff << 16)) >> 16) as u8;
+        let device_id: u8 = ((device & (0xff << 8)) >> 8) as u8;
+        let function_id: u8 = (device & 0xff) as u8;
+
+        let mut buses_locked = self.buses.lock().unwrap();
+
+        for bus in buses_locked.iter_mut() {
+            for dev in bus.devices.iter_mut() {
+                for func in dev.functions.iter_mut() {
+                    if bus.num == bus_id && dev.num == device_id && func.num == function_id {
+                        func.interrupt_users -= 1;
+                        if func.interrupt_users == 0 {
+                            if let Some((bound_handle, interrupt_id)) = func.interrupt_event.take()
+                            {
+                                syscalls::unbind_interrupt(bound_handle, interrupt_id as usize)?;
+                                syscalls::close_handle(bound_handle)?;
+                            }
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        Err(OSError::new(Module::Pcie, Reason::NotFound))
+    }
 }
 
-impl PCIESession {
+impl Drop for PCIESession {
+    fn drop(&mut self) {
+        // A client that goes away without unbinding still lets go of its interrupts.
+        let server = self.get_server();
+        for device in self.interrupts.get_mut().unwrap().drain(..) {
+            let _ = server.release_interrupt(device);
+        }
+    }
+}
+
+impl PCIESessionInterface for PCIESession {
     fn list_devices(&self) -> Vec<PCIDeviceInfo> {
         let mut all_devices = Vec::new();
 
@@ -279,21 +322,41 @@ impl PCIESession {
             for dev in bus.devices.iter_mut() {
                 for func in dev.functions.iter_mut() {
                     if bus.num == bus_id && dev.num == device_id && func.num == function_id {
-                        let event_handle = syscalls::create_event().unwrap();
-
-                        let interrupt_id = if func.inner.interrupt_line != 0 {
-                            func.inner.interrupt_line
-                        } else {
-                            32 + server
-                                .interrupt_map
-                                .as_ref()
-                                .unwrap()
-                                .get_interrupt_id(device_id, func.inner.interrupt_pin)
-                                as u8
+                        let event_handle = match func.interrupt_event {
+                            Some((event_handle, _)) => event_handle,
+                            None => {
+                                let event_handle = syscalls::create_event().unwrap();
+
+                                let interrupt_id = if func.inner.interrupt_line != 0 {
+                                    func.inner.interrupt_line
+                                } else {
+                                    32 + server
+                                        .interrupt_map
+                                        .as_ref()
+                                        .unwrap()
+                                        .get_interrupt_id(device_id, func.inner.interrupt_pin)
+                                        as u8
+                                };
+
+                                if let Err(err) =
+                                    syscalls::bind_interrupt(event_handle, interrupt_id as usize)
+                                {
+                                    syscalls::close_handle(event_handle)?;
+                                    return Err(err);
+                                }
+                                func.interrupt_event = Some((event_handle, interrupt_id));
+                                event_handle
+                            }
                         };
 
-                        syscalls::bind_interrupt(event_handle, interrupt_id as usize).unwrap();
-                        return Ok(TranslateMoveHandle(event_handle));
+                        // Clients can wait on (and ack) the interrupt, but not signal or rebind it.
+                        let client_handle = syscalls::duplicate_handle(
+                            event_handle,
+                            HandleRights::WAIT | HandleRights::TRANSFER,
+                        )?;
+                        func.interrupt_users += 1;
+                        self.interrupts.lock().unwrap().push(device);
+                        return Ok(TranslateMoveHandle(client_handle));
                     }
                 }
             }
@@ -306,28 +369,19 @@ impl PCIESession {
         device: u32,
         event_handle: TranslateCopyHandle,
     ) -> OSResult<()> {
-        // just kidding this sucks a lot
-        let bus_id: u8 = ((device & (0xff << 16)) >> 16) as u8;
-        let device_id: u8 = ((device & (0xff << 8)) >> 8) as u8;
-        let function_id: u8 = (device & 0xff) as u8;
-
-        let server = self.get_server();
-        let mut buses_locked = server.buses.lock().unwrap();
-
-        for bus in buses_locked.iter_mut() {
-            for dev in bus.devices.iter_mut() {
-                for func in dev.functions.iter_mut() {
-                    if bus.num == bus_id && dev.num == device_id && func.num == function_id {
-                        syscalls::unbind_interrupt(
-                            event_handle.0,
-                            func.inner.interrupt_line as usize,
-                        )
-                        .unwrap();
-                    }
-                }
+        // The client's handle can't unbind anything, so it's only closed.
+        syscalls::close_handle(event_handle.0)?;
+
+        // Only a client that was handed the event can let go of it, and only as often as it was.
+        let mut interrupts = self.interrupts.lock().unwrap();
+        match interrupts.iter().position(|x| *x == device) {
+            Some(index) => {
+                interrupts.remove(index);
+                drop(interrupts);
+                self.get_server().release_interrupt(device)
             }
+            None => Err(OSError::new(Module::Pcie, Reason::NotFound)),
         }
-        Err(OSError::new(Module::Pcie, Reason::NotFound))
     }
 }
 
@@ -363,3 +417,184 @@ async fn main() {
 
     syscalls::exit_process();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VENDOR_ID: u16 = 0x1af4;
+    const DEVICE_ID: u16 = 0x1041;
+    // Bus 0, device 0, function 0.
+    const DEVICE: u32 = 0;
+    const MEM_BASE: usize = 0x1000_0000;
+
+    // An ECAM window for bus 0 with a single network function on it. Everything else reads as all
+    // ones, which is what an empty slot looks like.
+    fn fake_ecam(interrupt_line: u8) -> usize {
+        let block: &'static mut [u8] = vec![0xffu8; 1 << 20].leak();
+        let config = &mut block[..0x100];
+        config.fill(0);
+
+        config[0x00..0x02].copy_from_slice(&VENDOR_ID.to_le_bytes());
+        config[0x02..0x04].copy_from_slice(&DEVICE_ID.to_le_bytes());
+        // Status: has a capability list.
+        config[0x06..0x08].copy_from_slice(&(1u16 << 4).to_le_bytes());
+        config[0x0a] = 0x00;
+        config[0x0b] = 0x02;
+        // BAR 0 is left for us to place, BAR 1 was placed by firmware.
+        config[0x14..0x18].copy_from_slice(&0xfebf_0000u32.to_le_bytes());
+        config[0x34] = 0x40;
+        config[0x3c] = interrupt_line;
+        config[0x3d] = 1;
+
+        // Two vendor specific capabilities.
+        config[0x40..0x44].copy_from_slice(&[0x09, 0x50, 4, 0xaa]);
+        config[0x50..0x55].copy_from_slice(&[0x09, 0x00, 5, 0xbb, 0xcc]);
+
+        block.as_mut_ptr() as usize
+    }
+
+    // The emulated interrupts are shared by every test, so each one that binds picks its own line.
+    fn fake_server(interrupt_line: u8) -> Arc<PCIEServerStruct> {
+        let port = syscalls::create_port("").unwrap();
+        Arc::new(PCIEServerStruct {
+            __server_impl: Mutex::new(ServerImpl::new(port)),
+            buses: Mutex::new(vec![
+                pcie::PCIBus::new(fake_ecam(interrupt_line), 0).unwrap()
+            ]),
+            mem_base: Mutex::new(MEM_BASE),
+            io_base: Mutex::new(0),
+            interrupt_map: None,
+        })
+    }
+
+    fn fake_session() -> Arc<PCIESession> {
+        fake_server(0).accept_main_session()
+    }
+
+    fn is_bound(server: &PCIEServerStruct) -> bool {
+        let buses = server.buses.lock().unwrap();
+        buses[0].devices[0].functions[0].interrupt_event.is_some()
+    }
+
+    fn is_closed(handle: Handle) -> bool {
+        match syscalls::wait_one_timeout(handle, 0) {
+            Err(err) => {
+                OSError::to_result_code(&err)
+                    == ResultCode::new(Module::Kernel, Reason::InvalidHandle)
+            }
+            Ok(()) => false,
+        }
+    }
+
+    #[test]
+    fn scan_finds_the_function() {
+        let session = fake_session();
+
+        let devices = session.list_devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].bus, 0);
+        assert_eq!(devices[0].device, 0);
+        assert_eq!(devices[0].vendor_id, VENDOR_ID);
+        assert_eq!(devices[0].device_id, DEVICE_ID);
+
+        assert_eq!(
+            session.get_devices_by_vidpid(VENDOR_ID, DEVICE_ID),
+            vec![DEVICE]
+        );
+        assert_eq!(session.get_devices_by_class(0x02, 0x00), vec![DEVICE]);
+        assert!(session.get_devices_by_vidpid(VENDOR_ID, 0x1000).is_empty());
+    }
+
+    #[test]
+    fn bars_are_placed_once() {
+        let session = fake_session();
+
+        // Fake memory reads back every bit we write, so the BAR looks 16 bytes long.
+        assert_eq!(session.get_bar(DEVICE, 0).unwrap(), (MEM_BASE, 16));
+        assert_eq!(session.get_bar(DEVICE, 0).unwrap(), (MEM_BASE, 16));
+        assert_eq!(session.get_bar(DEVICE, 1).unwrap(), (0xfebf_0000, 16));
+
+        assert!(session.get_bar(1 << 8, 0).is_err());
+    }
+
+    #[test]
+    fn capabilities_follow_the_list() {
+        let session = fake_session();
+
+        assert_eq!(
+            session.get_cap(DEVICE, 0).unwrap(),
+            vec![0x09, 0x50, 4, 0xaa]
+        );
+        assert_eq!(
+            session.get_cap(DEVICE, 1).unwrap(),
+            vec![0x09, 0x00, 5, 0xbb, 0xcc]
+        );
+        assert!(session.get_cap(DEVICE, 2).is_err());
+    }
+
+    #[test]
+    fn enable_sets_the_command_register() {
+        let session = fake_session();
+
+        session.enable(DEVICE).unwrap();
+        let command = {
+            let server = session.get_server();
+            let buses = server.buses.lock().unwrap();
+            buses[0].devices[0].functions[0].inner.header.command
+        };
+        assert_eq!(command, 1 | 2 | 4);
+
+        assert!(session.enable(1 << 8).is_err());
+    }
+
+    #[test]
+    fn interrupt_stays_bound_for_other_clients() {
+        let server = fake_server(20);
+        let first = server.accept_main_session();
+        let second = server.accept_main_session();
+
+        let first_event = first.get_interrupt_event(DEVICE).unwrap().0;
+        let second_event = second.get_interrupt_event(DEVICE).unwrap().0;
+
+        first
+            .unbind_interrupt_event(DEVICE, TranslateCopyHandle(first_event))
+            .unwrap();
+        assert!(is_closed(first_event));
+        assert!(is_bound(&server));
+
+        second
+            .unbind_interrupt_event(DEVICE, TranslateCopyHandle(second_event))
+            .unwrap();
+        assert!(is_closed(second_event));
+        assert!(!is_bound(&server));
+    }
+
+    #[test]
+    fn unbind_needs_a_binding() {
+        let server = fake_server(21);
+        let holder = server.accept_main_session();
+        let other = server.accept_main_session();
+        holder.get_interrupt_event(DEVICE).unwrap();
+
+        // Someone else's event doesn't let a client tear the binding down, but it's still closed.
+        let event = syscalls::create_event().unwrap();
+        assert!(other
+            .unbind_interrupt_event(DEVICE, TranslateCopyHandle(event))
+            .is_err());
+        assert!(is_closed(event));
+        assert!(is_bound(&server));
+    }
+
+    #[test]
+    fn dropped_sessions_release_their_interrupts() {
+        let server = fake_server(22);
+        let session = server.accept_main_session();
+        session.get_interrupt_event(DEVICE).unwrap();
+        session.get_interrupt_event(DEVICE).unwrap();
+        assert!(is_bound(&server));
+
+        drop(session);
+        assert!(!is_bound(&server));
+    }
+}
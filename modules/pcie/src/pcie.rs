@@ -1,4 +1,5 @@
 use crate::ecam::*;
+use common::Handle;
 use smallvec::SmallVec;
 
 fn get_function_header(
@@ -37,6 +38,10 @@ fn get_function_type0(
 pub struct PCIFunction {
     pub num: u8,
     pub inner: &'static mut ConfigurationSpaceType0,
+    // The event bound to this function's interrupt, and the interrupt id it was bound to.
+    pub interrupt_event: Option<(Handle, u8)>,
+    // How many clients were handed the event. It's unbound once they've all let go.
+    pub interrupt_users: usize,
 }
 
 #[derive(Debug)]
@@ -109,6 +114,8 @@ impl PCIDevice {
                     self.functions.push(PCIFunction {
                         num: function_num,
                         inner: function_type0,
+                        interrupt_event: None,
+                        interrupt_users: 0,
                     });
                 }
                 /* pci bridge */
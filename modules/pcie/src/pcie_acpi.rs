@@ -6,7 +6,7 @@ use process::syscalls;
 use std::ptr::NonNull;
 
 use acpi::{AcpiHandler, AcpiTables, PciConfigRegions, PhysicalMapping};
-use common::constants;
+use common::system_info::{SystemInfo, SystemInfoType};
 
 #[derive(Copy, Clone)]
 struct UserACPIHandler {}
@@ -45,7 +45,11 @@ impl AcpiHandler for UserACPIHandler {
 
 // When using ACPI, we assume firmware has already set up BARs etc.
 pub fn scan_via_acpi() -> Vec<PCIBus> {
-    let acpi_table_base = syscalls::bodge(constants::GET_ACPI_BASE, 0);
+    let Ok(SystemInfo::AcpiRsdpAddress(acpi_table_base)) =
+        syscalls::get_system_info(SystemInfoType::AcpiRsdpAddress, 0)
+    else {
+        panic!("No ACPI RSDP!");
+    };
 
     let handler = UserACPIHandler {};
     let tables = unsafe { AcpiTables::from_rsdp(handler, acpi_table_base).unwrap() };
@@ -35,7 +35,8 @@ impl SMServerStruct {
     }
 }
 
-impl SMSession {
+#[async_trait::async_trait]
+impl SMSessionInterface for SMSession {
     async fn get_service_handle(&self, tag: u64) -> OSResult<TranslateMoveHandle> {
         let server_port = {
             self.get_server()
@@ -112,3 +113,107 @@ async fn main() {
 
     syscalls::exit_process();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use process::ipc::message::IPCMessage;
+    use std::sync::Once;
+    use std::thread;
+    use std::time::Duration;
+
+    static START_SM: Once = Once::new();
+    // The generated clients share one IPC buffer, so only one test talks to sm at a time.
+    static CLIENT_LOCK: Mutex<()> = Mutex::new(());
+
+    // Runs the real server on the emulated kernel, for libprocess's generated sm client to talk to.
+    fn start_sm() {
+        START_SM.call_once(|| {
+            let port = syscalls::create_port("sm").unwrap();
+            let server = Arc::new(SMServerStruct {
+                __server_impl: Mutex::new(ServerImpl::new(port)),
+                server_ports: Mutex::new(HashMap::new()),
+                server_waiters: Mutex::new(HashMap::new()),
+            });
+
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+                runtime.block_on(async move { server.process_forever() });
+            });
+        });
+    }
+
+    fn accept_one(port: Handle) -> Handle {
+        let mut buffer = [0u8; 128];
+        match syscalls::ipc_receive(&[port], &mut buffer).unwrap() {
+            ReceiveResult::Signalled(0) => {}
+            other => panic!("expected a connection, got {:?}", other),
+        }
+        syscalls::ipc_accept(port).unwrap()
+    }
+
+    // Checks that client and server are two ends of the same session.
+    fn assert_connected(client: Handle, server: Handle) {
+        let mut buffer = [0u8; 128];
+        let mut message = IPCMessage::new(&mut buffer);
+        message.write(0x5eu32);
+        message.write_translates();
+        message.write_header_for(1);
+        syscalls::ipc_send(client, &mut buffer).unwrap();
+
+        let mut received = [0u8; 128];
+        syscalls::ipc_receive(&[server], &mut received).unwrap();
+        let mut message = IPCMessage::new(&mut received);
+        message.read_header().unwrap();
+        message.read_translates();
+        assert_eq!(message.read::<u32>().unwrap(), 0x5e);
+    }
+
+    #[test]
+    fn register_then_get_service() {
+        start_sm();
+        let _lock = CLIENT_LOCK.lock().unwrap();
+
+        let port = syscalls::create_port("").unwrap();
+        let tag = syscalls::make_tag("t_reg");
+        sm::register_port(tag, TranslateCopyHandle(port)).unwrap();
+
+        let acceptor = thread::spawn(move || accept_one(port));
+        let client = sm::get_service_handle(tag).unwrap().0;
+        let server = acceptor.join().unwrap();
+
+        assert_connected(client, server);
+
+        for handle in [client, server, port] {
+            syscalls::close_handle(handle).unwrap();
+        }
+    }
+
+    #[test]
+    fn get_service_waits_for_register() {
+        start_sm();
+        let _lock = CLIENT_LOCK.lock().unwrap();
+
+        let tag = syscalls::make_tag("t_late");
+        let waiter = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime.block_on(sm::get_service_handle_async(tag))
+        });
+
+        // Give the request time to reach sm, so it has to wait for the port.
+        thread::sleep(Duration::from_millis(50));
+
+        let port = syscalls::create_port("").unwrap();
+        sm::register_port(tag, TranslateCopyHandle(port)).unwrap();
+        let server = accept_one(port);
+        let client = waiter.join().unwrap().unwrap().0;
+
+        assert_connected(client, server);
+
+        for handle in [client, server, port] {
+            syscalls::close_handle(handle).unwrap();
+        }
+    }
+}
@@ -1,18 +1,84 @@
+use common::introspection::{ThreadInfo, INTROSPECTION_HANDLE};
 use process::ipc;
+use process::ipc::trace::{IPCTraceControl, TraceDecoder};
+use process::os_error::{Module, OSError, Reason, ResultCode};
 use process::syscalls;
 
 const SECOND: u64 = 1_000_000_000;
 
+// Like ps. Does nothing if we aren't allowed to look.
+fn print_threads() {
+    let mut threads = vec![ThreadInfo::default(); 16];
+    let count = loop {
+        match syscalls::list_threads(INTROSPECTION_HANDLE, &mut threads) {
+            Ok(count) if count > threads.len() => threads.resize(count, ThreadInfo::default()),
+            Ok(count) => break count,
+            Err(_) => return,
+        }
+    };
+
+    println!("  PID   TID NAME             STATE       SVC HANDLES   CPU (us)");
+    for thread in &threads[..count] {
+        let name = if thread.is_idle {
+            "[idle]"
+        } else {
+            thread.process_name()
+        };
+        println!(
+            "{:>5} {:>5} {:<16} {:<9} {:>5x} {:>7} {:>10}",
+            thread.process_id,
+            thread.thread_id,
+            name,
+            format!("{:?}", thread.state),
+            thread.last_svc_number,
+            thread.handle_count,
+            thread.cpu_time_ns / 1000
+        );
+    }
+}
+
+// Named ports are unique, so the second create_port for a name has to fail.
+fn check_duplicate_port() {
+    let port = syscalls::create_port("t_dup").unwrap();
+    match syscalls::create_port("t_dup") {
+        Err(err) => assert_eq!(
+            OSError::to_result_code(&err),
+            ResultCode::new(Module::Kernel, Reason::AlreadyExists)
+        ),
+        Ok(_) => panic!("Created a second port named t_dup"),
+    }
+    syscalls::close_handle(port).unwrap();
+}
+
 fn main() {
     println!("Hello from test!");
 
-    if let Ok(file_handle) = ipc::fs::open_file("efi/boot/bootx64.efi".to_string()) {
-        println!("Hello again from test: {:?}", file_handle);
-        println!("Reading file: {:?}", ipc::fs::read_file(file_handle.0, 0));
+    check_duplicate_port();
+
+    // Watch what it takes to read a file.
+    let tracing =
+        syscalls::ipc_trace_control(INTROSPECTION_HANDLE, IPCTraceControl::Enable).is_ok();
+
+    if let Ok(file) = ipc::fs::open_file("efi/boot/bootx64.efi".to_string()) {
+        println!("Hello again from test: {:?}", file);
+        let mut buffer = [0u8; 16];
+        println!("Reading file: {:?}", file.read_file(&mut buffer));
+        println!("Read: {:x?}", buffer);
     } else {
         println!("Probably failed to open file..");
     }
 
+    if tracing {
+        syscalls::ipc_trace_control(INTROSPECTION_HANDLE, IPCTraceControl::Disable).unwrap();
+
+        let mut decoder = TraceDecoder::new();
+        for record in ipc::trace::read_all(INTROSPECTION_HANDLE).unwrap() {
+            println!("ipc: {}", decoder.describe(&record));
+        }
+    }
+
+    print_threads();
+
     println!("Sleeping for 1 second...");
     syscalls::sleep_ns(1 * SECOND);
     println!("*yawn*");
@@ -0,0 +1,387 @@
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Argument registers in the order the System V ABI passes them. The kernel takes the fourth one in
+// r10 instead, since syscall clobbers rcx.
+const X86_64_ARG_REGISTERS: &[&str] = &["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+// Types that fit in the register an output comes back in.
+const OUTPUT_TYPES: &[&str] = &["u32", "u64", "usize"];
+
+// The wrappers use these names themselves.
+const RESERVED_NAMES: &[&str] = &["ctx", "res", "out"];
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Value {
+    name: String,
+    ty: String,
+    user_ty: Option<String>,
+}
+
+impl Value {
+    fn user_ty(&self) -> &str {
+        self.user_ty.as_ref().unwrap_or(&self.ty)
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Syscall {
+    id: usize,
+    name: String,
+    module: Option<String>,
+    #[serde(default)]
+    inputs: Vec<Value>,
+    #[serde(default = "default_true")]
+    result: bool,
+    output: Option<Value>,
+    #[serde(default)]
+    noreturn: bool,
+    #[serde(default)]
+    arch_specific: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct SyscallFile {
+    syscall: Vec<Syscall>,
+}
+
+impl Syscall {
+    // Whether libprocess passes a pointer for the output, which takes up an argument register.
+    fn has_output_pointer(&self) -> bool {
+        self.result && self.output.is_some()
+    }
+
+    fn call(&self, args: &[String]) -> String {
+        let args: Vec<_> = self
+            .inputs
+            .iter()
+            .zip(args)
+            .map(|(input, arg)| format!("{} as {}", arg, input.ty))
+            .collect();
+        format!("svc::svc_{}({})", self.name, args.join(", "))
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_lowercase() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+// Returns every problem with the file, rather than just the first.
+fn validate(spec: &SyscallFile) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut ids: HashMap<usize, &str> = HashMap::new();
+    let mut names: HashMap<&str, usize> = HashMap::new();
+
+    for syscall in &spec.syscall {
+        let mut error = |message: String| {
+            errors.push(format!("{}: {}", syscall.name, message));
+        };
+
+        if !is_identifier(&syscall.name) {
+            error("isn't a valid name".to_string());
+        }
+        if let Some(other) = ids.insert(syscall.id, &syscall.name) {
+            error(format!("id {:#x} is already used by {}", syscall.id, other));
+        }
+        if names.insert(&syscall.name, syscall.id).is_some() {
+            error("is defined more than once".to_string());
+        }
+
+        match (&syscall.module, syscall.arch_specific) {
+            (Some(module), false) if !is_identifier(module) => {
+                error(format!("module {:?} isn't a valid name", module))
+            }
+            (None, false) => error("needs a module".to_string()),
+            (Some(_), true) => error("is arch specific, so it has no module".to_string()),
+            _ => {}
+        }
+
+        for input in &syscall.inputs {
+            if !is_identifier(&input.name) || RESERVED_NAMES.contains(&input.name.as_str()) {
+                error(format!("input {:?} isn't a valid name", input.name));
+            }
+        }
+
+        let registers = syscall.inputs.len() + syscall.has_output_pointer() as usize;
+        if registers > X86_64_ARG_REGISTERS.len() {
+            error(format!(
+                "takes {} registers, but only {} can be passed",
+                registers,
+                X86_64_ARG_REGISTERS.len()
+            ));
+        }
+
+        if let Some(output) = &syscall.output {
+            if !OUTPUT_TYPES.contains(&output.ty.as_str()) {
+                error(format!("output can't be a {}", output.ty));
+            }
+        }
+
+        if syscall.noreturn && (syscall.result || syscall.output.is_some()) {
+            error("doesn't return, so it can't return anything".to_string());
+        }
+    }
+
+    errors
+}
+
+fn load_spec_or_panic(path: &str) -> SyscallFile {
+    let text = fs::read_to_string(path).unwrap_or_else(|err| panic!("{}: {}", path, err));
+    let spec: SyscallFile = toml::from_str(&text).unwrap_or_else(|err| panic!("{}: {}", path, err));
+
+    let errors = validate(&spec);
+    if !errors.is_empty() {
+        panic!(
+            "Invalid syscall definitions:\n{}",
+            errors
+                .iter()
+                .map(|err| format!("{}: {}", path, err))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+
+    println!("cargo:rerun-if-changed={}", path);
+    spec
+}
+
+// The wrapper for each syscall number, with unused numbers going to break.
+fn table<'a>(spec: &'a SyscallFile) -> Vec<Option<&'a Syscall>> {
+    let len = spec.syscall.iter().map(|x| x.id + 1).max().unwrap_or(0);
+    let mut table = vec![None; len];
+    for syscall in &spec.syscall {
+        table[syscall.id] = Some(syscall);
+    }
+    table
+}
+
+fn generate_x86_64_wrapper(syscall: &Syscall) -> String {
+    let params: Vec<_> = syscall
+        .inputs
+        .iter()
+        .map(|input| format!("{}: usize", input.name))
+        .collect();
+    let args: Vec<_> = syscall.inputs.iter().map(|x| x.name.clone()).collect();
+    let call = syscall.call(&args);
+
+    let (ret, body) = match (syscall.result, &syscall.output) {
+        (true, Some(_)) => (
+            " -> Pair",
+            format!(
+                "let (res, out) = {};\n    Pair {{\n        a: res.0 as usize,\n        b: out as usize,\n    }}",
+                call
+            ),
+        ),
+        (true, None) => (" -> u32", format!("{}.0", call)),
+        (false, Some(_)) => (" -> usize", format!("{} as usize", call)),
+        (false, None) => ("", format!("{};", call)),
+    };
+
+    format!(
+        "#[no_mangle]\n#[allow(clippy::unnecessary_cast)]\nunsafe extern \"C\" fn syscall_wrapper_{}({}){} {{\n    {}\n}}\n",
+        syscall.name,
+        params.join(", "),
+        ret,
+        body
+    )
+}
+
+fn generate_aarch64_wrapper(syscall: &Syscall) -> String {
+    let args: Vec<_> = (0..syscall.inputs.len())
+        .map(|i| format!("ctx.regs[{}]", i))
+        .collect();
+    let call = syscall.call(&args);
+
+    let body = match (syscall.result, &syscall.output) {
+        (true, Some(_)) => format!(
+            "let (res, out) = {};\n    ctx.regs[0] = res.0 as usize;\n    ctx.regs[1] = out as usize;",
+            call
+        ),
+        (true, None) => format!("let res = {};\n    ctx.regs[0] = res.0 as usize;", call),
+        (false, Some(_)) => format!("ctx.regs[0] = {} as usize;", call),
+        (false, None) => format!("{};", call),
+    };
+    let ctx = if syscall.inputs.is_empty() && !syscall.result && syscall.output.is_none() {
+        "_ctx"
+    } else {
+        "ctx"
+    };
+
+    format!(
+        "#[allow(clippy::unnecessary_cast)]\nfn syscall_wrapper_{}({}: &mut ExceptionContext) {{\n    {}\n}}\n",
+        syscall.name, ctx, body
+    )
+}
+
+// The wrappers and dispatch table for each arch, and the svc_ functions they call. The wrappers
+// are included into each arch's svc_wrappers module, and the exports into the svc module.
+pub fn generate_kernel(path: &str) {
+    let spec = load_spec_or_panic(path);
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+    let table = table(&spec);
+
+    let exports: String = spec
+        .syscall
+        .iter()
+        .filter_map(|syscall| {
+            syscall
+                .module
+                .as_ref()
+                .map(|module| format!("pub use {}::svc_{};\n", module, syscall.name))
+        })
+        .collect();
+    fs::write(Path::new(&out_dir).join("svc_exports.rs"), exports).unwrap();
+
+    let generated = spec.syscall.iter().filter(|x| !x.arch_specific);
+
+    let mut x86_64: String = generated.clone().map(generate_x86_64_wrapper).collect();
+    x86_64 += &format!(
+        "\npub static mut SYSCALL_WRAPPERS: [*const usize; {}] = [\n",
+        table.len()
+    );
+    for entry in &table {
+        x86_64 += &match entry {
+            Some(syscall) => format!("    syscall_wrapper_{} as *const usize,\n", syscall.name),
+            None => "    syscall_wrapper_break as *const usize, // unused\n".to_string(),
+        };
+    }
+    x86_64 += "];\n";
+    fs::write(Path::new(&out_dir).join("svc_wrappers_x86_64.rs"), x86_64).unwrap();
+
+    let mut aarch64: String = generated.map(generate_aarch64_wrapper).collect();
+    aarch64 += &format!(
+        "\ntype SVCHandler = fn(&mut ExceptionContext);\npub const SVC_HANDLERS: [SVCHandler; {}] = [\n",
+        table.len()
+    );
+    for entry in &table {
+        aarch64 += &match entry {
+            Some(syscall) => format!("    syscall_wrapper_{},\n", syscall.name),
+            None => "    syscall_wrapper_break, // unused\n".to_string(),
+        };
+    }
+    aarch64 += "];\n";
+    fs::write(Path::new(&out_dir).join("svc_wrappers_aarch64.rs"), aarch64).unwrap();
+}
+
+fn generate_extern(syscall: &Syscall) -> String {
+    let mut params: Vec<_> = syscall
+        .inputs
+        .iter()
+        .map(|input| format!("{}: {}", input.name, input.user_ty()))
+        .collect();
+
+    let ret = match (syscall.result, &syscall.output) {
+        (true, Some(output)) => {
+            params.push(format!("{}: *mut {}", output.name, output.user_ty()));
+            " -> ResultCode".to_string()
+        }
+        (true, None) => " -> ResultCode".to_string(),
+        (false, Some(output)) => format!(" -> {}", output.user_ty()),
+        (false, None) if syscall.noreturn => " -> !".to_string(),
+        (false, None) => "".to_string(),
+    };
+
+    format!(
+        "    pub fn syscall_{}({}){};\n",
+        syscall.name,
+        params.join(", "),
+        ret
+    )
+}
+
+fn generate_x86_64_stub(syscall: &Syscall) -> String {
+    let mut stub = format!("syscall_{}:\n", syscall.name);
+    let output = match &syscall.output {
+        Some(output) if syscall.result => Some(output),
+        _ => None,
+    };
+
+    // rbx is callee save, so it can hold the output pointer across the syscall.
+    if output.is_some() {
+        stub += "push rbx\n";
+    }
+    stub += &format!("mov eax, {:#04x}\n", syscall.id);
+    if output.is_some() {
+        stub += &format!("mov rbx, {}\n", X86_64_ARG_REGISTERS[syscall.inputs.len()]);
+    }
+    if syscall.inputs.len() > 3 {
+        stub += "mov r10, rcx\n";
+    }
+    stub += "syscall\n";
+    if let Some(output) = output {
+        let register = if output.ty == "u32" { "edx" } else { "rdx" };
+        stub += &format!("mov [rbx], {}\npop rbx\n", register);
+    }
+    stub + "ret\n"
+}
+
+fn generate_aarch64_stub(syscall: &Syscall) -> String {
+    let mut stub = format!("syscall_{}:\n", syscall.name);
+    let output = match &syscall.output {
+        Some(output) if syscall.result => Some(output),
+        _ => None,
+    };
+
+    // x9 is a temporary that the kernel doesn't touch.
+    if output.is_some() {
+        stub += &format!("mov x9, x{}\n", syscall.inputs.len());
+    }
+    stub += &format!("svc #{:#04x}\n", syscall.id);
+    if let Some(output) = output {
+        let register = if output.ty == "u32" { "w1" } else { "x1" };
+        stub += &format!("str {}, [x9]\n", register);
+    }
+    stub + "ret\n"
+}
+
+fn generate_asm(spec: &SyscallFile, stub: fn(&Syscall) -> String) -> String {
+    let mut asm = String::new();
+    for syscall in &spec.syscall {
+        asm += &format!(".global syscall_{}\n", syscall.name);
+    }
+    asm += "\n.section .text\n";
+    for syscall in &spec.syscall {
+        asm += "\n";
+        asm += &stub(syscall);
+    }
+    asm
+}
+
+// The extern declarations for libprocess, and the assembly stubs behind them for each arch.
+pub fn generate_user(path: &str) {
+    let spec = load_spec_or_panic(path);
+    let out_dir = env::var_os("OUT_DIR").unwrap();
+
+    let externs: String = spec.syscall.iter().map(generate_extern).collect();
+    fs::write(
+        Path::new(&out_dir).join("syscalls_extern.rs"),
+        format!("extern \"C\" {{\n{}}}\n", externs),
+    )
+    .unwrap();
+
+    fs::write(
+        Path::new(&out_dir).join("syscalls_x86_64.s"),
+        generate_asm(&spec, generate_x86_64_stub),
+    )
+    .unwrap();
+    fs::write(
+        Path::new(&out_dir).join("syscalls_aarch64.s"),
+        generate_asm(&spec, generate_aarch64_stub),
+    )
+    .unwrap();
+}